@@ -6,14 +6,144 @@ CREATE TABLE IF NOT EXISTS accounts (
 
 CREATE TABLE IF NOT EXISTS rooms (
      id TEXT PRIMARY KEY,
-     name TEXT NOT NULL
+     name TEXT NOT NULL,
+     owner_id TEXT REFERENCES accounts(id) ON DELETE SET NULL,
+     last_seq INTEGER NOT NULL DEFAULT 0,
+     kind TEXT NOT NULL DEFAULT 'public',
+     direct_key TEXT,
+     topic TEXT NOT NULL DEFAULT '',
+     description TEXT NOT NULL DEFAULT '',
+     created_at TEXT NOT NULL DEFAULT '',
+     created_by TEXT REFERENCES accounts(id) ON DELETE SET NULL,
+     archived INTEGER NOT NULL DEFAULT 0
 );
 
+CREATE UNIQUE INDEX IF NOT EXISTS rooms_direct_key ON rooms(direct_key);
+
+CREATE TABLE IF NOT EXISTS room_members (
+    room_id TEXT NOT NULL,
+    account_id TEXT NOT NULL,
+    role TEXT NOT NULL CHECK (role IN ('owner', 'moderator', 'member')),
+    PRIMARY KEY (room_id, account_id),
+    FOREIGN KEY (room_id) REFERENCES rooms(id) ON DELETE CASCADE,
+    FOREIGN KEY (account_id) REFERENCES accounts(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS room_members_account_id ON room_members(account_id);
+
+CREATE TABLE IF NOT EXISTS room_invites (
+    code TEXT PRIMARY KEY,
+    room_id TEXT NOT NULL,
+    created_by TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    expires_at TEXT,
+    max_uses INTEGER,
+    uses INTEGER NOT NULL DEFAULT 0,
+    FOREIGN KEY (room_id) REFERENCES rooms(id) ON DELETE CASCADE,
+    FOREIGN KEY (created_by) REFERENCES accounts(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS room_invites_room_id ON room_invites(room_id);
+
 CREATE TABLE IF NOT EXISTS messages (
     id TEXT PRIMARY KEY,
     room_id TEXT NOT NULL,
     username TEXT NOT NULL,
     content TEXT NOT NULL,
     date TEXT NOT NULL,
+    edited_at TEXT,
+    deleted_at TEXT,
+    reply_to TEXT REFERENCES messages(id),
+    thread_root TEXT REFERENCES messages(id),
+    seq INTEGER NOT NULL DEFAULT 0,
+    updated_seq INTEGER NOT NULL DEFAULT 0,
+    mentions TEXT NOT NULL DEFAULT '[]',
+    search_id INTEGER,
     FOREIGN KEY (room_id) REFERENCES rooms(id) ON DELETE CASCADE
 );
+
+CREATE INDEX IF NOT EXISTS messages_thread_root ON messages(thread_root, date, id);
+CREATE UNIQUE INDEX IF NOT EXISTS messages_search_id ON messages(search_id);
+
+CREATE TABLE IF NOT EXISTS message_edits (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    message_id TEXT NOT NULL,
+    content TEXT NOT NULL,
+    editor_id TEXT NOT NULL,
+    edited_at TEXT NOT NULL,
+    FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS message_edits_message_id ON message_edits(message_id, id);
+
+CREATE TABLE IF NOT EXISTS reactions (
+    message_id TEXT NOT NULL,
+    account_id TEXT NOT NULL,
+    emoji TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    PRIMARY KEY (message_id, account_id, emoji),
+    FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE,
+    FOREIGN KEY (account_id) REFERENCES accounts(id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS mentions (
+    message_id TEXT NOT NULL,
+    account_id TEXT NOT NULL,
+    PRIMARY KEY (message_id, account_id),
+    FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE,
+    FOREIGN KEY (account_id) REFERENCES accounts(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS mentions_account_id ON mentions(account_id);
+
+CREATE INDEX IF NOT EXISTS messages_room_id_date ON messages(room_id, date, id);
+CREATE INDEX IF NOT EXISTS messages_room_id_updated_seq ON messages(room_id, updated_seq);
+CREATE INDEX IF NOT EXISTS messages_room_id_seq ON messages(room_id, seq);
+
+CREATE TABLE IF NOT EXISTS read_positions (
+    room_id TEXT NOT NULL,
+    account_id TEXT NOT NULL,
+    last_read_seq INTEGER NOT NULL,
+    updated_at TEXT NOT NULL,
+    PRIMARY KEY (room_id, account_id),
+    FOREIGN KEY (room_id) REFERENCES rooms(id) ON DELETE CASCADE,
+    FOREIGN KEY (account_id) REFERENCES accounts(id) ON DELETE CASCADE
+);
+
+CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(
+    content,
+    content = 'messages',
+    content_rowid = 'search_id',
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+
+CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages BEGIN
+    UPDATE messages SET search_id = (SELECT COALESCE(MAX(search_id), 0) + 1 FROM messages)
+    WHERE id = new.id AND search_id IS NULL;
+    INSERT INTO messages_fts (rowid, content) SELECT search_id, content FROM messages WHERE id = new.id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages BEGIN
+    INSERT INTO messages_fts (messages_fts, rowid, content) VALUES ('delete', old.search_id, old.content);
+END;
+
+CREATE TRIGGER IF NOT EXISTS messages_fts_update AFTER UPDATE OF content ON messages BEGIN
+    INSERT INTO messages_fts (messages_fts, rowid, content) VALUES ('delete', old.search_id, old.content);
+    INSERT INTO messages_fts (rowid, content) VALUES (new.search_id, new.content);
+END;
+
+
+CREATE TABLE IF NOT EXISTS sessions (
+    token_hash TEXT PRIMARY KEY,
+    account_id TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    rotated_at TEXT NOT NULL,
+    last_seen_at TEXT NOT NULL,
+    expires_at TEXT NOT NULL,
+    replaced_by TEXT,
+    replaced_at TEXT,
+    FOREIGN KEY (account_id) REFERENCES accounts(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS sessions_account_id ON sessions(account_id);
+CREATE INDEX IF NOT EXISTS sessions_expires_at ON sessions(expires_at);
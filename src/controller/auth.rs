@@ -1,131 +1,119 @@
-use crate::entity::account::{LoginDTO, MeDTO, RegisterDTO};
+use crate::entity::account::{LoginDTO, RegisterDTO};
 use crate::entity::request_data::RequestData;
+use crate::entity::response::{Response, Status};
 use crate::entity::session::SessionTokenDTO;
-use crate::repository::account::{is_sha256_hash, insert_account, match_and_return_account, get_account_by_id};
-use crate::repository::session::{create_session, match_and_return_session, stop_session};
-use crate::utils::http_helper;
-use crate::utils::http_helper::{finish_request, invalid, is_route, parse_body, unauthenticated};
-use crate::utils::utils::{authorize, clear_cookies_response};
-
-pub const PREFIX: &str = "/api/auth";
-
-pub async fn auth_controller(mut data: RequestData) -> tokio::io::Result<()> {
-    match data {
-        _ if is_route("POST", "/register", PREFIX, &mut data) => register(data).await,
-        _ if is_route("POST", "/login", PREFIX, &mut data) => login(data).await,
-        _ if is_route("POST", "/me", PREFIX, &mut data) => me(data).await,
-        _ if is_route("POST", "/logout", PREFIX, &mut data) => logout(data).await,
-        _ => http_helper::not_found(data.stream).await
-    }
+use crate::repository::account::{insert_account, match_and_return_account, get_account_by_id};
+use crate::repository::connection;
+use crate::repository::session::{create_session, needs_rotation, rotate_session, stop_session};
+use crate::utils::http_helper::{internal_error, invalid, parse_body, unauthenticated};
+use crate::utils::config::CONFIG;
+use crate::utils::middleware::{Authenticate, Pipeline, RateLimit};
+use crate::utils::router::{http_handler, HttpHandler, Router};
+use crate::utils::utils::{bearer_token, redirect_to_login, with_session_cookies, WS_UNAUTHORIZED};
+
+const PREFIX: &str = "/api/auth";
+
+pub fn routes(router: Router<HttpHandler>) -> Router<HttpHandler> {
+    let limited = Pipeline::default()
+        .with(RateLimit::new(CONFIG.auth_rate_limit, CONFIG.auth_rate_limit_window));
+    let authenticated = Pipeline::default().with(Authenticate::RedirectToLogin);
+    let api = Pipeline::default().with(Authenticate::Reject);
+
+    router
+        .route("POST", &format!("{}/register", PREFIX), limited.wrap(http_handler(register)))
+        .route("POST", &format!("{}/login", PREFIX), limited.wrap(http_handler(login)))
+        .route("POST", &format!("{}/me", PREFIX), api.wrap(http_handler(me)))
+        .route("POST", &format!("{}/logout", PREFIX), authenticated.wrap(http_handler(logout)))
 }
 
-async fn register(data: RequestData) -> tokio::io::Result<()> {
-    let account_data: RegisterDTO = match parse_body(&data.buffer) {
+async fn register(data: RequestData) -> Response {
+    let account_data: RegisterDTO = match parse_body(&data.body) {
         Ok(data) => data,
-        Err(_) => return invalid(data.stream).await,
+        Err(_) => return invalid(),
     };
 
-    if !is_sha256_hash(&account_data.password) {
-        return invalid(data.stream).await;
+    if account_data.name.is_empty() || account_data.password.is_empty() {
+        return invalid();
     }
 
-    insert_account(account_data.name, account_data.password).await;
-
-    let response_body = "Account created successfully!".to_string();
-    let response = format!(
-        "HTTP/1.1 201 CREATED\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n{}",
-        response_body.len(),
-        response_body
-    );
+    let inserted = tokio::task::spawn_blocking(move || {
+        insert_account(account_data.name, account_data.password)
+    }).await;
 
-    finish_request(data.stream, &*response).await
+    match inserted {
+        Ok(Ok(())) => Response::text(Status::Created, "Account created successfully!"),
+        Ok(Err(err)) => {
+            eprintln!("{}", err);
+            Response::new(Status::Conflict)
+        }
+        Err(_) => internal_error(),
+    }
 }
 
-async fn login(data: RequestData) -> tokio::io::Result<()> {
-    let login_data: LoginDTO = match parse_body(&data.buffer) {
+async fn login(data: RequestData) -> Response {
+    let login_data: LoginDTO = match parse_body(&data.body) {
         Ok(data) => data,
-        Err(_) => return invalid(data.stream).await,
+        Err(_) => return invalid(),
     };
 
-    match match_and_return_account(&login_data.name, &login_data.password) {
+    let return_token = login_data.return_token;
+    let matched = tokio::task::spawn_blocking(move || {
+        match_and_return_account(&login_data.name, &login_data.password)
+    }).await;
+
+    match matched.ok().flatten() {
         Some(account) => {
-            let session = create_session(account.clone().id);
+            let (token, _) = create_session(account.clone().id);
             let session_dto = SessionTokenDTO {
-                id: account.id,
-                name: account.name,
-                token: session.token,
+                id: account.id.clone(),
+                name: account.name.clone(),
+                token: return_token.then(|| token.clone()),
             };
 
-            let response_body = serde_json::to_string(&session_dto)?;
-            let response = format!(
-                "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n{}",
-                response_body.len(),
-                response_body
-            );
-
-            finish_request(data.stream, &response).await
+            with_session_cookies(Response::json(&session_dto), &account.id, &account.name, &token)
         },
-        None => unauthenticated(data.stream).await,
+        None => unauthenticated(),
     }
 }
 
-async fn logout(data: RequestData) -> tokio::io::Result<()> {
-    let session = match authorize(&data.buffer) {
-        Ok(data) => data,
-        Err(_) => {
-            let response = format!("HTTP/1.1 302 Found\r\n\
-                Location: /login\r\n\
-                {}
-                \r\n",
-               clear_cookies_response()
-            );
-
-            return finish_request(data.stream, &response).await
-        },
-    };
-
-    stop_session(&session.id);
-
-    let response = format!("HTTP/1.1 302 Found\r\n\
-        Location: /login\r\n\
-        {}
-        \r\n",
-        clear_cookies_response()
-    );
+async fn logout(data: RequestData) -> Response {
+    if let Some(session) = data.session {
+        stop_session(&session.token_hash);
+        // Logging out with a token in its grace period ends its replacement too.
+        if let Some(replaced_by) = &session.replaced_by {
+            stop_session(replaced_by);
+        }
+        connection::close_session(session.current_token_hash(), (WS_UNAUTHORIZED, "Logged out"));
+    }
 
-    finish_request(data.stream, &response).await
+    redirect_to_login()
 }
 
-async fn me(data: RequestData) -> tokio::io::Result<()> {
-    let session_data: MeDTO = match parse_body(&data.buffer) {
-        Ok(data) => data,
-        Err(_) => return invalid(data.stream).await,
+async fn me(data: RequestData) -> Response {
+    let session = match data.session {
+        Some(session) => session,
+        None => return unauthenticated(),
     };
 
-    match match_and_return_session(session_data.id, session_data.token) {
-        Some(session) => {
-            let account = get_account_by_id(session.clone().id).await;
-            if account.is_none() {
-                return unauthenticated(data.stream).await;
-            }
+    let account = match get_account_by_id(session.id.clone()).await {
+        Some(account) => account,
+        None => return unauthenticated(),
+    };
 
-            let session = create_session(session.clone().id);
-            let session_dto = SessionTokenDTO {
-                id: session.id,
-                name: account.unwrap().name,
-                token: session.token,
-            };
+    let mut session_dto = SessionTokenDTO {
+        id: account.id.clone(),
+        name: account.name.clone(),
+        token: None,
+    };
 
-            let response_body = serde_json::to_string(&session_dto)?;
-            let response = format!(
-                "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n{}",
-                response_body.len(),
-                response_body
-            );
+    if !needs_rotation(&session) {
+        return Response::json(&session_dto);
+    }
 
-            finish_request(data.stream, &response).await
-        }
-        None => unauthenticated(data.stream).await,
+    let (token, _) = rotate_session(&session);
+    if bearer_token(&data.headers).is_some() {
+        session_dto.token = Some(token.clone());
     }
-}
 
+    with_session_cookies(Response::json(&session_dto), &account.id, &account.name, &token)
+}
@@ -0,0 +1,40 @@
+use crate::entity::request_data::RequestData;
+use crate::entity::response::Response;
+use crate::repository::connection;
+use crate::utils::http_helper::{not_found, ok, unauthenticated};
+use crate::utils::middleware::{Authenticate, Pipeline};
+use crate::utils::router::{http_handler, HttpHandler, Router};
+use crate::utils::utils::WS_GOING_AWAY;
+
+const PREFIX: &str = "/api/connection";
+
+pub fn routes(router: Router<HttpHandler>) -> Router<HttpHandler> {
+    let authenticated = Pipeline::default().with(Authenticate::Reject);
+
+    router
+        .route("GET", PREFIX, authenticated.wrap(http_handler(get_connections)))
+        .route("DELETE", &format!("{}/:id<uuid>", PREFIX), authenticated.wrap(http_handler(close_connection)))
+}
+
+async fn get_connections(data: RequestData) -> Response {
+    let session = match data.session {
+        Some(session) => session,
+        None => return unauthenticated(),
+    };
+
+    Response::json(&connection::get_by_account(&session.id))
+}
+
+async fn close_connection(data: RequestData) -> Response {
+    let session = match data.session {
+        Some(session) => session,
+        None => return unauthenticated(),
+    };
+
+    let id = data.params.get("id").cloned().unwrap_or_default();
+    if connection::close(&id, &session.id, (WS_GOING_AWAY, "Closed by user")) {
+        ok()
+    } else {
+        not_found()
+    }
+}
@@ -1,21 +1,52 @@
 use std::collections::HashMap;
-use tokio::io::{AsyncReadExt};
+use std::net::SocketAddr;
+use once_cell::sync::Lazy;
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
-use tokio_tungstenite::{accept_async, WebSocketStream};
+use tokio::time::timeout;
+use tokio_tungstenite::WebSocketStream;
+use tungstenite::handshake::derive_accept_key;
+use tungstenite::protocol::Role;
 use crate::entity::request_data::RequestData;
+use crate::entity::response::Response;
 use crate::utils::http_helper;
-use crate::utils::http_helper::{close_ws_with_error, serve_static};
-use crate::utils::utils::extract_path_from_request;
-use crate::controller::frontend::{frontend_controller, PREFIX as FRONTEND_CONTROLLER_PREFIX};
-use crate::controller::auth::{auth_controller, PREFIX as AUTH_CONTROLLER_PREFIX};
-use crate::controller::message::{router_message_ws, PREFIX as MESSAGE_CONTROLLER_PREFIX};
-use crate::controller::room::{room_controller, router_room_ws, PREFIX as ROOM_CONTROLLER_PREFIX};
+use crate::utils::config::CONFIG;
+use crate::utils::http_helper::{close_ws_with_error, is_keep_alive, is_websocket_upgrade, method_not_allowed, serve_static, websocket_key, write_response};
+use crate::utils::middleware::{CatchPanic, Pipeline, RequestLogger, SecurityHeaders, Timing};
+use crate::utils::request_reader::{ParsedRequest, ReadError, RequestReader};
+use crate::utils::router::{http_handler, HttpHandler, RouteMatch, Router, WsHandler};
+use crate::utils::utils::{authorize_ws, protocol_token, WS_TOKEN_PROTOCOL, WS_UNAUTHORIZED};
+use crate::repository::account::get_account_by_id;
+use crate::controller::{auth, connection, direct, frontend, message, room, socket};
+
+static HTTP_PIPELINE: Lazy<HttpHandler> = Lazy::new(|| {
+    Pipeline::default()
+        .with(RequestLogger)
+        .with(CatchPanic)
+        .with(Timing)
+        .with(SecurityHeaders)
+        .wrap(http_handler(routing))
+});
+
+static HTTP_ROUTER: Lazy<Router<HttpHandler>> = Lazy::new(|| {
+    let router = Router::default().route("GET", "/static/*path", http_handler(serve_static));
+    let router = auth::routes(router);
+    let router = room::routes(router);
+    let router = message::routes(router);
+    let router = connection::routes(router);
+    let router = direct::routes(router);
+    frontend::routes(router)
+});
+
+static WS_ROUTER: Lazy<Router<WsHandler>> = Lazy::new(|| {
+    socket::ws_routes(Router::default())
+});
 
 pub async fn init(listener: TcpListener) -> std::io::Result<()> {
     loop {
-        if let Ok((stream, _)) = listener.accept().await {
+        if let Ok((stream, remote_addr)) = listener.accept().await {
             tokio::spawn(async move {
-                if let Err(e) = route_request(stream).await {
+                if let Err(e) = handle_connection(stream, remote_addr).await {
                     eprintln!("Error: {}", e);
                 }
             });
@@ -23,54 +54,103 @@ pub async fn init(listener: TcpListener) -> std::io::Result<()> {
     }
 }
 
-async fn route_request(mut stream: TcpStream) -> std::io::Result<()> {
-    let mut buffer = [0; 1024];
-    let peeked_bytes = stream.peek(&mut buffer).await?;
-    let request = String::from_utf8_lossy(&buffer[..peeked_bytes]);
-    if request.contains("Upgrade: websocket") {
-        if let Some(path) = extract_path_from_request(&request) {
-            match accept_async(&mut stream).await {
-                Ok(ws_stream) => routing_ws(&*path, ws_stream, buffer).await,
-                Err(_) => http_helper::invalid(stream).await,
-            }
-        } else {
-            http_helper::invalid(stream).await
+async fn handle_connection(mut stream: TcpStream, remote_addr: SocketAddr) -> std::io::Result<()> {
+    let mut reader = RequestReader::default();
+    let mut served_requests = 0;
+
+    loop {
+        match timeout(CONFIG.keep_alive_timeout, reader.wait_for_request(&mut stream)).await {
+            Err(_) | Ok(Err(ReadError::Closed)) => return Ok(()),
+            Ok(Err(ReadError::Io(err))) => return Err(err),
+            Ok(_) => {}
         }
-    } else {
-        let mut buffer = [0; 1024];
-        let bytes_read = stream.read(&mut buffer).await?;
-        let request = String::from_utf8_lossy(&buffer[..bytes_read]);
-
-        if let Some(first_line) = request.lines().next() {
-            let parts: Vec<&str> = first_line.split_whitespace().collect();
-            if parts.len() >= 2 {
-                return routing(RequestData {
-                    stream,
-                    buffer,
-                    method: parts[0].to_string(),
-                    path: parts[1].to_string(),
-                    params: HashMap::new(),
-                }).await;
-            }
+
+        let request = match timeout(CONFIG.request_read_timeout, reader.read_request(&mut stream)).await {
+            Err(_) => return Ok(()),
+            Ok(Ok(request)) => request,
+            Ok(Err(ReadError::Closed)) => return Ok(()),
+            Ok(Err(ReadError::Io(err))) => return Err(err),
+            Ok(Err(ReadError::HeadersTooLarge)) => return write_response(&mut stream, &http_helper::headers_too_large(), false).await,
+            Ok(Err(ReadError::BodyTooLarge)) => return write_response(&mut stream, &http_helper::payload_too_large(), false).await,
+            Ok(Err(ReadError::Malformed)) => return write_response(&mut stream, &http_helper::invalid(), false).await,
+        };
+
+        if is_websocket_upgrade(&request.headers) {
+            return upgrade_websocket(stream, remote_addr, request).await;
+        }
+
+        served_requests += 1;
+        let keep_alive = is_keep_alive(&request.version, &request.headers)
+            && served_requests < CONFIG.max_keep_alive_requests;
+
+        let response = HTTP_PIPELINE(RequestData {
+            method: request.method,
+            path: request.path,
+            headers: request.headers,
+            body: request.body,
+            params: HashMap::new(),
+            remote_addr,
+            session: None,
+        }).await;
+        write_response(&mut stream, &response, keep_alive).await?;
+
+        if !keep_alive {
+            return Ok(());
         }
-        http_helper::invalid(stream).await
     }
 }
 
-async fn routing(data: RequestData) -> Result<(), std::io::Error> {
-    match &data.path {
-        p if p.starts_with("/static/") => serve_static(data).await,
-        p if p.starts_with(AUTH_CONTROLLER_PREFIX) => auth_controller(data).await,
-        p if p.starts_with(ROOM_CONTROLLER_PREFIX) => room_controller(data).await,
-        p if p.starts_with(FRONTEND_CONTROLLER_PREFIX) => frontend_controller(data).await,
-        _ => http_helper::not_found(data.stream).await
+async fn routing(mut data: RequestData) -> Response {
+    match HTTP_ROUTER.resolve(&data.method, &data.path) {
+        RouteMatch::Found(handler, params) => {
+            data.params = params;
+            handler(data).await
+        }
+        RouteMatch::MethodNotAllowed(allowed) => method_not_allowed(&allowed),
+        RouteMatch::NotFound => http_helper::not_found(),
     }
 }
 
-async fn routing_ws(path: &str, ws_stream: WebSocketStream<&mut TcpStream>, buffer: [u8; 1024]) -> Result<(), std::io::Error> {
-    match path {
-        p if p.starts_with(ROOM_CONTROLLER_PREFIX) => router_room_ws(path, ws_stream).await,
-        p if p.starts_with(MESSAGE_CONTROLLER_PREFIX) => router_message_ws(path, ws_stream, buffer).await,
-        _ => close_ws_with_error(ws_stream, 404, "Not Found".parse().unwrap()).await
+async fn upgrade_websocket(mut stream: TcpStream, remote_addr: SocketAddr, request: ParsedRequest) -> std::io::Result<()> {
+    let (handler, params) = match WS_ROUTER.resolve(&request.method, &request.path) {
+        RouteMatch::Found(handler, params) => (handler, params),
+        RouteMatch::MethodNotAllowed(allowed) => return write_response(&mut stream, &method_not_allowed(&allowed), false).await,
+        RouteMatch::NotFound => return write_response(&mut stream, &http_helper::not_found(), false).await,
+    };
+
+    let key = match websocket_key(&request.headers) {
+        Ok(key) => key,
+        Err(response) => return write_response(&mut stream, &response, false).await,
+    };
+
+    let mut response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n",
+        derive_accept_key(key.as_bytes())
+    );
+    if protocol_token(&request.headers).is_some() {
+        response.push_str(&format!("Sec-WebSocket-Protocol: {}\r\n", WS_TOKEN_PROTOCOL));
     }
-}
\ No newline at end of file
+    response.push_str("\r\n");
+    stream.write_all(response.as_bytes()).await?;
+
+    let ws_stream = WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+
+    let session = match authorize_ws(&request.headers) {
+        Ok(session) => session,
+        Err(_) => return close_ws_with_error(ws_stream, WS_UNAUTHORIZED, "Unauthorized").await,
+    };
+    let account = match get_account_by_id(session.id.clone()).await {
+        Some(account) => account,
+        None => return close_ws_with_error(ws_stream, WS_UNAUTHORIZED, "Unauthorized").await,
+    };
+
+    handler(ws_stream, RequestData {
+        method: request.method,
+        path: request.path,
+        headers: request.headers,
+        body: request.body,
+        params,
+        remote_addr,
+        session: Some(session),
+    }, account).await
+}
@@ -0,0 +1,51 @@
+use crate::entity::request_data::RequestData;
+use crate::entity::response::Response;
+use crate::entity::room::OpenDirectDTO;
+use crate::repository::account::get_account_by_id;
+use crate::repository::room;
+use crate::utils::http_helper::{invalid, parse_body, room_error, unauthenticated};
+use crate::utils::middleware::{Authenticate, Pipeline};
+use crate::utils::router::{http_handler, HttpHandler, Router};
+
+const PREFIX: &str = "/api/dm";
+
+/// Direct conversations are rooms, so once opened they are read and written
+/// through the room and message APIs like any other room.
+pub fn routes(router: Router<HttpHandler>) -> Router<HttpHandler> {
+    let authenticated = Pipeline::default().with(Authenticate::Reject);
+
+    router
+        .route("GET", PREFIX, authenticated.wrap(http_handler(get_direct)))
+        .route("POST", PREFIX, authenticated.wrap(http_handler(open_direct)))
+}
+
+async fn get_direct(data: RequestData) -> Response {
+    let session = match data.session {
+        Some(session) => session,
+        None => return unauthenticated(),
+    };
+
+    Response::json(&room::get_direct(session.id).await)
+}
+
+/// `POST /api/dm` with `{"usernames": [...]}` returns the conversation with
+/// those accounts, opening it the first time.
+async fn open_direct(data: RequestData) -> Response {
+    let account = match data.session {
+        Some(session) => match get_account_by_id(session.id).await {
+            Some(account) => account,
+            None => return unauthenticated(),
+        },
+        None => return unauthenticated(),
+    };
+
+    let body: OpenDirectDTO = match parse_body(&data.body) {
+        Ok(body) => body,
+        Err(_) => return invalid(),
+    };
+
+    match room::open_direct(account, body.usernames).await {
+        Ok(room) => Response::json(&room),
+        Err(err) => room_error(err),
+    }
+}
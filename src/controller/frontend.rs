@@ -1,40 +1,22 @@
 use askama::Template;
 use crate::entity::request_data::RequestData;
+use crate::entity::response::Response;
 use crate::entity::template::{IndexTemplate, RegisterTemplate, LoginTemplate, LayoutTemplate, RoomTemplate};
-use crate::repository::account::get_account_by_id;
 use crate::utils::http_helper;
-use crate::utils::http_helper::{finish_request, is_route};
-use crate::utils::utils::{authorize, clear_cookies_response};
+use crate::utils::middleware::{Authenticate, Pipeline};
+use crate::utils::router::{http_handler, HttpHandler, Router};
 
-pub const PREFIX: &str = "";
+pub fn routes(router: Router<HttpHandler>) -> Router<HttpHandler> {
+    let authenticated = Pipeline::default().with(Authenticate::RedirectToLogin);
 
-pub async fn frontend_controller(mut data: RequestData) -> tokio::io::Result<()> {
-    match data {
-        _ if is_route("GET", "", PREFIX, &mut data) => get_index(data).await,
-        _ if is_route("GET", "/login", PREFIX, &mut data) => get_login(data).await,
-        _ if is_route("GET", "/register", PREFIX, &mut data) => get_register(data).await,
-        _ if is_route("GET", "/room", PREFIX, &mut data) => get_room(data).await,
-        _ => http_helper::not_found(data.stream).await
-    }
+    router
+        .route("GET", "/", authenticated.wrap(http_handler(get_index)))
+        .route("GET", "/login", http_handler(get_login))
+        .route("GET", "/register", http_handler(get_register))
+        .route("GET", "/room", authenticated.wrap(http_handler(get_room)))
 }
 
-async fn get_index(data: RequestData) -> tokio::io::Result<()> {
-    let session = match authorize(&data.buffer) {
-        Ok(data) => data,
-        Err(_) => {
-            let response = format!("HTTP/1.1 302 Found\r\n\
-                Location: /login\r\n\
-                {}
-                \r\n",
-                clear_cookies_response()
-            );
-
-            return finish_request(data.stream, &response).await
-        },
-    };
-
-    let _ = get_account_by_id(session.id);
-
+async fn get_index(_data: RequestData) -> Response {
     let template = LayoutTemplate {
         child: IndexTemplate {},
         subtitle: "".to_string(),
@@ -42,68 +24,18 @@ async fn get_index(data: RequestData) -> tokio::io::Result<()> {
         css: "index.css".to_string(),
     };
 
-    let response_body = template
-        .render()
-        .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Render error"))?;
-
-    let response = format!(
-        "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n{}",
-        response_body.len(),
-        response_body
-    );
-
-    finish_request(data.stream, &*response).await
+    render(template)
 }
 
-async fn get_login(data: RequestData) -> tokio::io::Result<()> {
-    let template = LoginTemplate {};
-
-    let response_body = template
-        .render()
-        .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Render error"))?;
-
-    let response = format!(
-        "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n{}",
-        response_body.len(),
-        response_body
-    );
-
-    finish_request(data.stream, &*response).await
+async fn get_login(_data: RequestData) -> Response {
+    render(LoginTemplate {})
 }
 
-async fn get_register(data: RequestData) -> tokio::io::Result<()> {
-    let template = RegisterTemplate {};
-
-    let response_body = template
-        .render()
-        .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Render error"))?;
-
-    let response = format!(
-        "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n{}",
-        response_body.len(),
-        response_body
-    );
-
-    finish_request(data.stream, &*response).await
+async fn get_register(_data: RequestData) -> Response {
+    render(RegisterTemplate {})
 }
 
-async fn get_room(data: RequestData) -> tokio::io::Result<()> {
-    let session = match authorize(&data.buffer) {
-        Ok(data) => data,
-        Err(_) => {
-            let response = format!("HTTP/1.1 302 Found\r\n\
-                Location: /login\r\n\
-                {}
-                \r\n",
-               clear_cookies_response()
-            );
-
-            return finish_request(data.stream, &response).await
-        },
-    };
-
-    let _ = get_account_by_id(session.id);
-
+async fn get_room(_data: RequestData) -> Response {
     let template = LayoutTemplate {
         child: RoomTemplate {},
         subtitle: " - room".to_string(),
@@ -111,15 +43,15 @@ async fn get_room(data: RequestData) -> tokio::io::Result<()> {
         css: "room.css".to_string(),
     };
 
-    let response_body = template
-        .render()
-        .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Render error"))?;
-
-    let response = format!(
-        "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n{}",
-        response_body.len(),
-        response_body
-    );
+    render(template)
+}
 
-    finish_request(data.stream, &*response).await
-}
\ No newline at end of file
+fn render<T: Template>(template: T) -> Response {
+    match template.render() {
+        Ok(body) => Response::html(body),
+        Err(err) => {
+            eprintln!("Render error: {}", err);
+            http_helper::internal_error()
+        }
+    }
+}
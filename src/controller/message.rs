@@ -1,90 +1,204 @@
-use futures_util::{SinkExt, StreamExt};
-use tokio::net::TcpStream;
-use tokio_tungstenite::WebSocketStream;
+use chrono::{DateTime, NaiveDate, Utc};
+use crate::entity::account::Account;
+use crate::entity::message::{is_valid_content, is_valid_emoji, EditMessageDTO, MessageSearch};
+use crate::entity::request_data::RequestData;
+use crate::entity::response::Response;
 use crate::repository::account::get_account_by_id;
-use crate::repository::room::{add_message_to_room, ROOMS};
-use crate::utils::http_helper::{get_query_params, is_ws_route};
-use crate::utils::utils::authorize;
+use crate::repository::message;
+use crate::repository::room;
+use crate::utils::config::CONFIG;
+use crate::utils::http_helper::{get_query_params, internal_error, invalid, parse_body, room_error, unauthenticated};
+use crate::utils::middleware::{Authenticate, Pipeline};
+use crate::utils::router::{http_handler, HttpHandler, Router};
 
-pub const PREFIX: &str = "/api/message";
+const PREFIX: &str = "/api/message";
 
-pub async fn router_message_ws(path: &str, ws_stream: WebSocketStream<&mut TcpStream>, buffer: [u8; 1024]) -> std::io::Result<()> {
-    match path {
-        _ if is_ws_route("/send", PREFIX, path) => send_message(ws_stream, path, buffer).await,
-        _ if is_ws_route("/get", PREFIX, path) => receive_message(ws_stream, path).await,
-        _ => Err(tokio::io::Error::new(tokio::io::ErrorKind::NotFound, "Route not found")),
+pub fn routes(router: Router<HttpHandler>) -> Router<HttpHandler> {
+    let authenticated = Pipeline::default().with(Authenticate::Reject);
+
+    router
+        .route("GET", &format!("{}/search", PREFIX), authenticated.wrap(http_handler(search_messages)))
+        .route("PUT", &format!("{}/:id<uuid>", PREFIX), authenticated.wrap(http_handler(edit_message)))
+        .route("DELETE", &format!("{}/:id<uuid>", PREFIX), authenticated.wrap(http_handler(delete_message)))
+        .route("GET", &format!("{}/:id<uuid>/history", PREFIX), authenticated.wrap(http_handler(get_message_history)))
+        .route("GET", &format!("{}/:id<uuid>/thread", PREFIX), authenticated.wrap(http_handler(get_thread)))
+        .route("PUT", &format!("{}/:id<uuid>/reactions/:emoji", PREFIX), authenticated.wrap(http_handler(add_reaction)))
+        .route("DELETE", &format!("{}/:id<uuid>/reactions/:emoji", PREFIX), authenticated.wrap(http_handler(remove_reaction)))
+}
+
+/// Accepts a full RFC 3339 timestamp or a bare `YYYY-MM-DD` date, which covers
+/// the whole day, and returns it in the format message dates are stored in.
+fn parse_date_bound(value: &str, end_of_day: bool) -> Option<String> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(value) {
+        return Some(date.with_timezone(&Utc).to_rfc3339());
     }
+
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+    let time = if end_of_day {
+        date.and_hms_nano_opt(23, 59, 59, 999_999_999)?
+    } else {
+        date.and_hms_opt(0, 0, 0)?
+    };
+    Some(time.and_utc().to_rfc3339())
 }
 
-async fn send_message(ws_stream: WebSocketStream<&mut TcpStream>, path: &str, buffer: [u8; 1024]) -> tokio::io::Result<()> {
-    let (mut sender, mut receiver) = ws_stream.split();
-    let (_, query) = path.split_once('?').unwrap_or((&path, ""));
+/// `GET /api/message/search?q=&room=&user=&from=&to=&limit=&offset=`
+async fn search_messages(data: RequestData) -> Response {
+    let session = match data.session {
+        Some(session) => session,
+        None => return unauthenticated(),
+    };
+
+    let (_, query) = data.path.split_once('?').unwrap_or((&data.path, ""));
     let params = get_query_params(query);
 
-    let session = match authorize(&buffer) {
-        Ok(data) => data,
-        Err(_) => {
-            return Ok(())
-        },
+    let text = params.get("q").map(|q| q.trim().to_string()).unwrap_or_default();
+    if text.is_empty() {
+        return invalid();
+    }
+
+    let from = match params.get("from").map(|from| parse_date_bound(from, false)) {
+        Some(None) => return invalid(),
+        from => from.flatten(),
+    };
+    let to = match params.get("to").map(|to| parse_date_bound(to, true)) {
+        Some(None) => return invalid(),
+        to => to.flatten(),
+    };
+
+    let limit = match params.get("limit").map(|limit| limit.parse::<usize>()) {
+        None => CONFIG.message_page_size,
+        Some(Ok(limit)) if limit > 0 => limit.min(CONFIG.max_message_page_size),
+        Some(_) => return invalid(),
+    };
+    let offset = match params.get("offset").map(|offset| offset.parse::<usize>()) {
+        None => 0,
+        Some(Ok(offset)) => offset,
+        Some(Err(_)) => return invalid(),
     };
 
-    let account = get_account_by_id(session.id).await;
-    let id = params.get("id").cloned().unwrap_or_default();
-
-    while let Some(Ok(msg)) = receiver.next().await {
-        match msg {
-            tungstenite::Message::Text(text) => {
-                match add_message_to_room(
-                    id.clone(),
-                    account.clone().unwrap().name,
-                    text.clone()
-                ).await {
-                    Ok(_) => {}
-                    Err(err) => {
-                        sender
-                            .send(tungstenite::Message::Text(format!(
-                                "Error: {}",
-                                err
-                            )))
-                            .await
-                            .expect("Error occurred on sending error message");
-                    }
-                }
-            }
-            tungstenite::Message::Close(_) => break,
-            _ => println!("Received unexpected message"),
+    let search = MessageSearch {
+        query: text,
+        account_id: session.id,
+        room_id: params.get("room").cloned(),
+        username: params.get("user").cloned(),
+        from,
+        to,
+        limit,
+        offset,
+    };
 
+    match tokio::task::spawn_blocking(move || message::search(&search)).await {
+        Ok(Ok(page)) => Response::json(&page),
+        Ok(Err(err)) => {
+            eprintln!("Failed to search messages: {}", err);
+            internal_error()
         }
+        Err(_) => internal_error(),
+    }
+}
+
+async fn edit_message(data: RequestData) -> Response {
+    let account = match session_account(&data).await {
+        Some(account) => account,
+        None => return unauthenticated(),
+    };
+
+    let body = match parse_body::<EditMessageDTO>(&data.body) {
+        Ok(body) if is_valid_content(&body.content) => body,
+        _ => return invalid(),
+    };
+
+    let id = data.params.get("id").cloned().unwrap_or_default();
+    match room::edit_message(id, account, body.content).await {
+        Ok(message) => Response::json(&message),
+        Err(err) => room_error(err),
+    }
+}
+
+async fn delete_message(data: RequestData) -> Response {
+    let account = match session_account(&data).await {
+        Some(account) => account,
+        None => return unauthenticated(),
+    };
+
+    let id = data.params.get("id").cloned().unwrap_or_default();
+    match room::delete_message(id, account).await {
+        Ok(message) => Response::json(&message),
+        Err(err) => room_error(err),
     }
+}
 
-    Ok(())
+async fn get_message_history(data: RequestData) -> Response {
+    let session = match data.session {
+        Some(session) => session,
+        None => return unauthenticated(),
+    };
+
+    let id = data.params.get("id").cloned().unwrap_or_default();
+    match room::get_message_history(id, session.id).await {
+        Ok(edits) => Response::json(&edits),
+        Err(err) => room_error(err),
+    }
 }
 
-async fn receive_message(mut ws_stream: WebSocketStream<&mut TcpStream>, path: &str) -> tokio::io::Result<()> {
-    let (_, query) = path.split_once('?').unwrap_or((&path, ""));
+/// `GET /api/message/:id/thread?after=<reply id>&limit=50`
+async fn get_thread(data: RequestData) -> Response {
+    let session = match data.session {
+        Some(session) => session,
+        None => return unauthenticated(),
+    };
+
+    let (_, query) = data.path.split_once('?').unwrap_or((&data.path, ""));
     let params = get_query_params(query);
 
-    let room_id = params.get("id").cloned().unwrap_or_default();
-    let room = {
-        let rooms = ROOMS.lock().unwrap();
-        rooms.get(&room_id).cloned().ok_or_else(|| {
-            tokio::io::Error::new(tokio::io::ErrorKind::NotFound, "Room not found")
-        })?
+    let limit = match params.get("limit").map(|limit| limit.parse::<usize>()) {
+        None => CONFIG.message_page_size,
+        Some(Ok(limit)) if limit > 0 => limit.min(CONFIG.max_message_page_size),
+        Some(_) => return invalid(),
     };
 
-    let mut broadcast_receiver = room.sender.subscribe();
-
-    while let Ok(message) = broadcast_receiver.recv().await {
-        if ws_stream
-            .send(tungstenite::Message::Text(
-                serde_json::to_string(&message).unwrap(),
-            ))
-            .await
-            .is_err()
-        {
-            break;
-        }
+    let id = data.params.get("id").cloned().unwrap_or_default();
+    match room::get_thread(id, session.id, params.get("after").cloned(), limit).await {
+        Ok(thread) => Response::json(&thread),
+        Err(err) => room_error(err),
+    }
+}
+
+/// `PUT /api/message/:id/reactions/:emoji`, with the emoji percent-encoded.
+async fn add_reaction(data: RequestData) -> Response {
+    change_reaction(data, true).await
+}
+
+async fn remove_reaction(data: RequestData) -> Response {
+    change_reaction(data, false).await
+}
+
+async fn change_reaction(data: RequestData, add: bool) -> Response {
+    let account = match session_account(&data).await {
+        Some(account) => account,
+        None => return unauthenticated(),
+    };
+
+    let id = data.params.get("id").cloned().unwrap_or_default();
+    let emoji = data.params.get("emoji").cloned().unwrap_or_default();
+    if !is_valid_emoji(&emoji) {
+        return invalid();
     }
 
-    Ok(())
-}
\ No newline at end of file
+    let result = if add {
+        room::add_reaction(id, account, emoji).await
+    } else {
+        room::remove_reaction(id, account, emoji).await
+    };
+
+    match result {
+        Ok(message) => Response::json(&message),
+        Err(err) => room_error(err),
+    }
+}
+
+async fn session_account(data: &RequestData) -> Option<Account> {
+    let session = data.session.as_ref()?;
+    get_account_by_id(session.id.clone()).await
+}
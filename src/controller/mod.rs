@@ -2,4 +2,8 @@ mod frontend;
 mod auth;
 mod room;
 mod message;
+mod socket;
+mod connection;
+mod direct;
+#[allow(clippy::module_inception)]
 pub(crate) mod controller;
\ No newline at end of file
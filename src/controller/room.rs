@@ -1,116 +1,373 @@
-use futures_util::{SinkExt, StreamExt};
-use tokio::net::TcpStream;
-use tokio_tungstenite::WebSocketStream;
 use crate::entity::request_data::RequestData;
-use crate::entity::room::{CreateRoomDTO};
+use crate::entity::message::MessageCursor;
+use crate::entity::response::Response;
+use crate::entity::room::{is_valid_name, CreateInviteLinkDTO, CreateRoomDTO, InviteMemberDTO, RoomKind, UpdateMemberDTO, UpdateRoomDTO, MAX_INVITE_LINK_TTL_SECS};
+use crate::repository::account::get_account_by_id;
+use crate::repository::presence;
 use crate::repository::room;
-use crate::repository::room::{ROOM_SENDER};
-use crate::utils::http_helper::{finish_request, invalid, is_route, is_ws_route, not_found, ok, parse_body};
+use crate::utils::config::CONFIG;
+use crate::utils::http_helper::{get_query_params, invalid, not_found, ok, parse_body, room_error, unauthenticated};
+use crate::utils::middleware::{Authenticate, Pipeline};
+use crate::utils::router::{http_handler, HttpHandler, Router};
 
-pub const PREFIX: &str = "/api/room";
+const PREFIX: &str = "/api/room";
+const INVITE_PREFIX: &str = "/api/invite";
 
-pub async fn room_controller(mut data: RequestData) -> tokio::io::Result<()> {
-    match data {
-        _ if is_route("GET", "", PREFIX, &mut data) => get_rooms(data).await,
-        _ if is_route("GET", ":id", PREFIX, &mut data) => get_room(data).await,
-        _ if is_route("POST", "", PREFIX, &mut data) => create_room(data).await,
-        _ => not_found(data.stream).await
+pub fn routes(router: Router<HttpHandler>) -> Router<HttpHandler> {
+    let authenticated = Pipeline::default().with(Authenticate::Reject);
+
+    router
+        .route("GET", PREFIX, authenticated.wrap(http_handler(get_rooms)))
+        .route("POST", PREFIX, authenticated.wrap(http_handler(create_room)))
+        .route("GET", &format!("{}/:id<uuid>", PREFIX), authenticated.wrap(http_handler(get_room)))
+        .route("GET", &format!("{}/:id<uuid>/messages", PREFIX), authenticated.wrap(http_handler(get_messages)))
+        .route("GET", &format!("{}/:id<uuid>/online", PREFIX), authenticated.wrap(http_handler(get_online)))
+        .route("GET", &format!("{}/:id<uuid>/reads", PREFIX), authenticated.wrap(http_handler(get_read_receipts)))
+        .route("PATCH", &format!("{}/:id<uuid>", PREFIX), authenticated.wrap(http_handler(update_room)))
+        .route("DELETE", &format!("{}/:id<uuid>", PREFIX), authenticated.wrap(http_handler(delete_room_http)))
+        .route("PUT", &format!("{}/:id<uuid>/members/:account_id<uuid>", PREFIX), authenticated.wrap(http_handler(update_member)))
+        .route("GET", &format!("{}/:id<uuid>/members", PREFIX), authenticated.wrap(http_handler(get_members)))
+        .route("POST", &format!("{}/:id<uuid>/members", PREFIX), authenticated.wrap(http_handler(invite_member)))
+        .route("POST", &format!("{}/:id<uuid>/join", PREFIX), authenticated.wrap(http_handler(join_room)))
+        .route("POST", &format!("{}/:id<uuid>/leave", PREFIX), authenticated.wrap(http_handler(leave_room)))
+        .route("GET", &format!("{}/:id<uuid>/links", PREFIX), authenticated.wrap(http_handler(get_invite_links)))
+        .route("POST", &format!("{}/:id<uuid>/links", PREFIX), authenticated.wrap(http_handler(create_invite_link)))
+        .route("DELETE", &format!("{}/:id<uuid>/links/:code<uuid>", PREFIX), authenticated.wrap(http_handler(revoke_invite_link)))
+        .route("POST", &format!("{}/:code<uuid>", INVITE_PREFIX), authenticated.wrap(http_handler(join_by_invite)))
+}
+
+async fn create_room(data: RequestData) -> Response {
+    let session = match data.session {
+        Some(session) => session,
+        None => return unauthenticated(),
+    };
+
+    let body = match parse_body::<CreateRoomDTO>(&data.body) {
+        Ok(data) if is_valid_name(&data.name) => data,
+        _ => return invalid(),
+    };
+
+    let kind = if body.private { RoomKind::Private } else { RoomKind::Public };
+    Response::json(&room::create(body.name, session.id, kind).await)
+}
+
+/// `GET /api/room/:id/messages?before=<message id>&limit=50` pages backwards
+/// through history; `after=` pages forwards. Without a cursor the newest page
+/// is returned.
+async fn get_messages(data: RequestData) -> Response {
+    let session = match data.session {
+        Some(session) => session,
+        None => return unauthenticated(),
+    };
+
+    let (_, query) = data.path.split_once('?').unwrap_or((&data.path, ""));
+    let params = get_query_params(query);
+
+    let cursor = match (params.get("before"), params.get("after")) {
+        (None, None) => MessageCursor::Latest,
+        (Some(before), None) => MessageCursor::Before(before.clone()),
+        (None, Some(after)) => MessageCursor::After(after.clone()),
+        (Some(_), Some(_)) => return invalid(),
+    };
+
+    let limit = match params.get("limit").map(|limit| limit.parse::<usize>()) {
+        None => CONFIG.message_page_size,
+        Some(Ok(limit)) if limit > 0 => limit.min(CONFIG.max_message_page_size),
+        Some(_) => return invalid(),
+    };
+
+    let id = data.params.get("id").cloned().unwrap_or_default();
+    match room::get_messages(&id, &session.id, cursor, limit) {
+        Ok(page) => Response::json(&page),
+        Err(err) => room_error(err),
     }
 }
 
-pub async fn router_room_ws(path: &str, ws_stream: WebSocketStream<&mut TcpStream>) -> std::io::Result<()> {
-    match path {
-        _ if is_ws_route("/get", PREFIX, path) => receive_room(ws_stream).await,
-        _ if is_ws_route("/send", PREFIX, path) => send_room(ws_stream).await,
-        _ if is_ws_route("/delete", PREFIX, path) => delete_room(ws_stream).await,
-        _ => Err(tokio::io::Error::new(tokio::io::ErrorKind::NotFound, "Route not found")),
+/// Accounts with the room open right now, and whether each is typing.
+async fn get_online(data: RequestData) -> Response {
+    let session = match data.session {
+        Some(session) => session,
+        None => return unauthenticated(),
+    };
+
+    let id = data.params.get("id").cloned().unwrap_or_default();
+    if let Err(err) = room::check_access(&id, &session.id) {
+        return room_error(err);
     }
+
+    Response::json(&presence::get_online(&id))
 }
 
-async fn create_room(data: RequestData) -> tokio::io::Result<()> {
-    let body: CreateRoomDTO = match parse_body(&data.buffer) {
+/// `PATCH /api/room/:id` with any of `name`, `topic`, `description` and
+/// `archived`.
+async fn update_room(data: RequestData) -> Response {
+    let session = match data.session {
+        Some(session) => session,
+        None => return unauthenticated(),
+    };
+
+    let changes = match parse_body::<UpdateRoomDTO>(&data.body) {
+        Ok(changes) if changes.is_valid() && !changes.is_empty() => changes,
+        _ => return invalid(),
+    };
+
+    let id = data.params.get("id").cloned().unwrap_or_default();
+    match room::update(id, session.id, changes).await {
+        Ok(room) => Response::json(&room),
+        Err(err) => room_error(err),
+    }
+}
+
+async fn delete_room_http(data: RequestData) -> Response {
+    let session = match data.session {
+        Some(session) => session,
+        None => return unauthenticated(),
+    };
+
+    let id = data.params.get("id").cloned().unwrap_or_default();
+    match room::delete(id, session.id).await {
+        Ok(()) => ok(),
+        Err(err) => room_error(err),
+    }
+}
+
+async fn update_member(data: RequestData) -> Response {
+    let session = match data.session {
+        Some(session) => session,
+        None => return unauthenticated(),
+    };
+
+    let body: UpdateMemberDTO = match parse_body(&data.body) {
         Ok(data) => data,
-        Err(_) => return invalid(data.stream).await,
+        Err(_) => return invalid(),
+    };
+
+    let id = data.params.get("id").cloned().unwrap_or_default();
+    let account_id = data.params.get("account_id").cloned().unwrap_or_default();
+    if get_account_by_id(account_id.clone()).await.is_none() {
+        return not_found();
+    }
+
+    match room::set_role(id, session.id, account_id, body.role).await {
+        Ok(()) => ok(),
+        Err(err) => room_error(err),
+    }
+}
+
+async fn get_rooms(data: RequestData) -> Response {
+    let session = match data.session {
+        Some(session) => session,
+        None => return unauthenticated(),
+    };
+
+    let rooms = room::get(session.id).await;
+    Response::json(&rooms)
+}
+
+async fn get_read_receipts(data: RequestData) -> Response {
+    let session = match data.session {
+        Some(session) => session,
+        None => return unauthenticated(),
     };
 
-    room::create(body.name).await;
-    ok(data.stream).await
+    let id = data.params.get("id").cloned().unwrap_or_default();
+    match room::get_read_receipts(id, session.id).await {
+        Ok(receipts) => Response::json(&receipts),
+        Err(err) => room_error(err),
+    }
 }
 
-async fn get_rooms(data: RequestData) -> tokio::io::Result<()> {
-    let rooms = room::get().await;
-    let response_body = serde_json::to_string(&rooms)?;
+async fn get_room(data: RequestData) -> Response {
+    let session = match data.session {
+        Some(session) => session,
+        None => return unauthenticated(),
+    };
 
-    let response = format!(
-        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
-        response_body.len(),
-        response_body
-    );
+    let id = data.params.get("id").cloned().unwrap_or_default();
+    if let Err(err) = room::check_access(&id, &session.id) {
+        return room_error(err);
+    }
 
-    finish_request(data.stream, &response).await
+    match room::get_one_by_id(id).await {
+        Some(room) => Response::json(&room),
+        None => not_found(),
+    }
 }
 
-async fn get_room(data: RequestData) -> tokio::io::Result<()> {
-    if let Some(id) = data.params.get("id") {
-        let room = room::get_one_by_id(id.to_string()).await;
-        let response_body = serde_json::to_string(&room)?;
-        let response = format!(
-            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
-            response_body.len(),
-            response_body
-        );
+async fn get_members(data: RequestData) -> Response {
+    let session = match data.session {
+        Some(session) => session,
+        None => return unauthenticated(),
+    };
 
-        return finish_request(data.stream, &response).await
+    let id = data.params.get("id").cloned().unwrap_or_default();
+    match room::get_members(id, session.id).await {
+        Ok(members) => Response::json(&members),
+        Err(err) => room_error(err),
     }
+}
+
+/// `POST /api/room/:id/members` with `{"username": ...}` adds the account to
+/// the room.
+async fn invite_member(data: RequestData) -> Response {
+    let session = match data.session {
+        Some(session) => session,
+        None => return unauthenticated(),
+    };
+
+    let body: InviteMemberDTO = match parse_body(&data.body) {
+        Ok(data) => data,
+        Err(_) => return invalid(),
+    };
 
-    not_found(data.stream).await
+    let id = data.params.get("id").cloned().unwrap_or_default();
+    match room::invite(id, session.id, body.username).await {
+        Ok(member) => Response::json(&member),
+        Err(err) => room_error(err),
+    }
 }
 
-async fn send_room(ws_stream: WebSocketStream<&mut TcpStream>) -> tokio::io::Result<()> {
-    let (_, mut receiver) = ws_stream.split();
+async fn join_room(data: RequestData) -> Response {
+    let session = match data.session {
+        Some(session) => session,
+        None => return unauthenticated(),
+    };
 
-    while let Some(Ok(msg)) = receiver.next().await {
-        match msg {
-            tungstenite::Message::Text(text) => {
-                room::create(text.clone()).await;
-            }
-            tungstenite::Message::Close(_) => break,
-            _ => println!("Received unexpected message"),
+    let id = data.params.get("id").cloned().unwrap_or_default();
+    match room::join(id, session.id).await {
+        Ok(room) => Response::json(&room),
+        Err(err) => room_error(err),
+    }
+}
 
-        }
+async fn leave_room(data: RequestData) -> Response {
+    let account = match data.session {
+        Some(session) => match get_account_by_id(session.id).await {
+            Some(account) => account,
+            None => return unauthenticated(),
+        },
+        None => return unauthenticated(),
+    };
+
+    let id = data.params.get("id").cloned().unwrap_or_default();
+    match room::leave(id, account).await {
+        Ok(()) => ok(),
+        Err(err) => room_error(err),
     }
+}
+
+async fn get_invite_links(data: RequestData) -> Response {
+    let session = match data.session {
+        Some(session) => session,
+        None => return unauthenticated(),
+    };
 
-    Ok(())
+    let id = data.params.get("id").cloned().unwrap_or_default();
+    match room::get_invite_links(id, session.id).await {
+        Ok(links) => Response::json(&links),
+        Err(err) => room_error(err),
+    }
 }
 
-async fn delete_room(ws_stream: WebSocketStream<&mut TcpStream>) -> tokio::io::Result<()> {
-    let (_, mut receiver) = ws_stream.split();
+/// `POST /api/room/:id/links` with optional `expires_in_secs` and `max_uses`.
+async fn create_invite_link(data: RequestData) -> Response {
+    let session = match data.session {
+        Some(session) => session,
+        None => return unauthenticated(),
+    };
 
-    while let Some(Ok(msg)) = receiver.next().await {
-        match msg {
-            tungstenite::Message::Text(text) => {
-                room::delete(text.clone()).await;
-            }
-            tungstenite::Message::Close(_) => break,
-            _ => println!("Received unexpected message"),
+    let body = match parse_body::<CreateInviteLinkDTO>(&data.body) {
+        Ok(body) if body.max_uses.is_none_or(|max_uses| max_uses > 0) => body,
+        _ => return invalid(),
+    };
+    let expires_at = match body.expires_in_secs {
+        None => None,
+        Some(secs) if secs > MAX_INVITE_LINK_TTL_SECS => return invalid(),
+        Some(secs) => match chrono::Duration::try_seconds(secs as i64)
+            .and_then(|expires_in| chrono::Utc::now().checked_add_signed(expires_in))
+        {
+            Some(expires_at) => Some(expires_at),
+            None => return invalid(),
+        },
+    };
 
-        }
+    let id = data.params.get("id").cloned().unwrap_or_default();
+    match room::create_invite_link(id, session.id, expires_at, body.max_uses).await {
+        Ok(link) => Response::json(&link),
+        Err(err) => room_error(err),
     }
+}
+
+async fn revoke_invite_link(data: RequestData) -> Response {
+    let session = match data.session {
+        Some(session) => session,
+        None => return unauthenticated(),
+    };
 
-    Ok(())
+    let id = data.params.get("id").cloned().unwrap_or_default();
+    let code = data.params.get("code").cloned().unwrap_or_default();
+    match room::revoke_invite_link(id, session.id, code).await {
+        Ok(()) => ok(),
+        Err(err) => room_error(err),
+    }
 }
 
-async fn receive_room(mut ws_stream: WebSocketStream<&mut TcpStream>) -> tokio::io::Result<()> {
-    let mut broadcast_receiver = ROOM_SENDER.subscribe();
-    while let Ok(_) = broadcast_receiver.recv().await {
-        if ws_stream
-            .send(tungstenite::Message::Text("update".to_string()))
-            .await
-            .is_err()
-        {
-            break;
+/// `POST /api/invite/:code` joins the room the link points to.
+async fn join_by_invite(data: RequestData) -> Response {
+    let session = match data.session {
+        Some(session) => session,
+        None => return unauthenticated(),
+    };
+
+    let code = data.params.get("code").cloned().unwrap_or_default();
+    match room::join_by_invite(code, session.id).await {
+        Ok(room) => Response::json(&room),
+        Err(err) => room_error(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use chrono::Utc;
+    use crate::entity::response::Status;
+    use crate::entity::room::{MAX_DESCRIPTION_LENGTH, MAX_NAME_LENGTH};
+    use crate::entity::session::Session;
+
+    fn request(body: String) -> RequestData {
+        let now = Utc::now();
+        RequestData {
+            method: "POST".to_string(),
+            path: PREFIX.to_string(),
+            headers: HashMap::new(),
+            body: body.into_bytes(),
+            params: HashMap::from([("id".to_string(), "room-id".to_string())]),
+            remote_addr: "127.0.0.1:1".parse().unwrap(),
+            session: Some(Session {
+                id: "alice-id".to_string(),
+                token_hash: String::new(),
+                created_at: now,
+                rotated_at: now,
+                last_seen_at: now,
+                expires_at: now,
+                replaced_by: None,
+                replaced_at: None,
+            }),
         }
     }
 
-    Ok(())
-}
\ No newline at end of file
+    #[tokio::test]
+    async fn rejects_overlong_room_names() {
+        let name = "a".repeat(MAX_NAME_LENGTH + 1);
+        let body = serde_json::json!({ "name": name }).to_string();
+        assert_eq!(create_room(request(body.clone())).await.status, Status::BadRequest);
+        assert_eq!(update_room(request(body)).await.status, Status::BadRequest);
+    }
+
+    #[tokio::test]
+    async fn rejects_overlong_descriptions() {
+        let body = serde_json::json!({ "description": "a".repeat(MAX_DESCRIPTION_LENGTH + 1) }).to_string();
+        assert_eq!(update_room(request(body)).await.status, Status::BadRequest);
+    }
+}
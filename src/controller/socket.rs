@@ -0,0 +1,695 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use futures_util::{SinkExt, StreamExt};
+use serde::Serialize;
+use serde_json::Value;
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::{self, JoinHandle};
+use tokio::time::{self, Instant, MissedTickBehavior};
+use tokio_tungstenite::WebSocketStream;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use crate::entity::account::Account;
+use crate::entity::envelope::{ClientCommand, ErrorPayload, EventPayload, ProtocolError, ServerEnvelope, ServerMessage, ThreadEventPayload, UnsubscribedPayload, PROTOCOL_VERSION};
+use crate::entity::message::{is_valid_content, is_valid_emoji};
+use crate::entity::presence::{PresenceEventDTO, TypingEventDTO};
+use crate::entity::request_data::RequestData;
+use crate::entity::room::{is_valid_name, RoomError, RoomEvent, RoomKind, UpdateRoomDTO};
+use crate::repository::connection as registry;
+use crate::repository::connection::CloseRequest;
+use crate::repository::presence;
+use crate::repository::room;
+use crate::repository::room::{MENTION_SENDER, ROOM_SENDER};
+use crate::utils::config::CONFIG;
+use crate::utils::router::{ws_handler, Router, WsHandler};
+use crate::utils::utils::{WS_GOING_AWAY, WS_HEARTBEAT_TIMEOUT};
+
+const PATH: &str = "/ws";
+
+/// Envelopes queued for a client before forwarding tasks start to wait on it.
+const OUTBOX_SIZE: usize = 256;
+
+/// Pings and the close frame, which skip the envelope queue.
+const CONTROL_SIZE: usize = 4;
+
+/// How long a close frame may take to be written before the socket is dropped.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub fn ws_routes(router: Router<WsHandler>) -> Router<WsHandler> {
+    router.route("GET", PATH, ws_handler(connect))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Subscription {
+    Room(String),
+    Thread(String),
+    RoomList,
+}
+
+type Reply = Result<Option<Value>, ErrorPayload>;
+
+/// Sent by a room forwarder that stopped because the account lost access or
+/// the room was deleted, so the connection drops the subscription the
+/// forwarder belonged to.
+type Revoked = (Subscription, task::Id);
+
+/// What a room forwarder watches for: `account_id` losing access, or the
+/// room going away, ends the subscription `key`, which is reported through
+/// `notify`.
+struct RevokeWatch {
+    account_id: String,
+    key: Subscription,
+    notify: mpsc::UnboundedSender<Revoked>,
+}
+
+fn ack<T: Serialize>(value: &T) -> Reply {
+    Ok(serde_json::to_value(value).ok())
+}
+
+/// One client socket. Every subscription is a task forwarding a broadcast
+/// channel into `outbox`, which a single writer task drains into the socket.
+/// Mentions of the account are forwarded the same way without subscribing.
+struct Connection {
+    account: Account,
+    outbox: mpsc::Sender<ServerEnvelope>,
+    subscriptions: HashMap<Subscription, JoinHandle<()>>,
+    mentions: JoinHandle<()>,
+    revoked: mpsc::UnboundedSender<Revoked>,
+    /// Room forwarders waiting for the ack of the command that started them.
+    starting: Vec<oneshot::Sender<()>>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let keys: Vec<Subscription> = self.subscriptions.keys().cloned().collect();
+        for key in keys {
+            self.remove_subscription(&key);
+        }
+        self.mentions.abort();
+    }
+}
+
+impl Connection {
+    async fn handle(&mut self, command: ClientCommand) -> Reply {
+        match command {
+            ClientCommand::Subscribe { room_id, since } => self.subscribe_room(room_id, since).await,
+            ClientCommand::Unsubscribe { room_id } => self.unsubscribe(Subscription::Room(room_id)),
+            ClientCommand::SubscribeThread { message_id } => self.subscribe_thread(message_id).await,
+            ClientCommand::UnsubscribeThread { message_id } => self.unsubscribe(Subscription::Thread(message_id)),
+            ClientCommand::SubscribeRoomList => self.subscribe_room_list().await,
+            ClientCommand::UnsubscribeRoomList => self.unsubscribe(Subscription::RoomList),
+            ClientCommand::CreateRoom { name, private } => {
+                if !is_valid_name(&name) {
+                    return Err(ProtocolError::InvalidRequest.into());
+                }
+                let kind = if private { RoomKind::Private } else { RoomKind::Public };
+                ack(&room::create(name, self.account.id.clone(), kind).await)
+            }
+            ClientCommand::UpdateRoom { room_id, name, topic, description, archived } => {
+                let changes = UpdateRoomDTO { name, topic, description, archived };
+                if !changes.is_valid() || changes.is_empty() {
+                    return Err(ProtocolError::InvalidRequest.into());
+                }
+                ack(&room::update(room_id, self.account.id.clone(), changes).await?)
+            }
+            ClientCommand::JoinRoom { room_id } => ack(&room::join(room_id, self.account.id.clone()).await?),
+            ClientCommand::LeaveRoom { room_id } => {
+                room::leave(room_id.clone(), self.account.clone()).await?;
+                self.remove_subscription(&Subscription::Room(room_id));
+                Ok(None)
+            }
+            ClientCommand::InviteMember { room_id, username } => {
+                ack(&room::invite(room_id, self.account.id.clone(), username).await?)
+            }
+            ClientCommand::OpenDirect { usernames } => {
+                ack(&room::open_direct(self.account.clone(), usernames).await?)
+            }
+            ClientCommand::DeleteRoom { room_id } => {
+                room::delete(room_id, self.account.id.clone()).await?;
+                Ok(None)
+            }
+            ClientCommand::SendMessage { room_id, content, reply_to } => {
+                if !is_valid_content(&content) {
+                    return Err(ProtocolError::InvalidRequest.into());
+                }
+                let message = room::add_message_to_room(room_id.clone(), self.account.clone(), content, reply_to).await?;
+                presence::stop_typing(&room_id, &self.account.id);
+                ack(&message)
+            }
+            ClientCommand::EditMessage { message_id, content } => {
+                if !is_valid_content(&content) {
+                    return Err(ProtocolError::InvalidRequest.into());
+                }
+                ack(&room::edit_message(message_id, self.account.clone(), content).await?)
+            }
+            ClientCommand::DeleteMessage { message_id } => {
+                ack(&room::delete_message(message_id, self.account.clone()).await?)
+            }
+            ClientCommand::AddReaction { message_id, emoji } => {
+                if !is_valid_emoji(&emoji) {
+                    return Err(ProtocolError::InvalidRequest.into());
+                }
+                ack(&room::add_reaction(message_id, self.account.clone(), emoji).await?)
+            }
+            ClientCommand::RemoveReaction { message_id, emoji } => {
+                if !is_valid_emoji(&emoji) {
+                    return Err(ProtocolError::InvalidRequest.into());
+                }
+                ack(&room::remove_reaction(message_id, self.account.clone(), emoji).await?)
+            }
+            ClientCommand::Typing { room_id } => {
+                if !self.subscriptions.contains_key(&Subscription::Room(room_id.clone())) {
+                    return Err(ProtocolError::NotSubscribed.into());
+                }
+                room::check_access(&room_id, &self.account.id)?;
+                if presence::start_typing(&room_id, &self.account.id) == Some(true) {
+                    room::publish_ephemeral(&room_id, RoomEvent::Typing(TypingEventDTO {
+                        account_id: self.account.id.clone(),
+                        username: self.account.name.clone(),
+                        expires_in_ms: CONFIG.typing_ttl.as_millis() as u64,
+                    }));
+                }
+                Ok(None)
+            }
+            ClientCommand::MarkRead { room_id, message_id } => {
+                ack(&room::mark_read(room_id, self.account.clone(), message_id).await?)
+            }
+        }
+    }
+
+    /// Subscribing again with the same key replaces the subscription and
+    /// does not count against the per-connection limit.
+    fn check_capacity(&self, key: &Subscription) -> Result<(), ErrorPayload> {
+        if !self.subscriptions.contains_key(key) && self.subscriptions.len() >= CONFIG.ws_max_subscriptions {
+            return Err(ProtocolError::TooManySubscriptions.into());
+        }
+        Ok(())
+    }
+
+    /// Returns whether a subscription with the same key was replaced.
+    fn add_subscription(&mut self, key: Subscription, task: JoinHandle<()>) -> bool {
+        match self.subscriptions.insert(key, task) {
+            Some(previous) => {
+                previous.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns whether the subscription existed. Leaving a room's last
+    /// subscription takes the account offline there.
+    fn remove_subscription(&mut self, key: &Subscription) -> bool {
+        let task = match self.subscriptions.remove(key) {
+            Some(task) => task,
+            None => return false,
+        };
+        task.abort();
+
+        if let Subscription::Room(room_id) = key {
+            if presence::leave(room_id, &self.account.id) {
+                room::publish_ephemeral(room_id, RoomEvent::MemberLeft(self.presence_event()));
+            }
+        }
+        true
+    }
+
+    /// Drops a subscription whose forwarder stopped on losing access or on
+    /// the room's deletion, unless it has been replaced by a newer
+    /// subscription since.
+    fn remove_revoked(&mut self, (key, task_id): Revoked) {
+        if self.subscriptions.get(&key).is_some_and(|task| task.id() == task_id) {
+            self.remove_subscription(&key);
+        }
+    }
+
+    /// Lets forwarders started by the last command run, now that its ack is
+    /// queued ahead of their events.
+    fn start_forwarders(&mut self) {
+        for start in self.starting.drain(..) {
+            let _ = start.send(());
+        }
+    }
+
+    /// A room forwarder must not queue events before the ack carrying the
+    /// room's `last_seq`, or the client would see them out of order.
+    fn after_ack(&mut self) -> oneshot::Receiver<()> {
+        let (start, started) = oneshot::channel();
+        self.starting.push(start);
+        started
+    }
+
+    fn revoke_watch(&self, key: Subscription) -> RevokeWatch {
+        RevokeWatch {
+            account_id: self.account.id.clone(),
+            key,
+            notify: self.revoked.clone(),
+        }
+    }
+
+    fn presence_event(&self) -> PresenceEventDTO {
+        PresenceEventDTO {
+            account_id: self.account.id.clone(),
+            username: self.account.name.clone(),
+        }
+    }
+
+    /// Acks with the room and its newest page of messages; events follow.
+    /// A client resuming from `since` gets the room without messages and the
+    /// changes it missed replayed as events instead.
+    async fn subscribe_room(&mut self, room_id: String, since: Option<i64>) -> Reply {
+        let key = Subscription::Room(room_id.clone());
+        self.check_capacity(&key)?;
+
+        let (mut room, receiver) = room::subscribe(room_id.clone(), self.account.id.clone()).await?;
+        let replay_from = match since {
+            Some(since) if since < 0 || since > room.last_seq => return Err(RoomError::InvalidCursor.into()),
+            Some(since) => {
+                room.messages.clear();
+                since
+            }
+            None => room.last_seq,
+        };
+
+        let event_room_id = room_id.clone();
+        let watch = self.revoke_watch(key.clone());
+        let started = self.after_ack();
+        let task = forward_room(receiver, self.outbox.clone(), room_id.clone(), replay_from, watch, started, move |event| {
+            Some(ServerMessage::Event(EventPayload { room_id: event_room_id.clone(), event }))
+        });
+        if !self.add_subscription(key, task) && presence::join(&room_id, &self.account) {
+            room::publish_ephemeral(&room_id, RoomEvent::MemberJoined(self.presence_event()));
+        }
+        ack(&room)
+    }
+
+    /// Acks with the thread's root and first page of replies; events of that
+    /// thread follow.
+    async fn subscribe_thread(&mut self, message_id: String) -> Reply {
+        let key = Subscription::Thread(message_id.clone());
+        self.check_capacity(&key)?;
+
+        let room_id = room::get_thread_room(message_id.clone(), self.account.id.clone()).await?;
+        let (room, receiver) = room::subscribe(room_id.clone(), self.account.id.clone()).await?;
+        // Fetched before the forwarder is installed so a failed fetch leaves no
+        // subscription behind; the receiver already buffers events since `room`.
+        let thread = room::get_thread(message_id.clone(), self.account.id.clone(), None, CONFIG.message_page_size).await?;
+        let thread_id = message_id;
+        let event_room_id = room_id.clone();
+        let watch = self.revoke_watch(key.clone());
+        let started = self.after_ack();
+        let task = forward_room(receiver, self.outbox.clone(), room_id, room.last_seq, watch, started, move |event| {
+            event.is_in_thread(&thread_id).then(|| ServerMessage::ThreadEvent(ThreadEventPayload {
+                thread_id: thread_id.clone(),
+                room_id: event_room_id.clone(),
+                event,
+            }))
+        });
+        self.add_subscription(key, task);
+        ack(&thread)
+    }
+
+    async fn subscribe_room_list(&mut self) -> Reply {
+        let key = Subscription::RoomList;
+        self.check_capacity(&key)?;
+
+        let account_id = self.account.id.clone();
+        let task = forward(ROOM_SENDER.subscribe(), self.outbox.clone(), move |event| {
+            room::is_list_event_visible(&event, &account_id).then_some(ServerMessage::RoomListEvent(event))
+        });
+        self.add_subscription(key, task);
+        ack(&room::get(self.account.id.clone()).await)
+    }
+
+    fn unsubscribe(&mut self, key: Subscription) -> Reply {
+        if self.remove_subscription(&key) {
+            Ok(None)
+        } else {
+            Err(ProtocolError::NotSubscribed.into())
+        }
+    }
+}
+
+/// Forwards a broadcast channel into a connection's outbox until either side
+/// goes away. `to_message` returns `None` for events the subscriber skips.
+fn forward<T, F>(mut receiver: broadcast::Receiver<T>, outbox: mpsc::Sender<ServerEnvelope>, to_message: F) -> JoinHandle<()>
+where
+    T: Clone + Send + 'static,
+    F: Fn(T) -> Option<ServerMessage> + Send + 'static,
+{
+    tokio::spawn(async move {
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    eprintln!("Subscriber lagged behind, skipped {} events", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+            if let Some(message) = to_message(event) {
+                if outbox.send(ServerEnvelope::new(None, message)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    })
+}
+
+/// Forwards a room's events like `forward`, but tracks the sequence number of
+/// the last event handled. Changes after `last_seq` are first replayed from
+/// the database, and again whenever the subscriber lags behind the channel,
+/// so no event is lost or delivered twice. Forwarding stops once the watched
+/// account loses access to the room, or the room is deleted and its channel
+/// closes, which is reported to the connection. The client is told of the
+/// deletion, or of a revocation only noticed when catching up, with
+/// `unsubscribed`; a live revocation reaches it as a room event.
+/// Nothing is forwarded before `started` fires; events arriving meanwhile
+/// wait in the channel.
+fn forward_room<F>(
+    mut receiver: broadcast::Receiver<RoomEvent>,
+    outbox: mpsc::Sender<ServerEnvelope>,
+    room_id: String,
+    mut last_seq: i64,
+    watch: RevokeWatch,
+    started: oneshot::Receiver<()>,
+    to_message: F,
+) -> JoinHandle<()>
+where
+    F: Fn(RoomEvent) -> Option<ServerMessage> + Send + 'static,
+{
+    tokio::spawn(async move {
+        if started.await.is_err() {
+            return;
+        }
+        let mut backfill = true;
+        loop {
+            if backfill {
+                backfill = false;
+                // A revocation that fell into a lag gap is not replayed, so
+                // access is checked again before anything is caught up.
+                if room::check_access(&room_id, &watch.account_id).is_err() {
+                    unsubscribe(&outbox, room_id, watch).await;
+                    break;
+                }
+                let since = last_seq;
+                loop {
+                    let (messages, has_more) = match room::get_changes(room_id.clone(), last_seq, CONFIG.max_message_page_size).await {
+                        Ok(page) => page,
+                        Err(_) => return,
+                    };
+                    for message in messages {
+                        last_seq = message.updated_seq;
+                        if let Some(message) = to_message(RoomEvent::replay(message, since)) {
+                            if outbox.send(ServerEnvelope::new(None, message)).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    if !has_more {
+                        break;
+                    }
+                }
+            }
+
+            let event = match receiver.recv().await {
+                Ok(event) => match event.seq() {
+                    Some(seq) if seq <= last_seq => continue,
+                    Some(seq) => {
+                        last_seq = seq;
+                        event
+                    }
+                    None => event,
+                },
+                Err(RecvError::Lagged(_)) => {
+                    backfill = true;
+                    continue;
+                }
+                Err(RecvError::Closed) => {
+                    unsubscribe(&outbox, room_id, watch).await;
+                    break;
+                }
+            };
+
+            let revoked = matches!(&event, RoomEvent::AccessRevoked(member) if member.account_id == watch.account_id);
+            if let Some(message) = to_message(event) {
+                if outbox.send(ServerEnvelope::new(None, message)).await.is_err() {
+                    break;
+                }
+            }
+            if revoked {
+                let _ = watch.notify.send((watch.key, task::id()));
+                break;
+            }
+        }
+    })
+}
+
+/// Ends a room forwarder's subscription from inside the forwarder: the client
+/// is told with `unsubscribed` and the connection drops the subscription.
+async fn unsubscribe(outbox: &mpsc::Sender<ServerEnvelope>, room_id: String, watch: RevokeWatch) {
+    let thread_id = match &watch.key {
+        Subscription::Thread(thread_id) => Some(thread_id.clone()),
+        _ => None,
+    };
+    let message = ServerMessage::Unsubscribed(UnsubscribedPayload { room_id, thread_id });
+    let _ = outbox.send(ServerEnvelope::new(None, message)).await;
+    let _ = watch.notify.send((watch.key, task::id()));
+}
+
+/// Splits a frame into its correlation id and command. The id is returned
+/// even when the rest of the envelope is invalid, so the error can be matched
+/// to the request that caused it.
+fn parse_envelope(text: &str) -> (Option<String>, Result<ClientCommand, ProtocolError>) {
+    let value = match serde_json::from_str::<Value>(text) {
+        Ok(value @ Value::Object(_)) => value,
+        _ => return (None, Err(ProtocolError::InvalidEnvelope)),
+    };
+
+    let id = match value.get("id") {
+        Some(Value::String(id)) => Some(id.clone()),
+        Some(Value::Number(id)) => Some(id.to_string()),
+        _ => None,
+    };
+
+    let command = match value.get("v").and_then(Value::as_u64) {
+        Some(PROTOCOL_VERSION) => match value.get("type") {
+            Some(Value::String(_)) => serde_json::from_value::<ClientCommand>(value)
+                .map_err(|_| ProtocolError::InvalidRequest),
+            _ => Err(ProtocolError::InvalidEnvelope),
+        },
+        Some(_) => Err(ProtocolError::UnsupportedVersion),
+        None => Err(ProtocolError::InvalidEnvelope),
+    };
+
+    (id, command)
+}
+
+/// What the heartbeat asks the connection to do next.
+enum Beat {
+    Ping,
+    Timeout(CloseRequest),
+}
+
+/// When to ping the client and when to give up on it. Any frame counts as a
+/// sign of life, pongs included: the bundled client only sends commands when
+/// the user acts, so a reader who just watches a room stays connected.
+struct Heartbeat {
+    ping: time::Interval,
+    pong_timeout: Duration,
+    idle_timeout: Duration,
+    pong_deadline: Option<Instant>,
+    last_seen: Instant,
+}
+
+impl Heartbeat {
+    fn new(ping_interval: Duration, pong_timeout: Duration, idle_timeout: Duration) -> Self {
+        let mut ping = time::interval_at(Instant::now() + ping_interval, ping_interval);
+        ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Heartbeat {
+            ping,
+            pong_timeout,
+            idle_timeout,
+            pong_deadline: None,
+            last_seen: Instant::now(),
+        }
+    }
+
+    fn received(&mut self, frame: &tungstenite::Message) {
+        self.last_seen = Instant::now();
+        if matches!(frame, tungstenite::Message::Pong(_)) {
+            self.pong_deadline = None;
+        }
+    }
+
+    /// Resolves when a ping is due or the client timed out. Safe to cancel,
+    /// so it can race incoming frames in a `select!`.
+    async fn next(&mut self) -> Beat {
+        let pong_deadline = self.pong_deadline;
+        tokio::select! {
+            _ = self.ping.tick(), if pong_deadline.is_none() => {
+                self.pong_deadline = Some(Instant::now() + self.pong_timeout);
+                Beat::Ping
+            }
+            _ = time::sleep_until(pong_deadline.unwrap_or_else(Instant::now)), if pong_deadline.is_some() => {
+                Beat::Timeout((WS_HEARTBEAT_TIMEOUT, "Heartbeat timeout"))
+            }
+            _ = time::sleep_until(self.last_seen + self.idle_timeout) => {
+                Beat::Timeout((WS_GOING_AWAY, "Idle timeout"))
+            }
+        }
+    }
+}
+
+/// `GET /ws`: the single socket a client keeps open. Commands are answered
+/// with an `ack` or `error` carrying the command's `id`; events of every
+/// subscription are interleaved on the same socket.
+///
+/// The server pings every `ws_ping_interval` and closes with 1011 when no pong
+/// arrives within `ws_pong_timeout`, and with 1001 after `ws_idle_timeout`
+/// without any frame from the client or when closed through the registry.
+async fn connect(ws_stream: WebSocketStream<TcpStream>, data: RequestData, account: Account) -> tokio::io::Result<()> {
+    let (mut sink, mut stream) = ws_stream.split();
+    let (outbox, mut inbox) = mpsc::channel::<ServerEnvelope>(OUTBOX_SIZE);
+    let (control, mut control_inbox) = mpsc::channel::<tungstenite::Message>(CONTROL_SIZE);
+
+    // Control frames go out ahead of queued envelopes; a close frame is the
+    // last thing written.
+    let mut writer = tokio::spawn(async move {
+        loop {
+            let frame = tokio::select! {
+                biased;
+                Some(frame) = control_inbox.recv() => frame,
+                Some(envelope) = inbox.recv() => match serde_json::to_string(&envelope) {
+                    Ok(text) => tungstenite::Message::Text(text),
+                    Err(err) => {
+                        eprintln!("Failed to serialize envelope: {}", err);
+                        continue;
+                    }
+                },
+                else => break,
+            };
+
+            let closing = matches!(frame, tungstenite::Message::Close(_));
+            if sink.send(frame).await.is_err() || closing {
+                break;
+            }
+        }
+    });
+
+    let token_hash = data.session.as_ref().map(|session| session.current_token_hash().to_string()).unwrap_or_default();
+    let (connection_id, mut close_requested) = registry::register(account.id.clone(), token_hash, data.remote_addr);
+
+    let (revoked, mut revoked_inbox) = mpsc::unbounded_channel::<Revoked>();
+    let account_id = account.id.clone();
+    let mut connection = Connection {
+        account,
+        outbox: outbox.clone(),
+        subscriptions: HashMap::new(),
+        mentions: forward(MENTION_SENDER.subscribe(), outbox.clone(), move |event| {
+            event.account_ids.contains(&account_id).then_some(ServerMessage::Mentioned(event))
+        }),
+        revoked,
+        starting: Vec::new(),
+    };
+
+    let mut heartbeat = Heartbeat::new(CONFIG.ws_ping_interval, CONFIG.ws_pong_timeout, CONFIG.ws_idle_timeout);
+
+    let close: Option<CloseRequest> = loop {
+        tokio::select! {
+            frame = stream.next() => {
+                let frame = match frame {
+                    Some(Ok(frame)) => frame,
+                    _ => break None,
+                };
+                registry::touch(&connection_id);
+                heartbeat.received(&frame);
+
+                match frame {
+                    tungstenite::Message::Text(text) => {
+                        let (id, command) = parse_envelope(&text);
+                        let reply = match command {
+                            Ok(command) => connection.handle(command).await,
+                            Err(err) => Err(err.into()),
+                        };
+                        let message = match reply {
+                            Ok(payload) => ServerMessage::Ack(payload),
+                            Err(err) => ServerMessage::Error(err),
+                        };
+                        if outbox.send(ServerEnvelope::new(id, message)).await.is_err() {
+                            break None;
+                        }
+                        connection.start_forwarders();
+                    }
+                    tungstenite::Message::Close(_) => break None,
+                    _ => {}
+                }
+            }
+            beat = heartbeat.next() => match beat {
+                Beat::Ping => {
+                    if control.send(tungstenite::Message::Ping(vec![])).await.is_err() {
+                        break None;
+                    }
+                }
+                Beat::Timeout(request) => break Some(request),
+            },
+            Some(revoked) = revoked_inbox.recv() => connection.remove_revoked(revoked),
+            Ok(request) = &mut close_requested => break Some(request),
+        }
+    };
+
+    registry::unregister(&connection_id);
+    drop(connection);
+
+    // A dead peer never drains the socket, so the close frame gets a moment to
+    // go out before the writer is dropped.
+    if let Some((code, reason)) = close {
+        let frame = tungstenite::Message::Close(Some(CloseFrame {
+            code: CloseCode::from(code),
+            reason: reason.into(),
+        }));
+        if control.send(frame).await.is_ok() && time::timeout(CLOSE_TIMEOUT, &mut writer).await.is_ok() {
+            return Ok(());
+        }
+    }
+
+    writer.abort();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[tokio::test]
+    async fn client_answering_pings_outlives_idle_timeout() {
+        let mut heartbeat = Heartbeat::new(millis(10), millis(20), millis(50));
+        let started = Instant::now();
+        while started.elapsed() < millis(150) {
+            match heartbeat.next().await {
+                Beat::Ping => heartbeat.received(&tungstenite::Message::Pong(vec![])),
+                Beat::Timeout((_, reason)) => panic!("closed after {:?}: {}", started.elapsed(), reason),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn client_ignoring_pings_times_out() {
+        let mut heartbeat = Heartbeat::new(millis(10), millis(20), millis(500));
+        assert!(matches!(heartbeat.next().await, Beat::Ping));
+        assert!(matches!(heartbeat.next().await, Beat::Timeout((WS_HEARTBEAT_TIMEOUT, _))));
+    }
+
+    #[tokio::test]
+    async fn silent_client_times_out_when_idle() {
+        let mut heartbeat = Heartbeat::new(millis(500), millis(20), millis(30));
+        assert!(matches!(heartbeat.next().await, Beat::Timeout((WS_GOING_AWAY, _))));
+    }
+}
@@ -17,10 +17,7 @@ pub struct RegisterDTO {
 pub struct LoginDTO {
     pub name: String,
     pub password: String,
-}
-
-#[derive(Debug, Deserialize)]
-pub struct MeDTO {
-    pub id: String,
-    pub token: String,
+    /// API clients that cannot use cookies set this to receive the token in the body.
+    #[serde(default)]
+    pub return_token: bool,
 }
\ No newline at end of file
@@ -0,0 +1,11 @@
+use serde::Serialize;
+
+/// A live `/ws` connection as listed to its owner.
+#[derive(Debug, Serialize, Clone)]
+pub struct ConnectionDTO {
+    pub id: String,
+    pub remote_addr: String,
+    pub connected_at: String,
+    /// When the client last sent a frame, including pongs.
+    pub last_seen_at: String,
+}
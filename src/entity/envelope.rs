@@ -0,0 +1,177 @@
+use std::fmt;
+use serde::{Deserialize, Serialize};
+use crate::entity::message::MentionEventDTO;
+use crate::entity::room::{RoomError, RoomEvent, RoomListEvent};
+
+/// Version of the `/ws` protocol. Clients send it as `v` in every envelope.
+pub const PROTOCOL_VERSION: u64 = 1;
+
+/// A client command: `{"v": 1, "id": "...", "type": "...", "payload": {...}}`.
+/// The optional `id` is echoed in the `ack` or `error` that answers it.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+pub enum ClientCommand {
+    /// With `since`, the ack carries the room without messages and every
+    /// change after that sequence number is replayed as events first.
+    Subscribe {
+        room_id: String,
+        #[serde(default)]
+        since: Option<i64>,
+    },
+    Unsubscribe { room_id: String },
+    SubscribeThread { message_id: String },
+    UnsubscribeThread { message_id: String },
+    SubscribeRoomList,
+    UnsubscribeRoomList,
+    CreateRoom {
+        name: String,
+        #[serde(default)]
+        private: bool,
+    },
+    /// Changes the given metadata fields. Acks with the updated room.
+    UpdateRoom {
+        room_id: String,
+        #[serde(default)]
+        name: Option<String>,
+        #[serde(default)]
+        topic: Option<String>,
+        #[serde(default)]
+        description: Option<String>,
+        #[serde(default)]
+        archived: Option<bool>,
+    },
+    /// Joins a public room, which then lists the caller as a member.
+    JoinRoom { room_id: String },
+    /// Leaving a private room also ends the caller's subscriptions to it.
+    LeaveRoom { room_id: String },
+    /// Adds the named account to a room the caller manages.
+    InviteMember { room_id: String, username: String },
+    DeleteRoom { room_id: String },
+    /// Acks with the direct conversation between the caller and these
+    /// accounts, opened if it did not exist yet.
+    OpenDirect { usernames: Vec<String> },
+    SendMessage {
+        room_id: String,
+        content: String,
+        #[serde(default)]
+        reply_to: Option<String>,
+    },
+    EditMessage { message_id: String, content: String },
+    DeleteMessage { message_id: String },
+    AddReaction { message_id: String, emoji: String },
+    RemoveReaction { message_id: String, emoji: String },
+    /// Sent repeatedly while the user types in a subscribed room.
+    Typing { room_id: String },
+    /// Marks the room read up to and including the message.
+    MarkRead { room_id: String, message_id: String },
+}
+
+#[derive(Debug, Serialize)]
+pub struct ServerEnvelope {
+    pub v: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(flatten)]
+    pub message: ServerMessage,
+}
+
+impl ServerEnvelope {
+    pub fn new(id: Option<String>, message: ServerMessage) -> Self {
+        ServerEnvelope {
+            v: PROTOCOL_VERSION,
+            id,
+            message,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// The command succeeded. Commands that create or change something carry
+    /// the result.
+    Ack(Option<serde_json::Value>),
+    Error(ErrorPayload),
+    Event(EventPayload),
+    ThreadEvent(ThreadEventPayload),
+    RoomListEvent(RoomListEvent),
+    /// A message mentioning the account, sent whatever the subscriptions.
+    Mentioned(MentionEventDTO),
+    /// The server ended a subscription because its room was deleted.
+    Unsubscribed(UnsubscribedPayload),
+}
+
+#[derive(Debug, Serialize)]
+pub struct EventPayload {
+    pub room_id: String,
+    #[serde(flatten)]
+    pub event: RoomEvent,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ThreadEventPayload {
+    pub thread_id: String,
+    pub room_id: String,
+    #[serde(flatten)]
+    pub event: RoomEvent,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UnsubscribedPayload {
+    pub room_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProtocolError {
+    InvalidEnvelope,
+    UnsupportedVersion,
+    InvalidRequest,
+    NotSubscribed,
+    TooManySubscriptions,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::InvalidEnvelope => write!(f, "Envelope is not a valid command"),
+            ProtocolError::UnsupportedVersion => write!(f, "Unsupported protocol version, expected {}", PROTOCOL_VERSION),
+            ProtocolError::InvalidRequest => write!(f, "Unknown command or invalid payload"),
+            ProtocolError::NotSubscribed => write!(f, "Not subscribed"),
+            ProtocolError::TooManySubscriptions => write!(f, "Too many subscriptions on this connection"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(untagged)]
+pub enum ErrorCode {
+    Room(RoomError),
+    Protocol(ProtocolError),
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorPayload {
+    pub error: ErrorCode,
+    pub message: String,
+}
+
+impl From<RoomError> for ErrorPayload {
+    fn from(error: RoomError) -> Self {
+        ErrorPayload {
+            error: ErrorCode::Room(error),
+            message: error.to_string(),
+        }
+    }
+}
+
+impl From<ProtocolError> for ErrorPayload {
+    fn from(error: ProtocolError) -> Self {
+        ErrorPayload {
+            error: ErrorCode::Protocol(error),
+            message: error.to_string(),
+        }
+    }
+}
@@ -1,9 +1,245 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Clone)]
 pub struct Message {
     pub id: String,
     pub username: String,
+    /// Empty once the message is deleted.
     pub content: String,
     pub date: String,
-}
\ No newline at end of file
+    pub edited_at: Option<String>,
+    pub deleted_at: Option<String>,
+    /// The message this one answers.
+    pub reply_to: Option<String>,
+    /// The first message of the thread this reply belongs to. `None` for
+    /// messages that are not replies, which may themselves be thread roots.
+    pub thread_root: Option<String>,
+    /// Replies in the thread started by this message.
+    pub reply_count: i64,
+    pub quote: Option<QuoteDTO>,
+    /// One entry per emoji, in the order the emoji were first used.
+    pub reactions: Vec<ReactionDTO>,
+    /// Position of the message in its room's sequence.
+    pub seq: i64,
+    /// Room sequence number of the latest change to the message: its creation,
+    /// an edit, its deletion, a reaction or a new reply in its thread.
+    pub updated_seq: i64,
+    /// Handles from the content, without the `@`, that mention someone:
+    /// account names and the `room` and `here` groups. Clients highlight these.
+    pub mentions: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReactionDTO {
+    pub emoji: String,
+    pub count: i64,
+    pub usernames: Vec<String>,
+}
+
+/// A single reaction being added or removed. `count` is the number of
+/// reactions with this emoji after the change.
+#[derive(Debug, Serialize, Clone)]
+pub struct ReactionEventDTO {
+    pub message_id: String,
+    pub thread_root: Option<String>,
+    pub emoji: String,
+    pub username: String,
+    pub count: i64,
+    /// The message's `updated_seq` after the change.
+    pub updated_seq: i64,
+}
+
+/// Author and text of the message a reply quotes. The content is empty when
+/// the quoted message was deleted.
+#[derive(Debug, Serialize, Clone)]
+pub struct QuoteDTO {
+    pub username: String,
+    pub content: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ThreadDTO {
+    pub root: Message,
+    /// Oldest first.
+    pub replies: Vec<Message>,
+    pub has_more: bool,
+}
+
+pub const MAX_CONTENT_LENGTH: usize = 4000;
+
+/// Content must not be blank and is capped, as every message is stored,
+/// cached with its room and sent to each subscriber.
+pub fn is_valid_content(content: &str) -> bool {
+    !content.trim().is_empty() && content.chars().count() <= MAX_CONTENT_LENGTH
+}
+
+/// Emoji are kept short and free of whitespace, and multi-codepoint sequences
+/// work. The only ASCII allowed are the digits, `#` and `*` of keycaps, so no
+/// markup or quotes can be stored as a reaction.
+pub fn is_valid_emoji(emoji: &str) -> bool {
+    let length = emoji.chars().count();
+    (1..=16).contains(&length)
+        && !emoji.is_ascii()
+        && emoji.chars().all(|c| match c {
+            '0'..='9' | '#' | '*' => true,
+            c => !c.is_ascii() && !c.is_whitespace() && !c.is_control(),
+        })
+}
+
+/// `@handle` tokens of a message as written, without the `@`, in the order
+/// they first appear and without case-insensitive duplicates. A handle must
+/// follow the start of the text or a character that cannot be part of a word,
+/// so e-mail addresses mention no one, and trailing dots and dashes are left
+/// out so a mention can end a sentence.
+pub fn parse_mentions(content: &str) -> Vec<String> {
+    let is_handle_char = |c: char| c.is_alphanumeric() || matches!(c, '_' | '.' | '-');
+    let chars: Vec<char> = content.chars().collect();
+    let mut handles: Vec<String> = vec![];
+
+    let mut i = 0;
+    while i < chars.len() {
+        let at_word_start = i == 0 || !(chars[i - 1].is_alphanumeric() || chars[i - 1] == '_');
+        if chars[i] != '@' || !at_word_start {
+            i += 1;
+            continue;
+        }
+
+        let end = (i + 1..chars.len()).find(|&j| !is_handle_char(chars[j])).unwrap_or(chars.len());
+        let handle: String = chars[i + 1..end].iter().collect();
+        let handle = handle.trim_end_matches(['.', '-']);
+        if !handle.is_empty() && !handles.iter().any(|known| known.to_lowercase() == handle.to_lowercase()) {
+            handles.push(handle.to_string());
+        }
+        i = end;
+    }
+    handles
+}
+
+/// Sent to the mentioned accounts' connections, whether or not they are
+/// subscribed to the room.
+#[derive(Debug, Serialize, Clone)]
+pub struct MentionEventDTO {
+    #[serde(skip)]
+    pub account_ids: Vec<String>,
+    pub room_id: String,
+    pub message: Message,
+}
+
+/// A previous version of an edited message.
+#[derive(Debug, Serialize)]
+pub struct MessageEditDTO {
+    pub content: String,
+    pub editor_id: String,
+    pub edited_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EditMessageDTO {
+    pub content: String,
+}
+
+/// Position in a room's history, given as the id of a message the page
+/// should start right before or right after.
+#[derive(Debug, Clone)]
+pub enum MessageCursor {
+    Latest,
+    Before(String),
+    After(String),
+}
+
+#[derive(Debug, Serialize)]
+pub struct MessagePageDTO {
+    /// Oldest first, whichever direction the page was read in.
+    pub messages: Vec<Message>,
+    pub has_more: bool,
+}
+
+/// Filters for a full-text search. `from` and `to` are inclusive RFC 3339 bounds.
+#[derive(Debug, Clone)]
+pub struct MessageSearch {
+    pub query: String,
+    /// The searching account; rooms it cannot access are left out.
+    pub account_id: String,
+    pub room_id: Option<String>,
+    pub username: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub limit: usize,
+    pub offset: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchResultDTO {
+    pub id: String,
+    pub room_id: String,
+    pub room_name: String,
+    pub username: String,
+    pub content: String,
+    pub date: String,
+    /// HTML excerpt around the matches with each match wrapped in `<mark>`.
+    /// The message text in it is escaped.
+    pub snippet: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchPageDTO {
+    /// Best match first.
+    pub results: Vec<SearchResultDTO>,
+    pub has_more: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_content_length() {
+        assert!(is_valid_content("hello"));
+        assert!(is_valid_content(&"ż".repeat(MAX_CONTENT_LENGTH)));
+        assert!(!is_valid_content(" \n"));
+        assert!(!is_valid_content(&"a".repeat(MAX_CONTENT_LENGTH + 1)));
+    }
+
+    #[test]
+    fn accepts_emoji_sequences() {
+        for emoji in ["👍", "❤️", "👩‍👩‍👧", "🇵🇱", "1️⃣", "#️⃣", "👋🏽"] {
+            assert!(is_valid_emoji(emoji), "{}", emoji);
+        }
+    }
+
+    #[test]
+    fn rejects_markup_and_text() {
+        for emoji in ["", "<svg/onload=x>", "')-alert(1)-('", "👍<", "👍\"", "&amp;", "ok", "12", "👍 👍", "👍👍👍👍👍👍👍👍👍👍👍👍👍👍👍👍👍"] {
+            assert!(!is_valid_emoji(emoji), "{}", emoji);
+        }
+    }
+
+    #[test]
+    fn parses_handles_in_order() {
+        assert_eq!(parse_mentions("@bob hi @carol and @dave"), vec!["bob", "carol", "dave"]);
+        assert_eq!(parse_mentions("no mentions here"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn trims_surrounding_punctuation() {
+        assert_eq!(parse_mentions("thanks @bob, @carol. (@dave) @erin-!"), vec!["bob", "carol", "dave", "erin"]);
+        assert_eq!(parse_mentions("ask @j.doe. or @first-last"), vec!["j.doe", "first-last"]);
+        assert_eq!(parse_mentions("@zoë: hi"), vec!["zoë"]);
+    }
+
+    #[test]
+    fn ignores_email_addresses_and_bare_at_signs() {
+        assert_eq!(parse_mentions("mail a@b.com or bob@example.org"), Vec::<String>::new());
+        assert_eq!(parse_mentions("@ @. @- hi@ x_@bob"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn keeps_room_and_here() {
+        assert_eq!(parse_mentions("@room, then @here"), vec!["room", "here"]);
+    }
+
+    #[test]
+    fn drops_case_insensitive_duplicates() {
+        assert_eq!(parse_mentions("@Bob @bob @BOB @carol @Bob"), vec!["Bob", "carol"]);
+    }
+}
@@ -4,3 +4,7 @@ pub mod template;
 pub mod session;
 pub mod room;
 pub mod message;
+pub mod response;
+pub mod envelope;
+pub mod connection;
+pub mod presence;
\ No newline at end of file
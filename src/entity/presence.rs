@@ -0,0 +1,25 @@
+use serde::Serialize;
+
+/// Sent when an account's first subscription to a room opens, or its last
+/// one closes.
+#[derive(Debug, Serialize, Clone)]
+pub struct PresenceEventDTO {
+    pub account_id: String,
+    pub username: String,
+}
+
+/// Ephemeral and never stored. Clients show the indicator until
+/// `expires_in_ms` passes or a message from the same user arrives.
+#[derive(Debug, Serialize, Clone)]
+pub struct TypingEventDTO {
+    pub account_id: String,
+    pub username: String,
+    pub expires_in_ms: u64,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct OnlineMemberDTO {
+    pub account_id: String,
+    pub username: String,
+    pub typing: bool,
+}
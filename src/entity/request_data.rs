@@ -1,10 +1,13 @@
 use std::collections::HashMap;
-use tokio::net::TcpStream;
+use std::net::SocketAddr;
+use crate::entity::session::Session;
 
 pub struct RequestData {
-    pub(crate) stream: TcpStream,
-    pub(crate) buffer: [u8; 1024],
     pub(crate) method: String,
     pub(crate) path: String,
+    pub(crate) headers: HashMap<String, String>,
+    pub(crate) body: Vec<u8>,
     pub(crate) params: HashMap<String, String>,
-}
\ No newline at end of file
+    pub(crate) remote_addr: SocketAddr,
+    pub(crate) session: Option<Session>,
+}
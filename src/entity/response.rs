@@ -0,0 +1,196 @@
+use serde::Serialize;
+use crate::utils::http_helper::percent_encode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    Created,
+    Found,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    Conflict,
+    PayloadTooLarge,
+    UpgradeRequired,
+    TooManyRequests,
+    RequestHeaderFieldsTooLarge,
+    InternalServerError,
+}
+
+impl Status {
+    pub fn code(&self) -> u16 {
+        match self {
+            Status::Ok => 200,
+            Status::Created => 201,
+            Status::Found => 302,
+            Status::BadRequest => 400,
+            Status::Unauthorized => 401,
+            Status::Forbidden => 403,
+            Status::NotFound => 404,
+            Status::MethodNotAllowed => 405,
+            Status::Conflict => 409,
+            Status::PayloadTooLarge => 413,
+            Status::UpgradeRequired => 426,
+            Status::TooManyRequests => 429,
+            Status::RequestHeaderFieldsTooLarge => 431,
+            Status::InternalServerError => 500,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Status::Ok => "OK",
+            Status::Created => "Created",
+            Status::Found => "Found",
+            Status::BadRequest => "Bad Request",
+            Status::Unauthorized => "Unauthorized",
+            Status::Forbidden => "Forbidden",
+            Status::NotFound => "Not Found",
+            Status::MethodNotAllowed => "Method Not Allowed",
+            Status::Conflict => "Conflict",
+            Status::PayloadTooLarge => "Payload Too Large",
+            Status::UpgradeRequired => "Upgrade Required",
+            Status::TooManyRequests => "Too Many Requests",
+            Status::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            Status::InternalServerError => "Internal Server Error",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    pub path: String,
+    pub max_age: Option<i64>,
+    pub http_only: bool,
+    pub secure: bool,
+}
+
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Self {
+        Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: "/".to_string(),
+            max_age: None,
+            http_only: false,
+            secure: false,
+        }
+    }
+
+    /// A cookie that makes the browser drop any stored cookie with the same name.
+    pub fn expired(name: &str) -> Self {
+        Cookie {
+            max_age: Some(0),
+            ..Cookie::new(name, "")
+        }
+    }
+
+    /// The value is percent-encoded so arbitrary text (account names included)
+    /// cannot add attributes or break out of the header.
+    fn to_header_value(&self) -> String {
+        let mut value = format!("{}={}; Path={}", self.name, percent_encode(&self.value), self.path);
+        if let Some(max_age) = self.max_age {
+            value.push_str(&format!("; Max-Age={}", max_age));
+            if max_age <= 0 {
+                value.push_str("; Expires=Thu, 01 Jan 1970 00:00:00 GMT");
+            }
+        }
+        if self.http_only {
+            value.push_str("; HttpOnly");
+        }
+        if self.secure {
+            value.push_str("; Secure");
+        }
+        value.push_str("; SameSite=Strict");
+        value
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: Status,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: Status) -> Self {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn html(body: String) -> Self {
+        Response::new(Status::Ok)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(body.into_bytes())
+    }
+
+    pub fn text(status: Status, body: &str) -> Self {
+        Response::new(status)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(body.as_bytes().to_vec())
+    }
+
+    pub fn json<T: Serialize>(value: &T) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => Response::new(Status::Ok)
+                .with_header("Content-Type", "application/json")
+                .with_body(body),
+            Err(err) => {
+                eprintln!("Failed to serialize response: {}", err);
+                Response::new(Status::InternalServerError)
+            }
+        }
+    }
+
+    pub fn redirect(location: &str) -> Self {
+        Response::new(Status::Found).with_header("Location", location)
+    }
+
+    pub fn with_status(mut self, status: Status) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Sets a header, replacing any previous value with the same name.
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+
+    pub fn with_cookie(mut self, cookie: Cookie) -> Self {
+        self.headers.push(("Set-Cookie".to_string(), cookie.to_header_value()));
+        self
+    }
+
+    pub fn clear_cookie(self, name: &str) -> Self {
+        self.with_cookie(Cookie::expired(name))
+    }
+
+    pub fn to_bytes(&self, keep_alive: bool) -> Vec<u8> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status.code(), self.status.reason());
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        head.push_str(if keep_alive { "Connection: keep-alive\r\n" } else { "Connection: close\r\n" });
+        head.push_str("\r\n");
+
+        let mut bytes = head.into_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
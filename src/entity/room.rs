@@ -1,29 +1,438 @@
+use std::fmt;
 use serde::{Deserialize, Serialize};
 use tokio::sync::broadcast;
-use crate::entity::message::Message;
+use crate::entity::message::{Message, ReactionEventDTO};
+use crate::entity::presence::{PresenceEventDTO, TypingEventDTO};
+use crate::entity::response::Status;
+use crate::utils::config::CONFIG;
 
 #[derive(Debug, Serialize, Clone)]
 pub struct Room {
     pub id: String,
     pub name: String,
+    pub owner_id: Option<String>,
+    pub kind: RoomKind,
+    /// Empty when not set.
+    pub topic: String,
+    pub description: String,
+    pub created_at: String,
+    /// `None` for rooms created before creators were recorded.
+    pub created_by: Option<String>,
+    /// Archived rooms keep their history but accept no new messages, edits
+    /// or reactions.
+    pub archived: bool,
+    /// Sequence number of the room's latest event. Resuming clients pass it
+    /// back as `since` to replay what they missed.
+    pub last_seq: i64,
     pub messages: Vec<Message>,
     #[serde(skip)]
-    pub sender: broadcast::Sender<Message>,
+    pub sender: broadcast::Sender<RoomEvent>,
+}
+
+/// Published on a room's channel and sent to subscribers as JSON, tagged with
+/// `type` next to the message fields.
+#[derive(Debug, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RoomEvent {
+    MessageCreated(Message),
+    MessageEdited(Message),
+    MessageDeleted(Message),
+    /// Carries a thread root whose reply count changed.
+    ThreadUpdated(Message),
+    ReactionAdded(ReactionEventDTO),
+    ReactionRemoved(ReactionEventDTO),
+    MemberJoined(PresenceEventDTO),
+    MemberLeft(PresenceEventDTO),
+    Typing(TypingEventDTO),
+    ReadUpdated(ReadReceiptDTO),
+    /// The account left a private room. Its subscriptions to the room end
+    /// after this event.
+    AccessRevoked(PresenceEventDTO),
+    RoomUpdated(RoomUpdatedDTO),
+}
+
+impl RoomEvent {
+    /// Whether the event concerns the thread started by `root_id`.
+    pub fn is_in_thread(&self, root_id: &str) -> bool {
+        let (id, thread_root) = match self {
+            RoomEvent::MessageCreated(message)
+            | RoomEvent::MessageEdited(message)
+            | RoomEvent::MessageDeleted(message)
+            | RoomEvent::ThreadUpdated(message) => (&message.id, &message.thread_root),
+            RoomEvent::ReactionAdded(reaction)
+            | RoomEvent::ReactionRemoved(reaction) => (&reaction.message_id, &reaction.thread_root),
+            RoomEvent::MemberJoined(_)
+            | RoomEvent::MemberLeft(_)
+            | RoomEvent::Typing(_)
+            | RoomEvent::ReadUpdated(_)
+            | RoomEvent::AccessRevoked(_)
+            | RoomEvent::RoomUpdated(_) => return false,
+        };
+        id == root_id || thread_root.as_deref() == Some(root_id)
+    }
+
+    /// The room sequence number of the change the event describes. Presence,
+    /// typing, read, access and room events have none and are never replayed.
+    pub fn seq(&self) -> Option<i64> {
+        match self {
+            RoomEvent::MessageCreated(message)
+            | RoomEvent::MessageEdited(message)
+            | RoomEvent::MessageDeleted(message)
+            | RoomEvent::ThreadUpdated(message) => Some(message.updated_seq),
+            RoomEvent::ReactionAdded(reaction)
+            | RoomEvent::ReactionRemoved(reaction) => Some(reaction.updated_seq),
+            RoomEvent::MemberJoined(_)
+            | RoomEvent::MemberLeft(_)
+            | RoomEvent::Typing(_)
+            | RoomEvent::ReadUpdated(_)
+            | RoomEvent::AccessRevoked(_)
+            | RoomEvent::RoomUpdated(_) => None,
+        }
+    }
+
+    /// Rebuilds the event for a message changed after `since` from its stored
+    /// state. Edits, reactions and new replies all replay as `message_edited`
+    /// carrying the full message.
+    pub fn replay(message: Message, since: i64) -> Self {
+        if message.seq > since {
+            RoomEvent::MessageCreated(message)
+        } else if message.deleted_at.is_some() {
+            RoomEvent::MessageDeleted(message)
+        } else {
+            RoomEvent::MessageEdited(message)
+        }
+    }
 }
 
 impl Room {
-    pub(crate) fn new(id: String, name: String,) -> Self {
+    pub(crate) fn new(id: String, name: String, owner_id: Option<String>, kind: RoomKind, created_by: String) -> Self {
         let (sender, _receiver) = broadcast::channel(100);
         Room {
             id,
             name,
+            owner_id,
+            kind,
+            topic: String::new(),
+            description: String::new(),
+            created_at: chrono::Utc::now().to_rfc3339(),
+            created_by: Some(created_by),
+            archived: false,
+            last_seq: 0,
             messages: Vec::new(),
             sender,
         }
     }
+
+    /// Sends an event to the room's subscribers. Callers hold the cache lock
+    /// from allocating the event's sequence number until here, so subscribers
+    /// see events in sequence order.
+    pub(crate) fn publish(&mut self, event: RoomEvent) {
+        if let Some(seq) = event.seq() {
+            self.last_seq = self.last_seq.max(seq);
+        }
+        self.sender.send(event).unwrap_or(0);
+    }
+}
+
+/// Published on `ROOM_SENDER` when the list of rooms changes. Subscribers
+/// only receive events for rooms they can access.
+#[derive(Debug, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RoomListEvent {
+    Created(Room),
+    Updated(RoomUpdatedDTO),
+    /// Access can no longer be checked once the room is gone, so the members
+    /// who could see it are captured beforehand. `None` for a public room.
+    Deleted {
+        id: String,
+        #[serde(skip)]
+        account_ids: Option<Vec<String>>,
+    },
+    /// The account was added to a private room. Only that account is told;
+    /// the room's other members already list it.
+    Joined {
+        #[serde(flatten)]
+        room: Room,
+        #[serde(skip)]
+        account_id: String,
+    },
+    /// The account lost access to a private room by leaving it. Only that
+    /// account is told.
+    Left {
+        id: String,
+        #[serde(skip)]
+        account_id: String,
+    },
+}
+
+/// Public rooms are open to every account. Private rooms are open to their
+/// members, who join by invitation. Direct conversations have no owner and
+/// are open only to the participants they were opened with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomKind {
+    Public,
+    Private,
+    Direct,
+}
+
+impl RoomKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RoomKind::Public => "public",
+            RoomKind::Private => "private",
+            RoomKind::Direct => "direct",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "public" => Some(RoomKind::Public),
+            "private" => Some(RoomKind::Private),
+            "direct" => Some(RoomKind::Direct),
+            _ => None,
+        }
+    }
+}
+
+/// Accounts without a `room_members` row are plain members.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomRole {
+    Owner,
+    Moderator,
+    Member,
+}
+
+impl RoomRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RoomRole::Owner => "owner",
+            RoomRole::Moderator => "moderator",
+            RoomRole::Member => "member",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "owner" => Some(RoomRole::Owner),
+            "moderator" => Some(RoomRole::Moderator),
+            "member" => Some(RoomRole::Member),
+            _ => None,
+        }
+    }
+
+    /// Owners and moderators may update, archive and delete the room.
+    pub fn can_manage(&self) -> bool {
+        matches!(self, RoomRole::Owner | RoomRole::Moderator)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomError {
+    NotFound,
+    Forbidden,
+    InvalidRole,
+    InvalidCursor,
+    MessageNotFound,
+    MessageDeleted,
+    NotThreadRoot,
+    UnknownAccount,
+    InvalidParticipants,
+    InvalidInvite,
+    OwnerCannotLeave,
+    Archived,
+}
+
+impl RoomError {
+    pub fn status(&self) -> Status {
+        match self {
+            RoomError::NotFound
+            | RoomError::MessageNotFound
+            | RoomError::UnknownAccount
+            | RoomError::InvalidInvite => Status::NotFound,
+            RoomError::Forbidden => Status::Forbidden,
+            RoomError::InvalidRole
+            | RoomError::InvalidCursor
+            | RoomError::NotThreadRoot
+            | RoomError::InvalidParticipants
+            | RoomError::OwnerCannotLeave => Status::BadRequest,
+            RoomError::MessageDeleted | RoomError::Archived => Status::Conflict,
+        }
+    }
+}
+
+impl fmt::Display for RoomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoomError::NotFound => write!(f, "Room not found"),
+            RoomError::Forbidden => write!(f, "You do not have permission to do this"),
+            RoomError::InvalidRole => write!(f, "The owner role cannot be assigned or changed"),
+            RoomError::InvalidCursor => write!(f, "Cursor does not point into this room"),
+            RoomError::MessageNotFound => write!(f, "Message not found"),
+            RoomError::MessageDeleted => write!(f, "Message has been deleted"),
+            RoomError::NotThreadRoot => write!(f, "Message is a reply, not the start of a thread"),
+            RoomError::UnknownAccount => write!(f, "No account has that name"),
+            RoomError::InvalidParticipants => write!(
+                f,
+                "A direct conversation needs at least one other participant and at most {}",
+                CONFIG.max_direct_participants,
+            ),
+            RoomError::InvalidInvite => write!(f, "Invite link is invalid, used up or expired"),
+            RoomError::OwnerCannotLeave => write!(f, "The owner cannot leave the room"),
+            RoomError::Archived => write!(f, "Room is archived"),
+        }
+    }
+}
+
+/// Body of permission and lookup failures on both the REST and WebSocket room APIs.
+#[derive(Debug, Serialize)]
+pub struct RoomErrorDTO {
+    pub error: RoomError,
+    pub message: String,
+}
+
+impl From<RoomError> for RoomErrorDTO {
+    fn from(error: RoomError) -> Self {
+        RoomErrorDTO {
+            error,
+            message: error.to_string(),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
 pub struct CreateRoomDTO {
     pub name: String,
-}
\ No newline at end of file
+    #[serde(default)]
+    pub private: bool,
+}
+
+pub const MAX_NAME_LENGTH: usize = 100;
+pub const MAX_TOPIC_LENGTH: usize = 250;
+pub const MAX_DESCRIPTION_LENGTH: usize = 2000;
+
+/// Changes to a room's metadata. Fields left out stay as they are; an empty
+/// topic or description clears it. Also sent in `room_updated` events, with
+/// only the fields that actually changed.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct UpdateRoomDTO {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archived: Option<bool>,
+}
+
+/// Room names must not be blank and fit in the room list.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.trim().is_empty() && name.chars().count() <= MAX_NAME_LENGTH
+}
+
+impl UpdateRoomDTO {
+    pub fn is_valid(&self) -> bool {
+        self.name.as_ref().is_none_or(|name| is_valid_name(name))
+            && self.topic.as_ref().is_none_or(|topic| topic.chars().count() <= MAX_TOPIC_LENGTH)
+            && self.description.as_ref().is_none_or(|description| description.chars().count() <= MAX_DESCRIPTION_LENGTH)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none() && self.topic.is_none() && self.description.is_none() && self.archived.is_none()
+    }
+}
+
+/// Published to the room and the room list when its metadata changes.
+#[derive(Serialize, Debug, Clone)]
+pub struct RoomUpdatedDTO {
+    pub id: String,
+    #[serde(flatten)]
+    pub changes: UpdateRoomDTO,
+    pub updated_by: String,
+    pub updated_at: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UpdateMemberDTO {
+    pub role: RoomRole,
+}
+
+/// A room in the room list, with the caller's read position in it.
+#[derive(Debug, Serialize)]
+pub struct RoomSummaryDTO {
+    #[serde(flatten)]
+    pub room: Room,
+    pub last_read_seq: i64,
+    /// Messages from others after `last_read_seq` that are not deleted.
+    pub unread_count: i64,
+    /// Unread messages that mention the caller.
+    pub mention_count: i64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InviteMemberDTO {
+    pub username: String,
+}
+
+/// Invite links expire after at most a year.
+pub const MAX_INVITE_LINK_TTL_SECS: u64 = 365 * 24 * 60 * 60;
+
+/// Both limits are optional; a link without them works until revoked.
+#[derive(Deserialize, Debug)]
+pub struct CreateInviteLinkDTO {
+    pub expires_in_secs: Option<u64>,
+    pub max_uses: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InviteLinkDTO {
+    pub code: String,
+    pub room_id: String,
+    pub created_by: String,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+    pub max_uses: Option<i64>,
+    pub uses: i64,
+}
+
+/// A room member. Public rooms list the accounts that joined explicitly.
+#[derive(Debug, Serialize)]
+pub struct MemberDTO {
+    pub account_id: String,
+    pub username: String,
+    pub role: RoomRole,
+}
+
+/// Opens the direct conversation between the caller and these accounts.
+#[derive(Deserialize, Debug)]
+pub struct OpenDirectDTO {
+    pub usernames: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ParticipantDTO {
+    pub account_id: String,
+    pub username: String,
+}
+
+/// A direct conversation in the caller's list, with everyone taking part in
+/// it, the caller included.
+#[derive(Debug, Serialize)]
+pub struct DirectSummaryDTO {
+    #[serde(flatten)]
+    pub summary: RoomSummaryDTO,
+    pub participants: Vec<ParticipantDTO>,
+}
+
+/// How far an account has read a room. Published as `read_updated` whenever
+/// the position moves forward.
+#[derive(Debug, Serialize, Clone)]
+pub struct ReadReceiptDTO {
+    pub account_id: String,
+    pub username: String,
+    pub last_read_seq: i64,
+    pub updated_at: String,
+}
@@ -1,14 +1,35 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Clone)]
 pub struct Session {
     pub(crate) id: String,
-    pub(crate) token: String,
+    pub(crate) token_hash: String,
+    /// When the account logged in. Rotation keeps it, so it bounds the
+    /// session's whole lifetime.
+    pub(crate) created_at: DateTime<Utc>,
+    /// When the current token was issued.
+    pub(crate) rotated_at: DateTime<Utc>,
+    pub(crate) last_seen_at: DateTime<Utc>,
+    pub(crate) expires_at: DateTime<Utc>,
+    /// Set once the token was rotated: the hash of the token that replaced it
+    /// and when, which starts the old token's grace period.
+    pub(crate) replaced_by: Option<String>,
+    pub(crate) replaced_at: Option<DateTime<Utc>>,
+}
+
+impl Session {
+    /// The hash of the token the session is currently known by, which is the
+    /// replacement while a rotated token is still in its grace period.
+    pub fn current_token_hash(&self) -> &str {
+        self.replaced_by.as_deref().unwrap_or(&self.token_hash)
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct SessionTokenDTO {
     pub id: String,
     pub name: String,
-    pub token: String,
-}
\ No newline at end of file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
@@ -4,22 +4,48 @@ mod entity;
 mod repository;
 use std::{fs};
 use std::path::Path;
-use rusqlite::{Connection};
+use rusqlite::Connection;
 use tokio::io;
 use tokio::net::TcpListener;
 use crate::controller::controller::init;
-use crate::repository::{account, room};
+use crate::repository::{account, room, session};
+use crate::utils::config::CONFIG;
 
 #[tokio::main]
 async fn main() {
     init_db().await.expect("Unable to create a database.");
     account::init_cache();
-    room::init_cache();
+    tokio::spawn(cleanup_sessions());
+    tokio::spawn(evict_idle_rooms());
 
     let listener = TcpListener::bind("127.0.0.1:3000").await.unwrap();
     init(listener).await.expect("Error occurred on controller init");
 }
 
+async fn cleanup_sessions() {
+    let mut interval = tokio::time::interval(CONFIG.session_cleanup_interval);
+    loop {
+        interval.tick().await;
+        let removed = tokio::task::spawn_blocking(session::cleanup_expired_sessions)
+            .await
+            .unwrap_or(0);
+        if removed > 0 {
+            println!("Removed {} expired sessions.", removed);
+        }
+    }
+}
+
+async fn evict_idle_rooms() {
+    let mut interval = tokio::time::interval(CONFIG.room_cache_ttl);
+    loop {
+        interval.tick().await;
+        let evicted = room::evict_idle_rooms();
+        if evicted > 0 {
+            println!("Evicted {} idle rooms from cache.", evicted);
+        }
+    }
+}
+
 pub async fn init_db() -> io::Result<()> {
     let db_path = Path::new("data.db");
     let schema_path = Path::new("schema.sql");
@@ -38,10 +64,7 @@ pub async fn init_db() -> io::Result<()> {
     }
 
     let conn = Connection::open(db_path).map_err(|err| {
-        io::Error::new(
-            io::ErrorKind::Other,
-            format!("Failed to open database file: {}", err),
-        )
+        io::Error::other(format!("Failed to open database file: {}", err))
     })?;
 
     let schema = fs::read_to_string(schema_path).map_err(|err| {
@@ -51,6 +74,12 @@ pub async fn init_db() -> io::Result<()> {
         )
     })?;
 
+    migrate(&conn).map_err(|err| {
+        io::Error::other(format!("Failed to migrate database: {}", err))
+    })?;
+
+    let had_search_index = table_exists(&conn, "messages_fts").unwrap_or(false);
+
     conn.execute_batch(&schema).map_err(|err| {
         io::Error::new(
             io::ErrorKind::InvalidInput,
@@ -58,5 +87,90 @@ pub async fn init_db() -> io::Result<()> {
         )
     })?;
 
+    // Messages written before the search index existed are not in it yet.
+    if !had_search_index {
+        conn.execute_batch("INSERT INTO messages_fts (messages_fts) VALUES ('rebuild');").map_err(|err| {
+            io::Error::other(format!("Failed to build search index: {}", err))
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Brings databases created from an older schema.sql up to date. Runs before
+/// the schema itself so the indexes there can refer to the added columns.
+fn migrate(conn: &Connection) -> rusqlite::Result<()> {
+    // Rooms from before ownership go to whoever posted in them first. Rooms
+    // nobody posted in stay without an owner.
+    if add_column_if_missing(conn, "rooms", "owner_id", "TEXT REFERENCES accounts(id) ON DELETE SET NULL")? {
+        conn.execute_batch(
+            "UPDATE rooms SET owner_id = (
+                 SELECT a.id FROM messages m JOIN accounts a ON a.name = m.username
+                 WHERE m.room_id = rooms.id ORDER BY m.date LIMIT 1
+             );",
+        )?;
+    }
+    add_column_if_missing(conn, "messages", "edited_at", "TEXT")?;
+    add_column_if_missing(conn, "messages", "deleted_at", "TEXT")?;
+    add_column_if_missing(conn, "messages", "reply_to", "TEXT REFERENCES messages(id)")?;
+    add_column_if_missing(conn, "messages", "thread_root", "TEXT REFERENCES messages(id)")?;
+    add_column_if_missing(conn, "rooms", "last_seq", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "rooms", "kind", "TEXT NOT NULL DEFAULT 'public'")?;
+    add_column_if_missing(conn, "rooms", "direct_key", "TEXT")?;
+    add_column_if_missing(conn, "rooms", "topic", "TEXT NOT NULL DEFAULT ''")?;
+    add_column_if_missing(conn, "rooms", "description", "TEXT NOT NULL DEFAULT ''")?;
+    add_column_if_missing(conn, "rooms", "archived", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "rooms", "created_by", "TEXT REFERENCES accounts(id) ON DELETE SET NULL")?;
+
+    // Older rooms were created by their owner, no later than their first message.
+    if add_column_if_missing(conn, "rooms", "created_at", "TEXT NOT NULL DEFAULT ''")? {
+        conn.execute(
+            "UPDATE rooms SET created_by = owner_id,
+                 created_at = COALESCE((SELECT MIN(date) FROM messages WHERE messages.room_id = rooms.id), ?1);",
+            [chrono::Utc::now().to_rfc3339()],
+        )?;
+    }
+    add_column_if_missing(conn, "messages", "updated_seq", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "messages", "mentions", "TEXT NOT NULL DEFAULT '[]'")?;
+
+    // Existing messages are numbered in the order they were sent.
+    if add_column_if_missing(conn, "messages", "seq", "INTEGER NOT NULL DEFAULT 0")? {
+        conn.execute_batch(
+            "UPDATE messages SET seq = numbered.seq, updated_seq = numbered.seq
+             FROM (SELECT id, ROW_NUMBER() OVER (PARTITION BY room_id ORDER BY date, id) AS seq FROM messages) AS numbered
+             WHERE messages.id = numbered.id;
+             UPDATE rooms SET last_seq = (SELECT COALESCE(MAX(seq), 0) FROM messages WHERE messages.room_id = rooms.id);",
+        )?;
+    }
+
+    // The search index is keyed on `search_id`, as the implicit rowid of a
+    // table with a TEXT primary key may be renumbered by VACUUM.
+    if add_column_if_missing(conn, "messages", "search_id", "INTEGER")? {
+        conn.execute_batch("UPDATE messages SET search_id = rowid;")?;
+    }
+    add_column_if_missing(conn, "sessions", "replaced_by", "TEXT")?;
+    add_column_if_missing(conn, "sessions", "replaced_at", "TEXT")?;
     Ok(())
 }
+
+fn table_exists(conn: &Connection, table: &str) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE name = ?1);",
+        [table],
+        |row| row.get(0),
+    )
+}
+
+/// Returns whether the column was added.
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> rusqlite::Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({});", table))?;
+    let columns: Vec<String> = stmt.query_map([], |row| row.get(1))?.flatten().collect();
+
+    // A missing table is created with the column by schema.sql.
+    if columns.is_empty() || columns.iter().any(|existing| existing == column) {
+        return Ok(false);
+    }
+
+    conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} {};", table, column, definition))?;
+    Ok(true)
+}
@@ -2,15 +2,14 @@ use serde::Serialize;
 use std::sync::{Mutex};
 use uuid::Uuid;
 use crate::entity::account::Account;
-use constant_time_eq::constant_time_eq;
 use rusqlite::{Connection};
+use crate::utils::password::{hash_password, needs_rehash, verify_dummy_password, verify_password};
+use crate::repository::open_database;
 
 lazy_static::lazy_static! {
     #[derive(Debug, Serialize, Clone)]
     pub static ref ACCOUNTS: Mutex<Vec<Account>> = Mutex::new(vec![]);
-    pub static ref DB: Mutex<Connection> = Mutex::new(
-        Connection::open("data.db").expect("Failed to connect to SQLite")
-    );
+    pub static ref DB: Mutex<Connection> = Mutex::new(open_database("data.db"));
 }
 
 pub fn init_cache() {
@@ -35,28 +34,25 @@ pub fn init_cache() {
     }
 }
 
-pub fn is_sha256_hash(input: &str) -> bool {
-    input.len() == 64 && input.chars().all(|c| c.is_digit(16))
-}
-
-pub async fn insert_account(name: String, password: String) {
+pub fn insert_account(name: String, password: String) -> Result<(), String> {
     let account = Account {
         id: Uuid::new_v4().to_string(),
-        name: name.clone(),
-        password: password.clone(),
+        name,
+        password: hash_password(&password)?,
     };
 
     {
         let conn = DB.lock().unwrap();
         conn.execute(
             "INSERT INTO accounts (id, name, password) VALUES (?1, ?2, ?3);",
-            &[&account.id, &account.name, &account.password],
+            [&account.id, &account.name, &account.password],
         )
-            .expect("Failed to insert account into database");
+            .map_err(|err| format!("Failed to insert account into database: {}", err))?;
     }
 
     let mut accounts = ACCOUNTS.lock().unwrap();
     accounts.push(account);
+    Ok(())
 }
 
 pub async fn get_account_by_id(id: String) -> Option<Account> {
@@ -89,20 +85,47 @@ pub async fn get_account_by_id(id: String) -> Option<Account> {
 }
 
 pub fn match_and_return_account(name: &str, password: &str) -> Option<Account> {
-    {
+    let cached = {
         let accounts = ACCOUNTS.lock().unwrap();
-        if let Some(account) = accounts.iter().find(|account| {
-            account.name == name && constant_time_eq(account.password.as_bytes(), password.as_bytes())
-        }) {
-            return Some(account.clone());
+        accounts.iter().find(|account| account.name == name).cloned()
+    };
+
+    let account = match cached {
+        Some(account) => account,
+        None => {
+            let account = match get_account_by_name(name) {
+                Some(account) => account,
+                None => {
+                    verify_dummy_password(password);
+                    return None;
+                }
+            };
+            let mut accounts = ACCOUNTS.lock().unwrap();
+            accounts.push(account.clone());
+            account
         }
+    };
+
+    if !verify_password(&account.password, password) {
+        return None;
     }
 
+    if needs_rehash(&account.password) {
+        match hash_password(password) {
+            Ok(hash) => return Some(update_password(account, hash)),
+            Err(err) => eprintln!("{}", err),
+        }
+    }
+
+    Some(account)
+}
+
+fn get_account_by_name(name: &str) -> Option<Account> {
     let conn = DB.lock().unwrap();
     let mut stmt = conn
         .prepare("SELECT id, name, password FROM accounts WHERE name = ?1;")
         .expect("Failed to prepare statement");
-    let account_iter = stmt
+    let mut account_iter = stmt
         .query_map([name], |row| {
             Ok(Account {
                 id: row.get(0)?,
@@ -112,12 +135,26 @@ pub fn match_and_return_account(name: &str, password: &str) -> Option<Account> {
         })
         .expect("Failed to query account by name");
 
-    for account in account_iter.flatten() {
-        if constant_time_eq(account.password.as_bytes(), password.as_bytes()) {
-            let mut accounts = ACCOUNTS.lock().unwrap();
-            accounts.push(account.clone());
-            return Some(account);
+    account_iter.next().and_then(|account| account.ok())
+}
+
+/// Replaces the stored hash, e.g. when upgrading a legacy row after a successful login.
+fn update_password(mut account: Account, hash: String) -> Account {
+    {
+        let conn = DB.lock().unwrap();
+        if let Err(err) = conn.execute(
+            "UPDATE accounts SET password = ?1 WHERE id = ?2;",
+            [&hash, &account.id],
+        ) {
+            eprintln!("Failed to update password hash: {}", err);
+            return account;
         }
     }
-    None
-}
\ No newline at end of file
+
+    account.password = hash;
+    let mut accounts = ACCOUNTS.lock().unwrap();
+    if let Some(cached) = accounts.iter_mut().find(|cached| cached.id == account.id) {
+        cached.password = account.password.clone();
+    }
+    account
+}
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use chrono::{DateTime, Utc};
+use tokio::sync::oneshot;
+use uuid::Uuid;
+use crate::entity::connection::ConnectionDTO;
+
+/// Close code and reason a connection is asked to close with.
+pub type CloseRequest = (u16, &'static str);
+
+struct Connection {
+    account_id: String,
+    token_hash: String,
+    remote_addr: SocketAddr,
+    connected_at: DateTime<Utc>,
+    last_seen_at: DateTime<Utc>,
+    closer: Option<oneshot::Sender<CloseRequest>>,
+}
+
+impl Connection {
+    fn to_dto(&self, id: &str) -> ConnectionDTO {
+        ConnectionDTO {
+            id: id.to_string(),
+            remote_addr: self.remote_addr.to_string(),
+            connected_at: self.connected_at.to_rfc3339(),
+            last_seen_at: self.last_seen_at.to_rfc3339(),
+        }
+    }
+
+    fn close(&mut self, request: CloseRequest) -> bool {
+        self.closer.take().map(|closer| closer.send(request).is_ok()).unwrap_or(false)
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref CONNECTIONS: Mutex<HashMap<String, Connection>> = Mutex::new(HashMap::new());
+}
+
+/// Registers an open socket. The receiver resolves when someone asks for the
+/// connection to be closed.
+pub fn register(account_id: String, token_hash: String, remote_addr: SocketAddr) -> (String, oneshot::Receiver<CloseRequest>) {
+    let id = Uuid::new_v4().to_string();
+    let (closer, close_requested) = oneshot::channel();
+    let now = Utc::now();
+
+    CONNECTIONS.lock().unwrap().insert(id.clone(), Connection {
+        account_id,
+        token_hash,
+        remote_addr,
+        connected_at: now,
+        last_seen_at: now,
+        closer: Some(closer),
+    });
+    (id, close_requested)
+}
+
+pub fn unregister(id: &str) {
+    CONNECTIONS.lock().unwrap().remove(id);
+}
+
+pub fn touch(id: &str) {
+    if let Some(connection) = CONNECTIONS.lock().unwrap().get_mut(id) {
+        connection.last_seen_at = Utc::now();
+    }
+}
+
+/// The account's open connections, oldest first.
+pub fn get_by_account(account_id: &str) -> Vec<ConnectionDTO> {
+    let connections = CONNECTIONS.lock().unwrap();
+    let mut owned: Vec<ConnectionDTO> = connections
+        .iter()
+        .filter(|(_, connection)| connection.account_id == account_id)
+        .map(|(id, connection)| connection.to_dto(id))
+        .collect();
+    owned.sort_by(|a, b| a.connected_at.cmp(&b.connected_at));
+    owned
+}
+
+/// Closes one of the account's connections. Returns false when the account
+/// has no such connection.
+pub fn close(id: &str, account_id: &str, request: CloseRequest) -> bool {
+    match CONNECTIONS.lock().unwrap().get_mut(id) {
+        Some(connection) if connection.account_id == account_id => connection.close(request),
+        _ => false,
+    }
+}
+
+/// Moves connections opened with a rotated token over to its replacement, so
+/// they still close when the session is logged out.
+pub fn rekey_session(old_token_hash: &str, new_token_hash: &str) {
+    for connection in CONNECTIONS.lock().unwrap().values_mut() {
+        if connection.token_hash == old_token_hash {
+            connection.token_hash = new_token_hash.to_string();
+        }
+    }
+}
+
+/// Closes every connection opened with the session, e.g. once it is logged out.
+pub fn close_session(token_hash: &str, request: CloseRequest) -> usize {
+    CONNECTIONS
+        .lock()
+        .unwrap()
+        .values_mut()
+        .filter(|connection| connection.token_hash == token_hash)
+        .map(|connection| connection.close(request))
+        .filter(|closed| *closed)
+        .count()
+}
@@ -0,0 +1,195 @@
+use std::sync::Mutex;
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, Connection, Row};
+use crate::entity::message::{MessageSearch, SearchPageDTO, SearchResultDTO};
+use crate::utils::http_helper::escape_html;
+use crate::repository::open_database;
+
+/// Placed around matches by `snippet()` and replaced with `<mark>` tags once
+/// the excerpt has been escaped.
+const MATCH_START: char = '\u{2}';
+const MATCH_END: char = '\u{3}';
+
+lazy_static::lazy_static! {
+    pub static ref DB: Mutex<Connection> = Mutex::new(open_database("data.db"));
+}
+
+fn search_result_from_row(row: &Row) -> rusqlite::Result<SearchResultDTO> {
+    Ok(SearchResultDTO {
+        id: row.get(0)?,
+        room_id: row.get(1)?,
+        room_name: row.get(2)?,
+        username: row.get(3)?,
+        content: row.get(4)?,
+        date: row.get(5)?,
+        snippet: mark_snippet(&row.get::<_, String>(6)?),
+    })
+}
+
+/// Escapes an excerpt and turns its match delimiters into `<mark>` tags.
+/// Delimiters typed into the message itself are never left unbalanced.
+fn mark_snippet(excerpt: &str) -> String {
+    let mut marked = String::with_capacity(excerpt.len());
+    let mut open = false;
+    for part in excerpt.split_inclusive([MATCH_START, MATCH_END]) {
+        let (text, delimiter) = match part.chars().last() {
+            Some(c @ (MATCH_START | MATCH_END)) => (&part[..part.len() - c.len_utf8()], Some(c)),
+            _ => (part, None),
+        };
+        marked.push_str(&escape_html(text));
+        match delimiter {
+            Some(MATCH_START) if !open => {
+                marked.push_str("<mark>");
+                open = true;
+            }
+            Some(MATCH_END) if open => {
+                marked.push_str("</mark>");
+                open = false;
+            }
+            _ => {}
+        }
+    }
+    if open {
+        marked.push_str("</mark>");
+    }
+    marked
+}
+
+/// Turns free text into an FTS5 query that matches messages containing every
+/// word. Each word is quoted so FTS5 operators typed by users are taken
+/// literally; the last word also matches as a prefix.
+fn to_match_expression(query: &str) -> Option<String> {
+    let words: Vec<String> = query
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect();
+
+    let last = words.last()?;
+    let mut expression = words[..words.len() - 1].to_vec();
+    expression.push(format!("{}*", last));
+    Some(expression.join(" "))
+}
+
+/// Ranks matching messages with BM25. Only messages in rooms the caller can
+/// access are returned.
+pub fn search(search: &MessageSearch) -> rusqlite::Result<SearchPageDTO> {
+    search_with(&DB.lock().unwrap(), search)
+}
+
+fn search_with(conn: &Connection, search: &MessageSearch) -> rusqlite::Result<SearchPageDTO> {
+    let expression = match to_match_expression(&search.query) {
+        Some(expression) => expression,
+        None => return Ok(SearchPageDTO { results: vec![], has_more: false }),
+    };
+
+    let mut sql = String::from(
+        "SELECT m.id, m.room_id, r.name, m.username, m.content, m.date,
+                snippet(messages_fts, 0, char(2), char(3), '…', 16)
+         FROM messages_fts
+         JOIN messages m ON m.search_id = messages_fts.rowid
+         JOIN rooms r ON r.id = m.room_id
+         WHERE messages_fts MATCH ? AND m.deleted_at IS NULL
+           AND (r.kind = 'public' OR EXISTS (SELECT 1 FROM room_members x WHERE x.room_id = r.id AND x.account_id = ?))",
+    );
+    let mut values = vec![Value::Text(expression), Value::Text(search.account_id.clone())];
+
+    if let Some(room_id) = &search.room_id {
+        sql.push_str(" AND m.room_id = ?");
+        values.push(Value::Text(room_id.clone()));
+    }
+    if let Some(username) = &search.username {
+        sql.push_str(" AND m.username = ?");
+        values.push(Value::Text(username.clone()));
+    }
+    if let Some(from) = &search.from {
+        sql.push_str(" AND m.date >= ?");
+        values.push(Value::Text(from.clone()));
+    }
+    if let Some(to) = &search.to {
+        sql.push_str(" AND m.date <= ?");
+        values.push(Value::Text(to.clone()));
+    }
+
+    sql.push_str(" ORDER BY messages_fts.rank, m.date DESC LIMIT ? OFFSET ?;");
+    values.push(Value::Integer((search.limit + 1) as i64));
+    values.push(Value::Integer(search.offset as i64));
+
+    let mut results: Vec<SearchResultDTO> = conn
+        .prepare(&sql)?
+        .query_map(params_from_iter(values), search_result_from_row)?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let has_more = results.len() > search.limit;
+    results.truncate(search.limit);
+    Ok(SearchPageDTO { results, has_more })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mark_snippet_escapes_content() {
+        assert_eq!(
+            mark_snippet("<b>\u{2}hello\u{3}</b> & \u{2}world\u{3}"),
+            "&lt;b&gt;<mark>hello</mark>&lt;/b&gt; &amp; <mark>world</mark>"
+        );
+    }
+
+    #[test]
+    fn mark_snippet_balances_delimiters() {
+        assert_eq!(mark_snippet("a\u{3}b\u{2}c\u{2}d"), "ab<mark>cd</mark>");
+    }
+
+    fn database() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("../../schema.sql")).unwrap();
+        conn.execute_batch(
+            "INSERT INTO rooms (id, name) VALUES ('room', 'general');
+             INSERT INTO messages (id, room_id, username, content, date) VALUES
+                 ('m1', 'room', 'alice', 'alpha', '2024-01-01T00:00:00+00:00'),
+                 ('m2', 'room', 'alice', 'beta', '2024-01-01T00:00:01+00:00'),
+                 ('m3', 'room', 'alice', 'gamma', '2024-01-01T00:00:02+00:00');",
+        )
+        .unwrap();
+        conn
+    }
+
+    fn found(conn: &Connection, query: &str) -> Vec<String> {
+        let search = MessageSearch {
+            query: query.to_string(),
+            account_id: "alice-id".to_string(),
+            room_id: None,
+            username: None,
+            from: None,
+            to: None,
+            limit: 10,
+            offset: 0,
+        };
+        search_with(conn, &search).unwrap().results.into_iter().map(|result| result.id).collect()
+    }
+
+    /// VACUUM may renumber the implicit rowids of `messages`, which must not
+    /// detach the index from the messages it was built from.
+    #[test]
+    fn search_survives_renumbered_rowids() {
+        let conn = database();
+        conn.execute_batch("UPDATE messages SET rowid = 10 - rowid;").unwrap();
+        assert_eq!(found(&conn, "gamma"), vec!["m3"]);
+        assert_eq!(found(&conn, "alpha"), vec!["m1"]);
+    }
+
+    #[test]
+    fn search_follows_edits() {
+        let conn = database();
+        conn.execute("UPDATE messages SET content = 'delta' WHERE id = 'm2';", []).unwrap();
+        assert_eq!(found(&conn, "beta"), Vec::<String>::new());
+        assert_eq!(found(&conn, "delta"), vec!["m2"]);
+    }
+
+    #[test]
+    fn match_expression_quotes_words() {
+        assert_eq!(to_match_expression("foo \"bar"), Some("\"foo\" \"\"\"bar\"*".to_string()));
+        assert_eq!(to_match_expression("  "), None);
+    }
+}
@@ -1,3 +1,17 @@
+use rusqlite::Connection;
+
 pub mod account;
 pub mod session;
-pub mod room;
\ No newline at end of file
+pub mod room;
+pub mod room_cache;
+pub mod message;
+pub mod connection;
+pub mod presence;
+
+/// Opens a repository connection. SQLite only enforces foreign keys, and so
+/// the cascades in schema.sql, on connections that turn them on.
+pub fn open_database(path: &str) -> Connection {
+    let conn = Connection::open(path).expect("Failed to connect to SQLite");
+    conn.pragma_update(None, "foreign_keys", true).expect("Failed to enable foreign keys");
+    conn
+}
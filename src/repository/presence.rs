@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+use crate::entity::account::Account;
+use crate::entity::presence::OnlineMemberDTO;
+use crate::utils::config::CONFIG;
+
+struct Member {
+    username: String,
+    /// Open subscriptions to the room, one per socket.
+    connections: usize,
+    typing_until: Option<Instant>,
+    typing_announced_at: Option<Instant>,
+}
+
+lazy_static::lazy_static! {
+    /// Room id to the accounts subscribed to it. Only rooms with at least one
+    /// subscriber have an entry.
+    static ref PRESENCE: Mutex<HashMap<String, HashMap<String, Member>>> = Mutex::new(HashMap::new());
+}
+
+/// Counts a new subscription of the account to the room. Returns whether it
+/// is the account's first, i.e. whether the account just came online there.
+pub fn join(room_id: &str, account: &Account) -> bool {
+    let mut presence = PRESENCE.lock().unwrap();
+    let member = presence
+        .entry(room_id.to_string())
+        .or_default()
+        .entry(account.id.clone())
+        .or_insert_with(|| Member {
+            username: account.name.clone(),
+            connections: 0,
+            typing_until: None,
+            typing_announced_at: None,
+        });
+
+    member.connections += 1;
+    member.connections == 1
+}
+
+/// Counts a closed subscription. Returns whether it was the account's last.
+pub fn leave(room_id: &str, account_id: &str) -> bool {
+    let mut presence = PRESENCE.lock().unwrap();
+    let members = match presence.get_mut(room_id) {
+        Some(members) => members,
+        None => return false,
+    };
+
+    let gone = match members.get_mut(account_id) {
+        Some(member) => {
+            member.connections -= 1;
+            member.connections == 0
+        }
+        None => return false,
+    };
+
+    if gone {
+        members.remove(account_id);
+        if members.is_empty() {
+            presence.remove(room_id);
+        }
+    }
+    gone
+}
+
+/// Accounts subscribed to the room, by name.
+pub fn get_online(room_id: &str) -> Vec<OnlineMemberDTO> {
+    let presence = PRESENCE.lock().unwrap();
+    let now = Instant::now();
+
+    let mut online: Vec<OnlineMemberDTO> = presence
+        .get(room_id)
+        .map(|members| {
+            members
+                .iter()
+                .map(|(account_id, member)| OnlineMemberDTO {
+                    account_id: account_id.clone(),
+                    username: member.username.clone(),
+                    typing: member.typing_until.is_some_and(|until| until > now),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    online.sort_by(|a, b| a.username.cmp(&b.username));
+    online
+}
+
+/// Marks the account as typing for `typing_ttl`. Returns whether subscribers
+/// should be told, which at most happens once per `typing_throttle`; clients
+/// keep sending while the user types, so the indicator stays up regardless.
+/// `None` when the account is not in the room.
+pub fn start_typing(room_id: &str, account_id: &str) -> Option<bool> {
+    let mut presence = PRESENCE.lock().unwrap();
+    let member = presence.get_mut(room_id)?.get_mut(account_id)?;
+    let now = Instant::now();
+
+    member.typing_until = Some(now + CONFIG.typing_ttl);
+    let announce = member
+        .typing_announced_at
+        .is_none_or(|announced_at| now.duration_since(announced_at) >= CONFIG.typing_throttle);
+    if announce {
+        member.typing_announced_at = Some(now);
+    }
+    Some(announce)
+}
+
+/// Sending a message ends typing, so the next keystroke is announced again.
+pub fn stop_typing(room_id: &str, account_id: &str) {
+    let mut presence = PRESENCE.lock().unwrap();
+    if let Some(member) = presence.get_mut(room_id).and_then(|members| members.get_mut(account_id)) {
+        member.typing_until = None;
+        member.typing_announced_at = None;
+    }
+}
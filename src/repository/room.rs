@@ -1,217 +1,1494 @@
-use std::collections::HashMap;
 use std::sync::Mutex;
 use tokio::sync::{broadcast};
-use serde::Serialize;
 use uuid::Uuid;
-use crate::entity::message::Message;
-use crate::entity::room::Room;
+use crate::entity::account::Account;
+use crate::entity::message::{parse_mentions, MentionEventDTO, Message, MessageCursor, MessageEditDTO, MessagePageDTO, QuoteDTO, ReactionEventDTO, ThreadDTO};
+use crate::entity::presence::PresenceEventDTO;
+use crate::entity::room::{DirectSummaryDTO, InviteLinkDTO, MemberDTO, ParticipantDTO, ReadReceiptDTO, Room, RoomError, RoomEvent, RoomKind, RoomListEvent, RoomRole, RoomSummaryDTO, RoomUpdatedDTO, UpdateRoomDTO};
 use once_cell::sync::Lazy;
-use rusqlite::Connection;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use rusqlite::types::Type;
+use crate::repository::presence;
+use crate::repository::room_cache::RoomCache;
+use crate::utils::config::CONFIG;
+use crate::repository::open_database;
 
 lazy_static::lazy_static! {
-    #[derive(Debug, Serialize, Clone)]
-    pub static ref ROOMS: Mutex<HashMap<String, Room>> = Mutex::new(HashMap::new());
-    pub static ref DB: Mutex<Connection> = Mutex::new(
-        Connection::open("data.db").expect("Failed to connect to SQLite")
+    static ref ROOMS: Mutex<RoomCache> = Mutex::new(
+        RoomCache::new(CONFIG.room_cache_capacity, CONFIG.room_cache_ttl)
     );
+    pub static ref DB: Mutex<Connection> = Mutex::new(open_database("data.db"));
 }
 
-pub static ROOM_SENDER: Lazy<broadcast::Sender<Room>> = Lazy::new(|| {
+pub static ROOM_SENDER: Lazy<broadcast::Sender<RoomListEvent>> = Lazy::new(|| {
     let (sender, _receiver) = broadcast::channel(100);
     sender
 });
 
-pub fn init_cache() {
-    let conn = DB.lock().unwrap();
+/// Mentions for every connection; each one keeps those of its own account.
+pub static MENTION_SENDER: Lazy<broadcast::Sender<MentionEventDTO>> = Lazy::new(|| {
+    let (sender, _receiver) = broadcast::channel(100);
+    sender
+});
 
-    let mut stmt = conn
-        .prepare("SELECT id, name FROM rooms;")
-        .expect("Failed to prepare statement");
+/// Columns read by `room_from_row`, selected from `rooms r`.
+const ROOM_COLUMNS: &str = "r.id, r.name, r.owner_id, r.last_seq, r.kind, r.topic, r.description, r.created_at, r.created_by, r.archived";
 
-    let room_iter = stmt
-        .query_map([], |row| {
-            Ok(Room {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                messages: vec![],
-                sender: broadcast::channel(100).0, // Każdy pokój ma własny kanał
-            })
+fn room_from_row(row: &Row) -> rusqlite::Result<Room> {
+    Ok(Room {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        owner_id: row.get(2)?,
+        last_seq: row.get(3)?,
+        kind: kind_from_row(row, 4)?,
+        topic: row.get(5)?,
+        description: row.get(6)?,
+        created_at: row.get(7)?,
+        created_by: row.get(8)?,
+        archived: row.get(9)?,
+        messages: vec![],
+        sender: broadcast::channel(100).0, // Każdy pokój ma własny kanał
+    })
+}
+
+/// The kind column is not constrained by the schema, so values written by
+/// anything other than `RoomKind::as_str` are rejected when read.
+fn kind_from_row(row: &Row, index: usize) -> rusqlite::Result<RoomKind> {
+    let value: String = row.get(index)?;
+    RoomKind::parse(&value).ok_or_else(|| {
+        rusqlite::Error::FromSqlConversionFailure(index, Type::Text, format!("Unknown room kind: {}", value).into())
+    })
+}
+
+/// Columns read by `message_from_row`, selected from `MESSAGE_SOURCE`.
+const MESSAGE_COLUMNS: &str = "m.id, m.username, m.content, m.date, m.edited_at, m.deleted_at, m.reply_to, m.thread_root,
+    (SELECT COUNT(*) FROM messages r WHERE r.thread_root = m.id AND r.deleted_at IS NULL),
+    q.username, q.content,
+    (SELECT json_group_array(json_object('emoji', emoji, 'count', count, 'usernames', json(usernames)))
+     FROM (SELECT x.emoji, COUNT(*) AS count, json_group_array(a.name) AS usernames
+           FROM reactions x JOIN accounts a ON a.id = x.account_id
+           WHERE x.message_id = m.id
+           GROUP BY x.emoji ORDER BY MIN(x.created_at))),
+    m.seq, m.updated_seq, m.mentions";
+
+/// `m` is the message itself and `q` the message it quotes, if any.
+const MESSAGE_SOURCE: &str = "messages m LEFT JOIN messages q ON q.id = m.reply_to";
+
+fn message_from_row(row: &Row) -> rusqlite::Result<Message> {
+    message_from_row_at(row, 0)
+}
+
+fn message_from_row_at(row: &Row, offset: usize) -> rusqlite::Result<Message> {
+    let quote_username: Option<String> = row.get(offset + 9)?;
+    let quote_content: Option<String> = row.get(offset + 10)?;
+    let reactions: Option<String> = row.get(offset + 11)?;
+    let mentions: String = row.get(offset + 14)?;
+
+    Ok(Message {
+        id: row.get(offset)?,
+        username: row.get(offset + 1)?,
+        content: row.get(offset + 2)?,
+        date: row.get(offset + 3)?,
+        edited_at: row.get(offset + 4)?,
+        deleted_at: row.get(offset + 5)?,
+        reply_to: row.get(offset + 6)?,
+        thread_root: row.get(offset + 7)?,
+        reply_count: row.get(offset + 8)?,
+        quote: quote_username.map(|username| QuoteDTO {
+            username,
+            content: quote_content.unwrap_or_default(),
+        }),
+        reactions: reactions
+            .and_then(|reactions| serde_json::from_str(&reactions).ok())
+            .unwrap_or_default(),
+        seq: row.get(offset + 12)?,
+        updated_seq: row.get(offset + 13)?,
+        mentions: serde_json::from_str(&mentions).unwrap_or_default(),
+    })
+}
+
+/// Allocates the room's next sequence number. Callers hold `ROOMS` until the
+/// event carrying it is published.
+fn next_seq(conn: &Connection, room_id: &str) -> i64 {
+    conn.query_row(
+        "UPDATE rooms SET last_seq = last_seq + 1 WHERE id = ?1 RETURNING last_seq;",
+        [room_id],
+        |row| row.get(0),
+    )
+        .expect("Failed to allocate sequence number")
+}
+
+/// Resolves the handles in a message to the accounts they mention, leaving
+/// out the author and anyone who cannot access the room. `@room` stands for
+/// everyone who has taken part in the room, by posting, reading or joining
+/// it, and `@here` for everyone online in it. Returns the handles that
+/// resolved and the mentioned account ids.
+fn resolve_mentions(conn: &Connection, room_id: &str, author: &str, content: &str) -> (Vec<String>, Vec<String>) {
+    let mut handles = vec![];
+    let mut account_ids: Vec<String> = vec![];
+
+    for handle in parse_mentions(content) {
+        let targets: Vec<(String, String)> = match handle.to_lowercase().as_str() {
+            "room" => conn
+                .prepare(
+                    "SELECT a.id, a.name FROM accounts a
+                     WHERE EXISTS (SELECT 1 FROM messages m WHERE m.room_id = ?1 AND m.username = a.name)
+                        OR EXISTS (SELECT 1 FROM read_positions p WHERE p.room_id = ?1 AND p.account_id = a.id)
+                        OR EXISTS (SELECT 1 FROM room_members r WHERE r.room_id = ?1 AND r.account_id = a.id);",
+                )
+                .and_then(|mut stmt| stmt.query_map([room_id], |row| Ok((row.get(0)?, row.get(1)?)))?.collect())
+                .expect("Failed to query room participants"),
+            "here" => presence::get_online(room_id)
+                .into_iter()
+                .map(|member| (member.account_id, member.username))
+                .collect(),
+            // Names are unique but case-sensitive, so an exact match wins.
+            _ => match conn
+                .query_row(
+                    "SELECT id, name FROM accounts WHERE name = ?1 COLLATE NOCASE ORDER BY name = ?1 DESC LIMIT 1;",
+                    [&handle],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()
+                .expect("Failed to query account")
+                .filter(|(account_id, _): &(String, String)| check_access_with(conn, room_id, account_id).is_ok())
+            {
+                Some(account) => vec![account],
+                None => continue,
+            },
+        };
+
+        handles.push(handle);
+        for (account_id, name) in targets {
+            if name != author && !account_ids.contains(&account_id) && check_access_with(conn, room_id, &account_id).is_ok() {
+                account_ids.push(account_id);
+            }
+        }
+    }
+    (handles, account_ids)
+}
+
+/// Replaces the accounts a message mentions.
+fn save_mentions(conn: &Connection, message_id: &str, account_ids: &[String]) {
+    conn.execute("DELETE FROM mentions WHERE message_id = ?1;", [message_id])
+        .expect("Failed to delete mentions from database");
+    for account_id in account_ids {
+        conn.execute(
+            "INSERT INTO mentions (message_id, account_id) VALUES (?1, ?2);",
+            [message_id, account_id],
+        )
+            .expect("Failed to insert mention into database");
+    }
+}
+
+fn notify_mentioned(room_id: &str, message: &Message, account_ids: Vec<String>) {
+    if account_ids.is_empty() {
+        return;
+    }
+    // Nobody being connected is not an error.
+    let _ = MENTION_SENDER.send(MentionEventDTO {
+        account_ids,
+        room_id: room_id.to_string(),
+        message: message.clone(),
+    });
+}
+
+/// The newest page of a room's history, which is all the cache keeps per room.
+fn load_recent_messages(conn: &Connection, room_id: &str) -> Vec<Message> {
+    read_page(conn, room_id, &MessageCursor::Latest, CONFIG.message_page_size)
+        .map(|(messages, _)| messages)
+        .unwrap_or_else(|err| {
+            eprintln!("Failed to load messages: {}", err);
+            vec![]
         })
-        .expect("Failed to query rooms");
+}
 
-    let mut rooms = ROOMS.lock().unwrap();
+/// Reads up to `limit` messages next to the cursor, ordered by `(date, id)` so
+/// messages sharing a timestamp still page deterministically. Returns the
+/// messages oldest first and whether more exist beyond the page.
+fn read_page(conn: &Connection, room_id: &str, cursor: &MessageCursor, limit: usize) -> rusqlite::Result<(Vec<Message>, bool)> {
+    let fetch = (limit + 1) as i64;
+    let mut messages: Vec<Message> = match cursor {
+        MessageCursor::Latest => conn
+            .prepare(&format!(
+                "SELECT {} FROM {} WHERE m.room_id = ?1
+                 ORDER BY m.date DESC, m.id DESC LIMIT ?2;",
+                MESSAGE_COLUMNS, MESSAGE_SOURCE,
+            ))?
+            .query_map(params![room_id, fetch], message_from_row)?
+            .collect::<rusqlite::Result<_>>()?,
+        MessageCursor::Before(anchor) => conn
+            .prepare(&format!(
+                "SELECT {} FROM {}, messages a
+                 WHERE a.id = ?2 AND a.room_id = ?1 AND m.room_id = ?1 AND (m.date, m.id) < (a.date, a.id)
+                 ORDER BY m.date DESC, m.id DESC LIMIT ?3;",
+                MESSAGE_COLUMNS, MESSAGE_SOURCE,
+            ))?
+            .query_map(params![room_id, anchor, fetch], message_from_row)?
+            .collect::<rusqlite::Result<_>>()?,
+        MessageCursor::After(anchor) => conn
+            .prepare(&format!(
+                "SELECT {} FROM {}, messages a
+                 WHERE a.id = ?2 AND a.room_id = ?1 AND m.room_id = ?1 AND (m.date, m.id) > (a.date, a.id)
+                 ORDER BY m.date ASC, m.id ASC LIMIT ?3;",
+                MESSAGE_COLUMNS, MESSAGE_SOURCE,
+            ))?
+            .query_map(params![room_id, anchor, fetch], message_from_row)?
+            .collect::<rusqlite::Result<_>>()?,
+    };
 
-    for mut room in room_iter.flatten() {
-        let mut message_stmt = conn
-            .prepare("SELECT id, username, content, date FROM messages WHERE room_id = ?;")
-            .expect("Failed to prepare statement for messages");
+    let has_more = messages.len() > limit;
+    messages.truncate(limit);
+    if !matches!(cursor, MessageCursor::After(_)) {
+        messages.reverse();
+    }
 
-        let message_iter = message_stmt
-            .query_map([room.id.clone()], |row| {
-                Ok(Message {
-                    id: row.get(0)?,
-                    username: row.get(1)?,
-                    content: row.get(2)?,
-                    date: row.get(3)?,
-                })
-            })
-            .expect("Failed to query messages");
+    Ok((messages, has_more))
+}
+
+pub fn get_messages(room_id: &str, account_id: &str, cursor: MessageCursor, limit: usize) -> Result<MessagePageDTO, RoomError> {
+    let conn = DB.lock().unwrap();
+    check_access_with(&conn, room_id, account_id)?;
+
+    if let MessageCursor::Before(anchor) | MessageCursor::After(anchor) = &cursor {
+        let anchor_exists = conn
+            .query_row("SELECT 1 FROM messages WHERE id = ?1 AND room_id = ?2;", [anchor, room_id], |_| Ok(()))
+            .optional()
+            .expect("Failed to query message");
+        if anchor_exists.is_none() {
+            return Err(RoomError::InvalidCursor);
+        }
+    }
+
+    let (messages, has_more) = read_page(&conn, room_id, &cursor, limit).expect("Failed to query messages");
+    Ok(MessagePageDTO { messages, has_more })
+}
 
-        room.messages = message_iter.flatten().collect();
+fn load_room(conn: &Connection, id: &str) -> Option<Room> {
+    let mut room = conn
+        .query_row(&format!("SELECT {} FROM rooms r WHERE r.id = ?1;", ROOM_COLUMNS), [id], room_from_row)
+        .optional()
+        .expect("Failed to query room by id")?;
 
-        rooms.insert(room.id.clone(), room);
+    room.messages = load_recent_messages(conn, id);
+    Some(room)
+}
+
+/// Returns the cached room, loading it from the database when it is not
+/// cached. Callers hold `ROOMS` for as long as they use the room, which keeps
+/// the lock order `ROOMS` before `DB`.
+fn cached_room<'a>(rooms: &'a mut RoomCache, id: &str) -> Option<&'a mut Room> {
+    if rooms.get(id).is_some() {
+        return rooms.get(id);
     }
+
+    let room = load_room(&DB.lock().unwrap(), id)?;
+    Some(rooms.insert(room))
 }
 
-pub async fn create(name: String) {
-    let room = Room::new(Uuid::new_v4().to_string(), name.clone());
+/// Subscribes to a room's events and returns the room with its newest page of
+/// messages. Both happen under the cache lock, so the snapshot and the events
+/// neither overlap nor leave a gap, and the room cannot be evicted in between.
+pub async fn subscribe(id: String, account_id: String) -> Result<(Room, broadcast::Receiver<RoomEvent>), RoomError> {
+    let mut rooms = ROOMS.lock().unwrap();
+    check_access(&id, &account_id)?;
+    cached_room(&mut rooms, &id)
+        .map(|room| (room.clone(), room.sender.subscribe()))
+        .ok_or(RoomError::NotFound)
+}
+
+/// Sends an event that is not stored, such as presence or typing, to the
+/// room's subscribers. A room that is not cached has none.
+pub fn publish_ephemeral(room_id: &str, event: RoomEvent) {
+    if let Some(room) = ROOMS.lock().unwrap().get(room_id) {
+        room.publish(event);
+    }
+}
+
+pub fn evict_idle_rooms() -> usize {
+    ROOMS.lock().unwrap().evict_idle()
+}
+
+/// Creates a public or private room. A private room starts with its owner as
+/// the only member.
+pub async fn create(name: String, owner_id: String, kind: RoomKind) -> Room {
+    let room = Room::new(Uuid::new_v4().to_string(), name.clone(), Some(owner_id.clone()), kind, owner_id.clone());
 
     {
         let conn = DB.lock().unwrap();
         conn.execute(
-            "INSERT INTO rooms (id, name) VALUES (?1, ?2);",
-            &[&room.id, &room.name],
+            "INSERT INTO rooms (id, name, owner_id, kind, created_at, created_by) VALUES (?1, ?2, ?3, ?4, ?5, ?3);",
+            [&room.id, &room.name, &owner_id, kind.as_str(), &room.created_at],
         )
             .expect("Failed to insert room into database");
+        conn.execute(
+            "INSERT INTO room_members (room_id, account_id, role) VALUES (?1, ?2, ?3);",
+            [&room.id, &owner_id, RoomRole::Owner.as_str()],
+        )
+            .expect("Failed to insert room owner into database");
     }
 
     let mut rooms = ROOMS.lock().unwrap();
-    if let Err(err) = ROOM_SENDER.send(room.clone()) {
+    if let Err(err) = ROOM_SENDER.send(RoomListEvent::Created(room.clone())) {
         eprintln!("Failed to broadcast room: {}", err);
     }
-    rooms.insert(room.id.clone(), room);
+    rooms.insert(room).clone()
 }
 
-pub async fn delete(id: String) {
-    {
+/// Resolves the caller's role in a room. The `owner_id` column is
+/// authoritative for owners; everyone else without a row is a member. A room
+/// the caller cannot access is not found rather than forbidden.
+pub fn get_role(room_id: &str, account_id: &str) -> Result<RoomRole, RoomError> {
+    get_role_with(&DB.lock().unwrap(), room_id, account_id)
+}
+
+fn get_role_with(conn: &Connection, room_id: &str, account_id: &str) -> Result<RoomRole, RoomError> {
+    check_access_with(conn, room_id, account_id)?;
+    let owner_id: Option<String> = conn
+        .query_row("SELECT owner_id FROM rooms WHERE id = ?1;", [room_id], |row| row.get(0))
+        .optional()
+        .expect("Failed to query room owner")
+        .ok_or(RoomError::NotFound)?;
+
+    if owner_id.as_deref() == Some(account_id) {
+        return Ok(RoomRole::Owner);
+    }
+
+    let role: Option<String> = conn
+        .query_row(
+            "SELECT role FROM room_members WHERE room_id = ?1 AND account_id = ?2;",
+            [room_id, account_id],
+            |row| row.get(0),
+        )
+        .optional()
+        .expect("Failed to query room member");
+
+    Ok(role
+        .and_then(|role| RoomRole::parse(&role))
+        .filter(|role| *role != RoomRole::Owner)
+        .unwrap_or(RoomRole::Member))
+}
+
+fn require_manager(room_id: &str, account_id: &str) -> Result<(), RoomError> {
+    require_manager_with(&DB.lock().unwrap(), room_id, account_id)
+}
+
+fn require_manager_with(conn: &Connection, room_id: &str, account_id: &str) -> Result<(), RoomError> {
+    if get_role_with(conn, room_id, account_id)?.can_manage() {
+        Ok(())
+    } else {
+        Err(RoomError::Forbidden)
+    }
+}
+
+pub async fn delete(id: String, account_id: String) -> Result<(), RoomError> {
+    require_manager(&id, &account_id)?;
+
+    // Members, invites, read positions and messages, with their edits,
+    // reactions and mentions, go with the room through its cascades.
+    let account_ids = {
         let conn = DB.lock().unwrap();
+        let account_ids = match room_kind(&conn, &id)? {
+            RoomKind::Public => None,
+            RoomKind::Private | RoomKind::Direct => Some(
+                conn.prepare("SELECT account_id FROM room_members WHERE room_id = ?1;")
+                    .and_then(|mut stmt| stmt.query_map([&id], |row| row.get(0))?.collect())
+                    .expect("Failed to query room members"),
+            ),
+        };
+
         conn.execute("DELETE FROM rooms WHERE id = ?1;", [&id])
             .expect("Failed to delete room from database");
-        conn.execute("DELETE FROM messages WHERE room_id = ?1;", [&id])
-            .expect("Failed to delete messages from database");
+        account_ids
+    };
+
+    let mut rooms = ROOMS.lock().unwrap();
+    rooms.remove(&id);
+    if let Err(err) = ROOM_SENDER.send(RoomListEvent::Deleted { id, account_ids }) {
+        eprintln!("Failed to broadcast room: {}", err);
     }
 
+    Ok(())
+}
+
+/// Applies the changes that differ from the room's current metadata and
+/// announces them to the room and the room list. Only owners and moderators
+/// may update a room, and an archived room only accepts updates that
+/// unarchive it.
+pub async fn update(id: String, account_id: String, changes: UpdateRoomDTO) -> Result<Room, RoomError> {
+    require_manager(&id, &account_id)?;
+
     let mut rooms = ROOMS.lock().unwrap();
-    if let Some(room) = rooms.remove(&id) {
-        if let Err(err) = ROOM_SENDER.send(room.clone()) {
+    let room = cached_room(&mut rooms, &id).ok_or(RoomError::NotFound)?;
+    if room.archived && changes.archived != Some(false) {
+        return Err(RoomError::Archived);
+    }
+
+    let changes = UpdateRoomDTO {
+        name: changes.name.filter(|name| *name != room.name),
+        topic: changes.topic.filter(|topic| *topic != room.topic),
+        description: changes.description.filter(|description| *description != room.description),
+        archived: changes.archived.filter(|archived| *archived != room.archived),
+    };
+    if changes.is_empty() {
+        return Ok(room.clone());
+    }
+
+    {
+        let conn = DB.lock().unwrap();
+        conn.execute(
+            "UPDATE rooms SET name = COALESCE(?1, name), topic = COALESCE(?2, topic),
+                 description = COALESCE(?3, description), archived = COALESCE(?4, archived)
+             WHERE id = ?5;",
+            params![&changes.name, &changes.topic, &changes.description, changes.archived, &id],
+        )
+            .expect("Failed to update room in database");
+    }
+
+    if let Some(name) = &changes.name {
+        room.name = name.clone();
+    }
+    if let Some(topic) = &changes.topic {
+        room.topic = topic.clone();
+    }
+    if let Some(description) = &changes.description {
+        room.description = description.clone();
+    }
+    if let Some(archived) = changes.archived {
+        room.archived = archived;
+    }
+
+    let update = RoomUpdatedDTO {
+        id,
+        changes,
+        updated_by: account_id,
+        updated_at: chrono::Utc::now().to_rfc3339(),
+    };
+    room.publish(RoomEvent::RoomUpdated(update.clone()));
+    if let Err(err) = ROOM_SENDER.send(RoomListEvent::Updated(update)) {
+        eprintln!("Failed to broadcast room: {}", err);
+    }
+    Ok(room.clone())
+}
+
+/// Messages in archived rooms cannot be sent, edited, deleted or reacted to.
+fn check_writable(conn: &Connection, room_id: &str) -> Result<(), RoomError> {
+    let archived: bool = conn
+        .query_row("SELECT archived FROM rooms WHERE id = ?1;", [room_id], |row| row.get(0))
+        .optional()
+        .expect("Failed to query room")
+        .ok_or(RoomError::NotFound)?;
+    if archived {
+        return Err(RoomError::Archived);
+    }
+    Ok(())
+}
+
+/// Promotes a member to moderator or demotes them back. Only the owner may
+/// change roles, and ownership itself cannot be granted or taken this way.
+/// In a private room the account must already be a member.
+pub async fn set_role(id: String, owner_id: String, account_id: String, role: RoomRole) -> Result<(), RoomError> {
+    if get_role(&id, &owner_id)? != RoomRole::Owner {
+        return Err(RoomError::Forbidden);
+    }
+    if role == RoomRole::Owner || account_id == owner_id {
+        return Err(RoomError::InvalidRole);
+    }
+
+    let conn = DB.lock().unwrap();
+    check_access_with(&conn, &id, &account_id).map_err(|_| RoomError::UnknownAccount)?;
+    conn.execute(
+        "INSERT INTO room_members (room_id, account_id, role) VALUES (?1, ?2, ?3)
+         ON CONFLICT (room_id, account_id) DO UPDATE SET role = excluded.role;",
+        [&id, &account_id, role.as_str()],
+    )
+        .expect("Failed to update room member in database");
+
+    Ok(())
+}
+
+/// Lists every public room and the private rooms the account is a member of,
+/// without messages, with the account's unread and mention counts. Reads
+/// straight from the database so rooms that are not cached are listed too.
+pub async fn get(account_id: String) -> Vec<RoomSummaryDTO> {
+    let conn = DB.lock().unwrap();
+    read_summaries(
+        &conn,
+        &account_id,
+        "r.kind = 'public'
+         OR (r.kind = 'private' AND EXISTS (SELECT 1 FROM room_members x WHERE x.room_id = r.id AND x.account_id = a.id))",
+    )
+}
+
+fn room_kind(conn: &Connection, room_id: &str) -> Result<RoomKind, RoomError> {
+    let kind: String = conn
+        .query_row("SELECT kind FROM rooms WHERE id = ?1;", [room_id], |row| row.get(0))
+        .optional()
+        .expect("Failed to query room")
+        .ok_or(RoomError::NotFound)?;
+    RoomKind::parse(&kind).ok_or(RoomError::NotFound)
+}
+
+/// Adds the account to the room's members, returning whether it was not
+/// one already.
+fn add_member(conn: &Connection, room_id: &str, account_id: &str) -> bool {
+    conn.execute(
+        "INSERT INTO room_members (room_id, account_id, role) VALUES (?1, ?2, ?3)
+         ON CONFLICT (room_id, account_id) DO NOTHING;",
+        [room_id, account_id, RoomRole::Member.as_str()],
+    )
+        .expect("Failed to insert room member into database")
+        > 0
+}
+
+/// Tells a new member of a private room about it through the room list.
+/// Only the new member receives the event.
+fn announce_to_member(rooms: &mut RoomCache, room_id: &str, account_id: &str) {
+    if let Some(room) = cached_room(rooms, room_id) {
+        let summary = Room { messages: vec![], ..room.clone() };
+        if let Err(err) = ROOM_SENDER.send(RoomListEvent::Joined { room: summary, account_id: account_id.to_string() }) {
             eprintln!("Failed to broadcast room: {}", err);
         }
     }
 }
 
-pub async fn get() -> Vec<Room> {
+/// Joins a public room. Private rooms are joined by invitation, so they are
+/// reported as not found unless the account is a member already.
+pub async fn join(room_id: String, account_id: String) -> Result<Room, RoomError> {
     let mut rooms = ROOMS.lock().unwrap();
-    if !rooms.is_empty() {
-        return rooms.values().cloned().collect();
+    {
+        let conn = DB.lock().unwrap();
+        check_access_with(&conn, &room_id, &account_id)?;
+        if room_kind(&conn, &room_id)? == RoomKind::Public {
+            add_member(&conn, &room_id, &account_id);
+        }
     }
+    cached_room(&mut rooms, &room_id).map(|room| room.clone()).ok_or(RoomError::NotFound)
+}
+
+/// Leaves a room. Leaving a private room ends access to it: the account's
+/// subscriptions to it end and it drops out of the account's room list.
+/// Owners cannot leave, and direct conversations are not left this way.
+pub async fn leave(room_id: String, account: Account) -> Result<(), RoomError> {
+    let mut rooms = ROOMS.lock().unwrap();
+    let kind = {
+        let conn = DB.lock().unwrap();
+        check_access_with(&conn, &room_id, &account.id)?;
+        let kind = room_kind(&conn, &room_id)?;
+        if kind == RoomKind::Direct {
+            return Err(RoomError::Forbidden);
+        }
+        let owner_id: Option<String> = conn
+            .query_row("SELECT owner_id FROM rooms WHERE id = ?1;", [&room_id], |row| row.get(0))
+            .expect("Failed to query room owner");
+        if owner_id.as_deref() == Some(account.id.as_str()) {
+            return Err(RoomError::OwnerCannotLeave);
+        }
+
+        conn.execute("DELETE FROM room_members WHERE room_id = ?1 AND account_id = ?2;", [&room_id, &account.id])
+            .expect("Failed to delete room member from database");
+        kind
+    };
+
+    if kind == RoomKind::Private {
+        if let Some(room) = rooms.get(&room_id) {
+            room.publish(RoomEvent::AccessRevoked(PresenceEventDTO {
+                account_id: account.id.clone(),
+                username: account.name.clone(),
+            }));
+        }
+        if let Err(err) = ROOM_SENDER.send(RoomListEvent::Left { id: room_id, account_id: account.id }) {
+            eprintln!("Failed to broadcast room: {}", err);
+        }
+    }
+    Ok(())
+}
+
+/// Lets a room owner or moderator add an account to the room by name. The
+/// account becomes a member straight away.
+pub async fn invite(room_id: String, account_id: String, username: String) -> Result<MemberDTO, RoomError> {
+    require_manager(&room_id, &account_id)?;
+
+    let mut rooms = ROOMS.lock().unwrap();
+    let (member, added) = {
+        let conn = DB.lock().unwrap();
+        let (invitee_id, invitee_name): (String, String) = conn
+            .query_row("SELECT id, name FROM accounts WHERE name = ?1;", [&username], |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()
+            .expect("Failed to query account")
+            .ok_or(RoomError::UnknownAccount)?;
+
+        let added = add_member(&conn, &room_id, &invitee_id);
+        let role: String = conn
+            .query_row(
+                "SELECT role FROM room_members WHERE room_id = ?1 AND account_id = ?2;",
+                [&room_id, &invitee_id],
+                |row| row.get(0),
+            )
+            .expect("Failed to query room member");
+        let member = MemberDTO {
+            account_id: invitee_id,
+            username: invitee_name,
+            role: RoomRole::parse(&role).unwrap_or(RoomRole::Member),
+        };
+        (member, added && room_kind(&conn, &room_id)? == RoomKind::Private)
+    };
+
+    if added {
+        announce_to_member(&mut rooms, &room_id, &member.account_id);
+    }
+    Ok(member)
+}
+
+/// Members of a room the account can access, owner first.
+pub async fn get_members(room_id: String, account_id: String) -> Result<Vec<MemberDTO>, RoomError> {
+    let conn = DB.lock().unwrap();
+    check_access_with(&conn, &room_id, &account_id)?;
+
+    let members = conn
+        .prepare(
+            "SELECT a.id, a.name, x.role FROM room_members x JOIN accounts a ON a.id = x.account_id
+             WHERE x.room_id = ?1
+             ORDER BY CASE x.role WHEN 'owner' THEN 0 WHEN 'moderator' THEN 1 ELSE 2 END, a.name;",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map([&room_id], |row| {
+                Ok(MemberDTO {
+                    account_id: row.get(0)?,
+                    username: row.get(1)?,
+                    role: RoomRole::parse(&row.get::<_, String>(2)?).unwrap_or(RoomRole::Member),
+                })
+            })?
+                .collect()
+        })
+        .expect("Failed to query room members");
+    Ok(members)
+}
+
+fn invite_link_from_row(row: &Row) -> rusqlite::Result<InviteLinkDTO> {
+    Ok(InviteLinkDTO {
+        code: row.get(0)?,
+        room_id: row.get(1)?,
+        created_by: row.get(2)?,
+        created_at: row.get(3)?,
+        expires_at: row.get(4)?,
+        max_uses: row.get(5)?,
+        uses: row.get(6)?,
+    })
+}
+
+/// Creates a link that adds whoever opens it to the room, until it expires
+/// or has been used `max_uses` times. Only owners and moderators create links.
+pub async fn create_invite_link(
+    room_id: String,
+    account_id: String,
+    expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    max_uses: Option<i64>,
+) -> Result<InviteLinkDTO, RoomError> {
+    require_manager(&room_id, &account_id)?;
+
+    let now = chrono::Utc::now();
+    let link = InviteLinkDTO {
+        code: Uuid::new_v4().to_string(),
+        room_id,
+        created_by: account_id,
+        created_at: now.to_rfc3339(),
+        expires_at: expires_at.map(|expires_at| expires_at.to_rfc3339()),
+        max_uses,
+        uses: 0,
+    };
+
+    DB.lock().unwrap().execute(
+        "INSERT INTO room_invites (code, room_id, created_by, created_at, expires_at, max_uses) VALUES (?1, ?2, ?3, ?4, ?5, ?6);",
+        params![&link.code, &link.room_id, &link.created_by, &link.created_at, &link.expires_at, link.max_uses],
+    )
+        .expect("Failed to insert invite link into database");
+    Ok(link)
+}
+
+/// The room's invite links, newest first, including used up and expired ones.
+pub async fn get_invite_links(room_id: String, account_id: String) -> Result<Vec<InviteLinkDTO>, RoomError> {
+    require_manager(&room_id, &account_id)?;
 
     let conn = DB.lock().unwrap();
+    let links = conn
+        .prepare(
+            "SELECT code, room_id, created_by, created_at, expires_at, max_uses, uses
+             FROM room_invites WHERE room_id = ?1 ORDER BY created_at DESC;",
+        )
+        .and_then(|mut stmt| stmt.query_map([&room_id], invite_link_from_row)?.collect())
+        .expect("Failed to query invite links");
+    Ok(links)
+}
+
+pub async fn revoke_invite_link(room_id: String, account_id: String, code: String) -> Result<(), RoomError> {
+    require_manager(&room_id, &account_id)?;
+
+    let deleted = DB.lock().unwrap()
+        .execute("DELETE FROM room_invites WHERE code = ?1 AND room_id = ?2;", [&code, &room_id])
+        .expect("Failed to delete invite link from database");
+    if deleted == 0 {
+        return Err(RoomError::InvalidInvite);
+    }
+    Ok(())
+}
+
+/// Joins the room an invite link points to. Accounts that can access the
+/// room already do not use up the link.
+pub async fn join_by_invite(code: String, account_id: String) -> Result<Room, RoomError> {
+    let mut rooms = ROOMS.lock().unwrap();
+    let (room_id, added) = {
+        let conn = DB.lock().unwrap();
+        let room_id: String = conn
+            .query_row("SELECT room_id FROM room_invites WHERE code = ?1;", [&code], |row| row.get(0))
+            .optional()
+            .expect("Failed to query invite link")
+            .ok_or(RoomError::InvalidInvite)?;
+
+        if check_access_with(&conn, &room_id, &account_id).is_ok() {
+            (room_id, false)
+        } else {
+            let used = conn
+                .execute(
+                    "UPDATE room_invites SET uses = uses + 1
+                     WHERE code = ?1 AND (expires_at IS NULL OR expires_at > ?2) AND (max_uses IS NULL OR uses < max_uses);",
+                    [&code, &chrono::Utc::now().to_rfc3339()],
+                )
+                .expect("Failed to use invite link");
+            if used == 0 {
+                return Err(RoomError::InvalidInvite);
+            }
+            (room_id.clone(), add_member(&conn, &room_id, &account_id))
+        }
+    };
+
+    if added {
+        announce_to_member(&mut rooms, &room_id, &account_id);
+    }
+    cached_room(&mut rooms, &room_id).map(|room| room.clone()).ok_or(RoomError::NotFound)
+}
+
+/// Rooms matching `filter`, a condition on `r` and the account `a`, as the
+/// account sees them in its lists.
+fn read_summaries(conn: &Connection, account_id: &str, filter: &str) -> Vec<RoomSummaryDTO> {
     let mut stmt = conn
-        .prepare("SELECT id, name FROM rooms;")
+        .prepare(&format!(
+            "SELECT {}, COALESCE(p.last_read_seq, 0),
+                    COUNT(m.id),
+                    COUNT(m.id) FILTER (WHERE EXISTS (SELECT 1 FROM mentions x WHERE x.message_id = m.id AND x.account_id = a.id))
+             FROM rooms r
+             JOIN accounts a ON a.id = ?1
+             LEFT JOIN read_positions p ON p.room_id = r.id AND p.account_id = a.id
+             LEFT JOIN messages m ON m.room_id = r.id AND m.seq > COALESCE(p.last_read_seq, 0)
+                 AND m.deleted_at IS NULL AND m.username != a.name
+             WHERE {}
+             GROUP BY r.id;",
+            ROOM_COLUMNS, filter,
+        ))
         .expect("Failed to prepare statement");
 
     let room_iter = stmt
-        .query_map([], |row| {
-            Ok(Room {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                messages: vec![],
-                sender: broadcast::channel(100).0,
+        .query_map([account_id], |row| {
+            Ok(RoomSummaryDTO {
+                room: room_from_row(row)?,
+                last_read_seq: row.get(10)?,
+                unread_count: row.get(11)?,
+                mention_count: row.get(12)?,
             })
         })
         .expect("Failed to query rooms");
 
-    let mut new_rooms = vec![];
-    for room in room_iter.flatten() {
-        new_rooms.push(room.clone());
-        rooms.insert(room.id.clone(), room);
-    }
+    room_iter.flatten().collect()
+}
+
+/// The account's direct conversations, by name.
+pub async fn get_direct(account_id: String) -> Vec<DirectSummaryDTO> {
+    let conn = DB.lock().unwrap();
+    let mut summaries = read_summaries(
+        &conn,
+        &account_id,
+        "r.kind = 'direct' AND EXISTS (SELECT 1 FROM room_members x WHERE x.room_id = r.id AND x.account_id = a.id)",
+    );
+    summaries.sort_by(|a, b| a.room.name.cmp(&b.room.name));
 
-    new_rooms
+    summaries
+        .into_iter()
+        .map(|summary| {
+            let participants = read_participants(&conn, &summary.room.id);
+            DirectSummaryDTO { summary, participants }
+        })
+        .collect()
 }
 
-pub async fn get_one_by_id(id: String) -> Option<Room> {
-    {
-        let rooms = ROOMS.lock().unwrap();
-        if let Some(room) = rooms.get(&id) {
-            return Some(room.clone());
+fn read_participants(conn: &Connection, room_id: &str) -> Vec<ParticipantDTO> {
+    conn.prepare(
+        "SELECT a.id, a.name FROM room_members x JOIN accounts a ON a.id = x.account_id
+         WHERE x.room_id = ?1 ORDER BY a.name;",
+    )
+        .and_then(|mut stmt| {
+            stmt.query_map([room_id], |row| Ok(ParticipantDTO { account_id: row.get(0)?, username: row.get(1)? }))?
+                .collect()
+        })
+        .expect("Failed to query participants")
+}
+
+/// Returns the direct conversation between the account and the named
+/// accounts, opening it on first use. A conversation is identified by its
+/// participants alone, so opening it again from either side, with the names
+/// in any order, returns the same room.
+pub async fn open_direct(account: Account, usernames: Vec<String>) -> Result<Room, RoomError> {
+    let mut rooms = ROOMS.lock().unwrap();
+    let (id, opened) = open_direct_with(&DB.lock().unwrap(), account, usernames)?;
+    if let Some(room) = opened {
+        return Ok(rooms.insert(room).clone());
+    }
+    cached_room(&mut rooms, &id).map(|room| room.clone()).ok_or(RoomError::NotFound)
+}
+
+/// Returns the id of the participants' conversation and, when it had to be
+/// opened, the new room. Every participant's room list is told about a new
+/// conversation once its members are stored, so it passes their access check.
+fn open_direct_with(conn: &Connection, account: Account, usernames: Vec<String>) -> Result<(String, Option<Room>), RoomError> {
+    let mut participants = vec![(account.id.clone(), account.name.clone())];
+    for username in &usernames {
+        let participant: (String, String) = conn
+            .query_row("SELECT id, name FROM accounts WHERE name = ?1;", [username], |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()
+            .expect("Failed to query account")
+            .ok_or(RoomError::UnknownAccount)?;
+        if !participants.contains(&participant) {
+            participants.push(participant);
         }
     }
+    if !(2..=CONFIG.max_direct_participants).contains(&participants.len()) {
+        return Err(RoomError::InvalidParticipants);
+    }
 
-    let conn = DB.lock().unwrap();
-    let mut stmt = conn
-        .prepare("SELECT id, name FROM rooms WHERE id = ?1;")
-        .expect("Failed to prepare statement");
+    let mut ids: Vec<&str> = participants.iter().map(|(id, _)| id.as_str()).collect();
+    ids.sort();
+    let direct_key = ids.join(",");
 
-    let mut room_iter = stmt
-        .query_map([id.clone()], |row| {
-            Ok(Room {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                messages: vec![],
-                sender: broadcast::channel(100).0,
-            })
-        })
-        .expect("Failed to query room by id");
+    let existing: Option<String> = conn
+        .query_row("SELECT id FROM rooms WHERE direct_key = ?1;", [&direct_key], |row| row.get(0))
+        .optional()
+        .expect("Failed to query direct conversation");
+    if let Some(id) = existing {
+        return Ok((id, None));
+    }
 
-    if let Some(Ok(room)) = room_iter.next() {
-        let mut messages_stmt = conn
-            .prepare("SELECT id, username, content, date FROM messages WHERE room_id = ?1;")
-            .expect("Failed to prepare statement for messages");
+    let mut names: Vec<&str> = participants.iter().map(|(_, name)| name.as_str()).collect();
+    names.sort();
+    let room = Room::new(Uuid::new_v4().to_string(), names.join(", "), None, RoomKind::Direct, account.id.clone());
 
-        let message_iter = messages_stmt
-            .query_map([id.clone()], |row| {
-                Ok(Message {
-                    id: row.get(0)?,
-                    username: row.get(1)?,
-                    content: row.get(2)?,
-                    date: row.get(3)?,
-                })
-            })
-            .expect("Failed to query messages");
+    let tx = conn.unchecked_transaction().expect("Failed to start transaction");
+    tx.execute(
+        "INSERT INTO rooms (id, name, kind, direct_key, created_at, created_by) VALUES (?1, ?2, ?3, ?4, ?5, ?6);",
+        [&room.id, &room.name, RoomKind::Direct.as_str(), &direct_key, &room.created_at, &account.id],
+    )
+        .expect("Failed to insert direct conversation into database");
+    for (participant_id, _) in &participants {
+        tx.execute(
+            "INSERT INTO room_members (room_id, account_id, role) VALUES (?1, ?2, ?3);",
+            [&room.id, participant_id, RoomRole::Member.as_str()],
+        )
+            .expect("Failed to insert participant into database");
+    }
+    tx.commit().expect("Failed to commit direct conversation");
+
+    if let Err(err) = ROOM_SENDER.send(RoomListEvent::Created(room.clone())) {
+        eprintln!("Failed to broadcast room: {}", err);
+    }
+    Ok((room.id.clone(), Some(room)))
+}
+
+/// Public rooms are open to every account, other rooms only to their
+/// members. A room the account cannot access is reported as not found, so
+/// its existence does not leak.
+fn check_access_with(conn: &Connection, room_id: &str, account_id: &str) -> Result<(), RoomError> {
+    let accessible: Option<bool> = conn
+        .query_row(
+            "SELECT r.kind = 'public' OR EXISTS (SELECT 1 FROM room_members x WHERE x.room_id = r.id AND x.account_id = ?2)
+             FROM rooms r WHERE r.id = ?1;",
+            [room_id, account_id],
+            |row| row.get(0),
+        )
+        .optional()
+        .expect("Failed to query room access");
+
+    match accessible {
+        Some(true) => Ok(()),
+        _ => Err(RoomError::NotFound),
+    }
+}
+
+pub fn check_access(room_id: &str, account_id: &str) -> Result<(), RoomError> {
+    check_access_with(&DB.lock().unwrap(), room_id, account_id)
+}
 
-        let mut messages = vec![];
-        for message in message_iter.flatten() {
-            messages.push(message);
+/// Whether a room list event is for the account's list. Access to created
+/// and updated rooms is checked as the event is delivered.
+pub fn is_list_event_visible(event: &RoomListEvent, account_id: &str) -> bool {
+    is_list_event_visible_with(&DB.lock().unwrap(), event, account_id)
+}
+
+fn is_list_event_visible_with(conn: &Connection, event: &RoomListEvent, account_id: &str) -> bool {
+    match event {
+        RoomListEvent::Created(Room { id, .. }) | RoomListEvent::Updated(RoomUpdatedDTO { id, .. }) => {
+            check_access_with(conn, id, account_id).is_ok()
+        }
+        RoomListEvent::Deleted { account_ids, .. } => {
+            account_ids.as_ref().is_none_or(|account_ids| account_ids.iter().any(|id| id == account_id))
         }
+        RoomListEvent::Joined { account_id: joined, .. } => joined == account_id,
+        RoomListEvent::Left { account_id: left, .. } => left == account_id,
+    }
+}
 
-        let mut room = room.clone();
-        room.messages = messages;
+/// Finds a message in a room the account can access. Messages elsewhere are
+/// reported as not found.
+fn find_accessible_message(conn: &Connection, message_id: &str, account_id: &str) -> Result<(String, Message), RoomError> {
+    let (room_id, message) = find_message(conn, message_id)?;
+    check_access_with(conn, &room_id, account_id).map_err(|_| RoomError::MessageNotFound)?;
+    Ok((room_id, message))
+}
+
+/// Moves the account's read position in the room up to the message. Reading
+/// an older message leaves it where it is; only moves are published.
+pub async fn mark_read(room_id: String, account: Account, message_id: String) -> Result<ReadReceiptDTO, RoomError> {
+    let (receipt, moved) = {
+        let conn = DB.lock().unwrap();
+        check_access_with(&conn, &room_id, &account.id)?;
+        let message = match find_message(&conn, &message_id)? {
+            (message_room_id, message) if message_room_id == room_id => message,
+            _ => return Err(RoomError::MessageNotFound),
+        };
 
-        let mut rooms = ROOMS.lock().unwrap();
-        rooms.insert(id.clone(), room.clone());
+        let moved = conn
+            .execute(
+                "INSERT INTO read_positions (room_id, account_id, last_read_seq, updated_at) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (room_id, account_id) DO UPDATE SET
+                     last_read_seq = excluded.last_read_seq,
+                     updated_at = excluded.updated_at
+                 WHERE excluded.last_read_seq > read_positions.last_read_seq;",
+                params![&room_id, &account.id, message.seq, chrono::Utc::now().to_rfc3339()],
+            )
+            .expect("Failed to update read position in database");
 
-        return Some(room);
+        let receipt = conn
+            .query_row(
+                "SELECT last_read_seq, updated_at FROM read_positions WHERE room_id = ?1 AND account_id = ?2;",
+                [&room_id, &account.id],
+                |row| {
+                    Ok(ReadReceiptDTO {
+                        account_id: account.id.clone(),
+                        username: account.name.clone(),
+                        last_read_seq: row.get(0)?,
+                        updated_at: row.get(1)?,
+                    })
+                },
+            )
+            .expect("Failed to query read position");
+        (receipt, moved > 0)
+    };
+
+    if moved {
+        publish_ephemeral(&room_id, RoomEvent::ReadUpdated(receipt.clone()));
     }
+    Ok(receipt)
+}
+
+/// Read positions of everyone who has read the room, furthest first.
+pub async fn get_read_receipts(room_id: String, account_id: String) -> Result<Vec<ReadReceiptDTO>, RoomError> {
+    let conn = DB.lock().unwrap();
+    check_access_with(&conn, &room_id, &account_id)?;
+
+    let mut stmt = conn
+        .prepare(
+            "SELECT p.account_id, a.name, p.last_read_seq, p.updated_at
+             FROM read_positions p JOIN accounts a ON a.id = p.account_id
+             WHERE p.room_id = ?1
+             ORDER BY p.last_read_seq DESC, a.name;",
+        )
+        .expect("Failed to prepare statement");
+
+    let receipts = stmt
+        .query_map([&room_id], |row| {
+            Ok(ReadReceiptDTO {
+                account_id: row.get(0)?,
+                username: row.get(1)?,
+                last_read_seq: row.get(2)?,
+                updated_at: row.get(3)?,
+            })
+        })
+        .expect("Failed to query read positions");
 
-    None
+    Ok(receipts.flatten().collect())
 }
 
-pub async fn add_message_to_room(id: String, username: String, content: String) -> Result<Message, String> {
+pub async fn get_one_by_id(id: String) -> Option<Room> {
     let mut rooms = ROOMS.lock().unwrap();
+    cached_room(&mut rooms, &id).map(|room| room.clone())
+}
+
+/// Adds a message to a room, optionally as a reply. A reply joins the thread of
+/// the message it answers, so every thread has a single root message.
+pub async fn add_message_to_room(id: String, account: Account, content: String, reply_to: Option<String>) -> Result<Message, RoomError> {
+    let mut rooms = ROOMS.lock().unwrap();
+    check_access(&id, &account.id)?;
+
+    let room = match cached_room(&mut rooms, &id) {
+        Some(room) => room,
+        None => return Err(RoomError::NotFound),
+    };
 
-    if let Some(room) = rooms.get_mut(&id) {
+    let username = account.name;
+    let (message, root, mentioned) = {
+        let conn = DB.lock().unwrap();
+        check_writable(&conn, &id)?;
+
+        let quoted = match &reply_to {
+            Some(reply_to) => match find_message(&conn, reply_to) {
+                Ok((room_id, quoted)) if room_id == id && quoted.deleted_at.is_none() => Some(quoted),
+                Ok((room_id, _)) if room_id == id => return Err(RoomError::MessageDeleted),
+                _ => return Err(RoomError::MessageNotFound),
+            },
+            None => None,
+        };
+
+        let (mentions, mentioned) = resolve_mentions(&conn, &id, &username, &content);
+        let tx = conn.unchecked_transaction().expect("Failed to start transaction");
+        let seq = next_seq(&tx, &id);
         let message = Message {
             id: Uuid::new_v4().to_string(),
             username: username.clone(),
             content: content.clone(),
             date: chrono::Utc::now().to_rfc3339(),
+            edited_at: None,
+            deleted_at: None,
+            reply_to: quoted.as_ref().map(|quoted| quoted.id.clone()),
+            thread_root: quoted.as_ref().map(|quoted| quoted.thread_root.clone().unwrap_or_else(|| quoted.id.clone())),
+            reply_count: 0,
+            reactions: vec![],
+            quote: quoted.as_ref().map(|quoted| QuoteDTO {
+                username: quoted.username.clone(),
+                content: quoted.content.clone(),
+            }),
+            seq,
+            updated_seq: seq,
+            mentions,
         };
 
-        {
-            let conn = DB.lock().unwrap();
-            conn.execute(
-                "INSERT INTO messages (id, room_id, username, content, date) VALUES (?1, ?2, ?3, ?4, ?5);",
-                &[&message.id, &id, &username, &content, &message.date],
+        tx.execute(
+            "INSERT INTO messages (id, room_id, username, content, date, reply_to, thread_root, seq, updated_seq, mentions) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?8, ?9);",
+            params![&message.id, &id, &username, &content, &message.date, &message.reply_to, &message.thread_root, seq, serde_json::to_string(&message.mentions).unwrap()],
+        )
+            .expect("Failed to insert message into database");
+        save_mentions(&tx, &message.id, &mentioned);
+
+        // The root's reply count changed, which is a change of its own.
+        let root = match &message.thread_root {
+            Some(root_id) => {
+                tx.execute(
+                    "UPDATE messages SET updated_seq = ?1 WHERE id = ?2;",
+                    params![next_seq(&tx, &id), root_id],
+                )
+                    .expect("Failed to update message in database");
+                find_message(&tx, root_id).ok().map(|(_, root)| root)
+            }
+            None => None,
+        };
+        tx.commit().expect("Failed to commit message");
+        (message, root, mentioned)
+    };
+
+    room.messages.push(message.clone());
+    let overflow = room.messages.len().saturating_sub(CONFIG.message_page_size);
+    room.messages.drain(..overflow);
+    room.publish(RoomEvent::MessageCreated(message.clone()));
+
+    if let Some(root) = root {
+        publish_change(&mut rooms, &id, &root, RoomEvent::ThreadUpdated(root.clone()));
+    }
+
+    notify_mentioned(&id, &message, mentioned);
+    Ok(message)
+}
+
+/// The room of a thread, given its root message.
+pub async fn get_thread_room(root_id: String, account_id: String) -> Result<String, RoomError> {
+    let conn = DB.lock().unwrap();
+    match find_accessible_message(&conn, &root_id, &account_id)? {
+        (_, root) if root.thread_root.is_some() => Err(RoomError::NotThreadRoot),
+        (room_id, _) => Ok(room_id),
+    }
+}
+
+/// A thread's root message and a page of its replies, oldest first.
+pub async fn get_thread(root_id: String, account_id: String, after: Option<String>, limit: usize) -> Result<ThreadDTO, RoomError> {
+    let conn = DB.lock().unwrap();
+    let (_, root) = find_accessible_message(&conn, &root_id, &account_id)?;
+    if root.thread_root.is_some() {
+        return Err(RoomError::NotThreadRoot);
+    }
+
+    let fetch = (limit + 1) as i64;
+    let mut replies: Vec<Message> = match &after {
+        None => conn
+            .prepare(&format!(
+                "SELECT {} FROM {} WHERE m.thread_root = ?1 ORDER BY m.date, m.id LIMIT ?2;",
+                MESSAGE_COLUMNS, MESSAGE_SOURCE,
+            ))
+            .and_then(|mut stmt| stmt.query_map(params![&root_id, fetch], message_from_row)?.collect())
+            .expect("Failed to query thread"),
+        Some(anchor) => {
+            let anchor_in_thread = conn
+                .query_row("SELECT 1 FROM messages WHERE id = ?1 AND thread_root = ?2;", [anchor, &root_id], |_| Ok(()))
+                .optional()
+                .expect("Failed to query message");
+            if anchor_in_thread.is_none() {
+                return Err(RoomError::InvalidCursor);
+            }
+
+            conn
+                .prepare(&format!(
+                    "SELECT {} FROM {}, messages a
+                     WHERE a.id = ?2 AND m.thread_root = ?1 AND (m.date, m.id) > (a.date, a.id)
+                     ORDER BY m.date, m.id LIMIT ?3;",
+                    MESSAGE_COLUMNS, MESSAGE_SOURCE,
+                ))
+                .and_then(|mut stmt| stmt.query_map(params![&root_id, anchor, fetch], message_from_row)?.collect())
+                .expect("Failed to query thread")
+        }
+    };
+
+    let has_more = replies.len() > limit;
+    replies.truncate(limit);
+    Ok(ThreadDTO { root, replies, has_more })
+}
+
+fn find_message(conn: &Connection, message_id: &str) -> Result<(String, Message), RoomError> {
+    conn.query_row(
+        &format!("SELECT m.room_id, {} FROM {} WHERE m.id = ?1;", MESSAGE_COLUMNS, MESSAGE_SOURCE),
+        [message_id],
+        |row| Ok((row.get(0)?, message_from_row_at(row, 1)?)),
+    )
+        .optional()
+        .expect("Failed to query message")
+        .ok_or(RoomError::MessageNotFound)
+}
+
+/// Replaces the message in the cached page, if it is there, and tells
+/// subscribers about the change.
+fn publish_change(rooms: &mut RoomCache, room_id: &str, message: &Message, event: RoomEvent) {
+    if let Some(room) = cached_room(rooms, room_id) {
+        if let Some(cached) = room.messages.iter_mut().find(|cached| cached.id == message.id) {
+            *cached = message.clone();
+        }
+        room.publish(event);
+    }
+}
+
+/// Messages of a room changed after `since`, in the order of their latest
+/// change, and whether more changes follow the page.
+pub async fn get_changes(room_id: String, since: i64, limit: usize) -> Result<(Vec<Message>, bool), RoomError> {
+    let conn = DB.lock().unwrap();
+    let mut messages: Vec<Message> = conn
+        .prepare(&format!(
+            "SELECT {} FROM {} WHERE m.room_id = ?1 AND m.updated_seq > ?2 ORDER BY m.updated_seq LIMIT ?3;",
+            MESSAGE_COLUMNS, MESSAGE_SOURCE,
+        ))
+        .and_then(|mut stmt| stmt.query_map(params![&room_id, since, (limit + 1) as i64], message_from_row)?.collect())
+        .expect("Failed to query message changes");
+
+    let has_more = messages.len() > limit;
+    messages.truncate(limit);
+    Ok((messages, has_more))
+}
+
+/// Only the author may edit a message. The previous content is kept in
+/// `message_edits`. Accounts the edit newly mentions are notified.
+pub async fn edit_message(message_id: String, account: Account, content: String) -> Result<Message, RoomError> {
+    let mut rooms = ROOMS.lock().unwrap();
+
+    let (room_id, message, newly_mentioned) = {
+        let conn = DB.lock().unwrap();
+        let (room_id, message) = find_accessible_message(&conn, &message_id, &account.id)?;
+        check_writable(&conn, &room_id)?;
+        if message.deleted_at.is_some() {
+            return Err(RoomError::MessageDeleted);
+        }
+        if message.username != account.name {
+            return Err(RoomError::Forbidden);
+        }
+
+        let previously_mentioned: Vec<String> = conn
+            .prepare("SELECT account_id FROM mentions WHERE message_id = ?1;")
+            .and_then(|mut stmt| stmt.query_map([&message.id], |row| row.get(0))?.collect())
+            .expect("Failed to query mentions");
+        let (mentions, mentioned) = resolve_mentions(&conn, &room_id, &message.username, &content);
+
+        let edited_at = chrono::Utc::now().to_rfc3339();
+        let tx = conn.unchecked_transaction().expect("Failed to start transaction");
+        let updated_seq = next_seq(&tx, &room_id);
+        tx.execute(
+            "INSERT INTO message_edits (message_id, content, editor_id, edited_at) VALUES (?1, ?2, ?3, ?4);",
+            [&message.id, &message.content, &account.id, &edited_at],
+        )
+            .expect("Failed to insert message edit into database");
+        tx.execute(
+            "UPDATE messages SET content = ?1, edited_at = ?2, updated_seq = ?3, mentions = ?4 WHERE id = ?5;",
+            params![&content, &edited_at, updated_seq, serde_json::to_string(&mentions).unwrap(), &message.id],
+        )
+            .expect("Failed to update message in database");
+        save_mentions(&tx, &message.id, &mentioned);
+        tx.commit().expect("Failed to commit message edit");
+
+        let newly_mentioned = mentioned.into_iter().filter(|id| !previously_mentioned.contains(id)).collect();
+        (room_id, Message { content, edited_at: Some(edited_at), updated_seq, mentions, ..message }, newly_mentioned)
+    };
+
+    publish_change(&mut rooms, &room_id, &message, RoomEvent::MessageEdited(message.clone()));
+    notify_mentioned(&room_id, &message, newly_mentioned);
+    Ok(message)
+}
+
+/// The author or a room owner or moderator may delete a message. The row stays
+/// so history pages keep their shape, but its content, edit history, reactions
+/// and mentions are removed.
+pub async fn delete_message(message_id: String, account: Account) -> Result<Message, RoomError> {
+    let mut rooms = ROOMS.lock().unwrap();
+
+    let (room_id, message) = {
+        let conn = DB.lock().unwrap();
+        let (room_id, message) = find_accessible_message(&conn, &message_id, &account.id)?;
+        check_writable(&conn, &room_id)?;
+        (room_id, message)
+    };
+    if message.deleted_at.is_some() {
+        return Err(RoomError::MessageDeleted);
+    }
+    if message.username != account.name && !get_role(&room_id, &account.id)?.can_manage() {
+        return Err(RoomError::Forbidden);
+    }
+
+    let deleted_at = chrono::Utc::now().to_rfc3339();
+    let updated_seq = {
+        let conn = DB.lock().unwrap();
+        let tx = conn.unchecked_transaction().expect("Failed to start transaction");
+        let updated_seq = next_seq(&tx, &room_id);
+        tx.execute("DELETE FROM message_edits WHERE message_id = ?1;", [&message.id])
+            .expect("Failed to delete message edits from database");
+        tx.execute("DELETE FROM reactions WHERE message_id = ?1;", [&message.id])
+            .expect("Failed to delete reactions from database");
+        save_mentions(&tx, &message.id, &[]);
+        tx.execute(
+            "UPDATE messages SET content = '', deleted_at = ?1, updated_seq = ?2, mentions = '[]' WHERE id = ?3;",
+            params![&deleted_at, updated_seq, &message.id],
+        )
+            .expect("Failed to delete message from database");
+        tx.commit().expect("Failed to commit message deletion");
+        updated_seq
+    };
+
+    let message = Message { content: String::new(), deleted_at: Some(deleted_at), reactions: vec![], updated_seq, mentions: vec![], ..message };
+    publish_change(&mut rooms, &room_id, &message, RoomEvent::MessageDeleted(message.clone()));
+    Ok(message)
+}
+
+/// Previous versions of a message, oldest first.
+pub async fn get_message_history(message_id: String, account_id: String) -> Result<Vec<MessageEditDTO>, RoomError> {
+    let conn = DB.lock().unwrap();
+    find_accessible_message(&conn, &message_id, &account_id)?;
+
+    let mut stmt = conn
+        .prepare("SELECT content, editor_id, edited_at FROM message_edits WHERE message_id = ?1 ORDER BY id;")
+        .expect("Failed to prepare statement");
+
+    let edits = stmt
+        .query_map([&message_id], |row| {
+            Ok(MessageEditDTO {
+                content: row.get(0)?,
+                editor_id: row.get(1)?,
+                edited_at: row.get(2)?,
+            })
+        })
+        .expect("Failed to query message edits");
+
+    Ok(edits.flatten().collect())
+}
+
+/// Adds or removes one account's reaction. Each account can react with a
+/// given emoji once, so repeating an add or remove changes nothing and
+/// publishes no event.
+async fn change_reaction(message_id: String, account: Account, emoji: String, add: bool) -> Result<Message, RoomError> {
+    let mut rooms = ROOMS.lock().unwrap();
+
+    let (room_id, message, changed) = {
+        let conn = DB.lock().unwrap();
+        let (room_id, message) = find_accessible_message(&conn, &message_id, &account.id)?;
+        check_writable(&conn, &room_id)?;
+        if message.deleted_at.is_some() {
+            return Err(RoomError::MessageDeleted);
+        }
+
+        let tx = conn.unchecked_transaction().expect("Failed to start transaction");
+        let changed = if add {
+            tx.execute(
+                "INSERT OR IGNORE INTO reactions (message_id, account_id, emoji, created_at) VALUES (?1, ?2, ?3, ?4);",
+                [&message_id, &account.id, &emoji, &chrono::Utc::now().to_rfc3339()],
+            )
+        } else {
+            tx.execute(
+                "DELETE FROM reactions WHERE message_id = ?1 AND account_id = ?2 AND emoji = ?3;",
+                [&message_id, &account.id, &emoji],
             )
-                .expect("Failed to insert message into database");
         }
+            .expect("Failed to update reactions in database");
 
-        room.messages.push(message.clone());
-        room.sender.send(message.clone()).unwrap_or(0);
+        if changed > 0 {
+            tx.execute(
+                "UPDATE messages SET updated_seq = ?1 WHERE id = ?2;",
+                params![next_seq(&tx, &room_id), &message_id],
+            )
+                .expect("Failed to update message in database");
+        }
+        tx.commit().expect("Failed to commit reaction change");
 
-        Ok(message)
-    } else {
-        Err(format!("Room with id {} not found", id))
+        let (_, message) = find_message(&conn, &message_id)?;
+        (room_id, message, changed > 0)
+    };
+
+    if changed {
+        let reaction = ReactionEventDTO {
+            message_id: message.id.clone(),
+            thread_root: message.thread_root.clone(),
+            count: message.reactions.iter().find(|reaction| reaction.emoji == emoji).map_or(0, |reaction| reaction.count),
+            emoji,
+            username: account.name,
+            updated_seq: message.updated_seq,
+        };
+        let event = if add { RoomEvent::ReactionAdded(reaction) } else { RoomEvent::ReactionRemoved(reaction) };
+        publish_change(&mut rooms, &room_id, &message, event);
+    }
+
+    Ok(message)
+}
+
+pub async fn add_reaction(message_id: String, account: Account, emoji: String) -> Result<Message, RoomError> {
+    change_reaction(message_id, account, emoji, true).await
+}
+
+pub async fn remove_reaction(message_id: String, account: Account, emoji: String) -> Result<Message, RoomError> {
+    change_reaction(message_id, account, emoji, false).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database() -> Connection {
+        let conn = open_database(":memory:");
+        conn.execute_batch(include_str!("../../schema.sql")).unwrap();
+        conn.execute_batch(
+            "INSERT INTO accounts (id, name, password) VALUES
+                 ('alice-id', 'alice', ''), ('bob-id', 'bob', ''), ('carol-id', 'Carol', ''), ('dave-id', 'dave', '');
+             INSERT INTO rooms (id, name, owner_id, kind) VALUES
+                 ('public', 'general', 'alice-id', 'public'), ('private', 'secret', 'alice-id', 'private');
+             INSERT INTO room_members (room_id, account_id, role) VALUES
+                 ('private', 'alice-id', 'owner'), ('private', 'bob-id', 'member');
+             INSERT INTO messages (id, room_id, username, content, date) VALUES
+                 ('m1', 'public', 'dave', 'hello', '2024-01-01T00:00:00+00:00');",
+        )
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn opening_a_direct_conversation_reaches_every_participant() {
+        let conn = database();
+        let mut events = ROOM_SENDER.subscribe();
+        let alice = Account { id: "alice-id".to_string(), name: "alice".to_string(), password: String::new() };
+
+        let (id, opened) = open_direct_with(&conn, alice, vec!["bob".to_string()]).unwrap();
+        assert!(opened.is_some());
+
+        let event = loop {
+            match events.try_recv().unwrap() {
+                RoomListEvent::Created(room) if room.id == id => break RoomListEvent::Created(room),
+                _ => continue,
+            }
+        };
+        assert!(is_list_event_visible_with(&conn, &event, "bob-id"));
+        assert!(is_list_event_visible_with(&conn, &event, "alice-id"));
+        assert!(!is_list_event_visible_with(&conn, &event, "dave-id"));
+    }
+
+    #[test]
+    fn deleting_a_room_cascades_to_its_rows() {
+        let conn = database();
+        conn.execute_batch(
+            "INSERT INTO messages (id, room_id, username, content, date) VALUES
+                 ('m2', 'private', 'bob', 'hi', '2024-01-01T00:00:00+00:00');
+             INSERT INTO reactions (message_id, account_id, emoji, created_at) VALUES ('m2', 'alice-id', '👍', '');
+             INSERT INTO mentions (message_id, account_id) VALUES ('m2', 'alice-id');
+             INSERT INTO read_positions (room_id, account_id, last_read_seq, updated_at) VALUES ('private', 'bob-id', 1, '');
+             DELETE FROM rooms WHERE id = 'private';",
+        )
+        .unwrap();
+
+        for table in ["room_members", "messages WHERE room_id = 'private'", "reactions", "mentions", "read_positions"] {
+            let count: i64 = conn.query_row(&format!("SELECT COUNT(*) FROM {};", table), [], |row| row.get(0)).unwrap();
+            assert_eq!(count, 0, "{}", table);
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn manager_check_hides_private_rooms_from_non_members() {
+        let conn = database();
+        assert_eq!(require_manager_with(&conn, "private", "dave-id"), Err(RoomError::NotFound));
+        assert_eq!(RoomError::NotFound.status(), crate::entity::response::Status::NotFound);
+        assert_eq!(require_manager_with(&conn, "private", "bob-id"), Err(RoomError::Forbidden));
+        assert_eq!(require_manager_with(&conn, "private", "alice-id"), Ok(()));
+        assert_eq!(require_manager_with(&conn, "public", "dave-id"), Err(RoomError::Forbidden));
+    }
+
+    #[test]
+    fn resolves_handles_case_insensitively() {
+        let conn = database();
+        assert_eq!(
+            resolve_mentions(&conn, "public", "alice", "@bob and @carol"),
+            (vec!["bob".to_string(), "carol".to_string()], vec!["bob-id".to_string(), "carol-id".to_string()])
+        );
+    }
+
+    #[test]
+    fn skips_unknown_accounts_and_the_author() {
+        let conn = database();
+        assert_eq!(
+            resolve_mentions(&conn, "public", "alice", "@nobody @alice"),
+            (vec!["alice".to_string()], vec![])
+        );
+    }
+
+    #[test]
+    fn skips_accounts_without_access() {
+        let conn = database();
+        assert_eq!(
+            resolve_mentions(&conn, "private", "alice", "@dave @bob"),
+            (vec!["bob".to_string()], vec!["bob-id".to_string()])
+        );
+    }
+
+    #[test]
+    fn room_mentions_participants_once() {
+        let conn = database();
+        assert_eq!(
+            resolve_mentions(&conn, "private", "alice", "@room @bob"),
+            (vec!["room".to_string(), "bob".to_string()], vec!["bob-id".to_string()])
+        );
+        assert_eq!(
+            resolve_mentions(&conn, "public", "alice", "@room"),
+            (vec!["room".to_string()], vec!["dave-id".to_string()])
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::entity::room::Room;
+
+struct CachedRoom {
+    room: Room,
+    last_used: Instant,
+}
+
+impl CachedRoom {
+    /// Rooms with live subscribers are never evicted, so everyone listening
+    /// to a room always shares the sender that new messages are published on.
+    fn has_subscribers(&self) -> bool {
+        self.room.sender.receiver_count() > 0
+    }
+}
+
+/// Least-recently-used cache of active rooms. A room is active while it has
+/// subscribers or has been used within `ttl`; idle rooms are dropped and
+/// loaded again from the database on their next use.
+pub struct RoomCache {
+    rooms: HashMap<String, CachedRoom>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+impl RoomCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        RoomCache {
+            rooms: HashMap::new(),
+            capacity: capacity.max(1),
+            ttl,
+        }
+    }
+
+    /// Returns the cached room and marks it as recently used.
+    pub fn get(&mut self, id: &str) -> Option<&mut Room> {
+        self.rooms.get_mut(id).map(|cached| {
+            cached.last_used = Instant::now();
+            &mut cached.room
+        })
+    }
+
+    pub fn insert(&mut self, room: Room) -> &mut Room {
+        if !self.rooms.contains_key(&room.id) {
+            self.evict_for_capacity();
+        }
+
+        let id = room.id.clone();
+        self.rooms.insert(id.clone(), CachedRoom { room, last_used: Instant::now() });
+        &mut self.rooms.get_mut(&id).unwrap().room
+    }
+
+    pub fn remove(&mut self, id: &str) -> Option<Room> {
+        self.rooms.remove(id).map(|cached| cached.room)
+    }
+
+    /// Drops rooms without subscribers that have not been used within `ttl`.
+    pub fn evict_idle(&mut self) -> usize {
+        let now = Instant::now();
+        let ttl = self.ttl;
+        let before = self.rooms.len();
+        self.rooms.retain(|_, cached| cached.has_subscribers() || now.duration_since(cached.last_used) < ttl);
+        before - self.rooms.len()
+    }
+
+    /// Makes room for one more entry by dropping the least recently used rooms
+    /// without subscribers. When every cached room has subscribers the cache
+    /// is allowed to grow past its capacity instead.
+    fn evict_for_capacity(&mut self) {
+        while self.rooms.len() >= self.capacity {
+            let oldest = self.rooms
+                .iter()
+                .filter(|(_, cached)| !cached.has_subscribers())
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(id, _)| id.clone());
+
+            match oldest {
+                Some(id) => self.rooms.remove(&id),
+                None => break,
+            };
+        }
+    }
+}
@@ -1,38 +1,254 @@
-use serde::Serialize;
-use std::sync::{Mutex};
-use uuid::Uuid;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use chrono::{DateTime, SecondsFormat, TimeDelta, Utc};
+use password_hash::rand_core::{OsRng, RngCore};
+use rusqlite::{Connection, Row};
+use sha2::{Digest, Sha256};
 use crate::entity::session::Session;
+use crate::repository::connection;
+use crate::utils::config::CONFIG;
+use crate::repository::open_database;
 
 lazy_static::lazy_static! {
-    #[derive(Debug, Serialize, Clone)]
-    pub static ref SESSIONS: Mutex<Vec<Session>> = Mutex::new(vec![]);
+    pub static ref SESSIONS: Mutex<HashMap<String, Session>> = Mutex::new(HashMap::new());
+    pub static ref DB: Mutex<Connection> = Mutex::new(open_database("data.db"));
 }
 
-pub fn create_session(id: String) -> Session {
-    let mut sessions = SESSIONS.lock().unwrap();
+/// `last_seen_at` is only written back once it is older than this, so active
+/// sessions do not cause a database write on every request.
+const LAST_SEEN_RESOLUTION: TimeDelta = TimeDelta::seconds(60);
+
+fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+pub fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn format_time(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn parse_time(value: String) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(&value)
+        .map(|time| time.with_timezone(&Utc))
+        .unwrap_or(DateTime::UNIX_EPOCH)
+}
+
+fn session_from_row(row: &Row) -> rusqlite::Result<Session> {
+    Ok(Session {
+        token_hash: row.get(0)?,
+        id: row.get(1)?,
+        created_at: parse_time(row.get(2)?),
+        last_seen_at: parse_time(row.get(3)?),
+        expires_at: parse_time(row.get(4)?),
+        rotated_at: parse_time(row.get(5)?),
+        replaced_by: row.get(6)?,
+        replaced_at: row.get::<_, Option<String>>(7)?.map(parse_time),
+    })
+}
+
+/// Sliding expiration: a session lives `session_ttl` past its last use, but
+/// never longer than `session_max_lifetime` from creation.
+fn expiry_for(created_at: &DateTime<Utc>, now: &DateTime<Utc>) -> DateTime<Utc> {
+    let sliding = *now + TimeDelta::from_std(CONFIG.session_ttl).unwrap_or(TimeDelta::MAX);
+    let absolute = *created_at + TimeDelta::from_std(CONFIG.session_max_lifetime).unwrap_or(TimeDelta::MAX);
+    sliding.min(absolute)
+}
+
+/// Creates a session for the account and returns the plain token together
+/// with the stored session. Only the token hash is persisted.
+pub fn create_session(id: String) -> (String, Session) {
+    let now = Utc::now();
+    let conn = DB.lock().unwrap();
+    insert_session(&conn, id, now, now)
+}
+
+/// Stores a new token for a session that began at `created_at`.
+fn insert_session(conn: &Connection, id: String, created_at: DateTime<Utc>, now: DateTime<Utc>) -> (String, Session) {
+    let token = generate_token();
     let session = Session {
         id,
-        token: Uuid::new_v4().to_string(),
+        token_hash: hash_token(&token),
+        created_at,
+        rotated_at: now,
+        last_seen_at: now,
+        expires_at: expiry_for(&created_at, &now),
+        replaced_by: None,
+        replaced_at: None,
+    };
+
+    conn.execute(
+        "INSERT INTO sessions (token_hash, account_id, created_at, rotated_at, last_seen_at, expires_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6);",
+        [
+            &session.token_hash,
+            &session.id,
+            &format_time(&session.created_at),
+            &format_time(&session.rotated_at),
+            &format_time(&session.last_seen_at),
+            &format_time(&session.expires_at),
+        ],
+    )
+        .expect("Failed to insert session into database");
+
+    let mut sessions = SESSIONS.lock().unwrap();
+    sessions.insert(session.token_hash.clone(), session.clone());
+    (token, session)
+}
+
+/// Looks up a live session by token, extending its expiry on success.
+pub fn find_session(token: &str) -> Option<Session> {
+    let conn = DB.lock().unwrap();
+    find_session_with(&conn, token, Utc::now())
+}
+
+fn find_session_with(conn: &Connection, token: &str, now: DateTime<Utc>) -> Option<Session> {
+    let token_hash = hash_token(token);
+    let cached = SESSIONS.lock().unwrap().get(&token_hash).cloned();
+
+    let mut session = match cached {
+        Some(session) => session,
+        None => {
+            let session = conn
+                .query_row(
+                    "SELECT token_hash, account_id, created_at, last_seen_at, expires_at, rotated_at, replaced_by, replaced_at
+                     FROM sessions WHERE token_hash = ?1;",
+                    [&token_hash],
+                    session_from_row,
+                )
+                .ok()?;
+
+            SESSIONS.lock().unwrap().insert(token_hash.clone(), session.clone());
+            session
+        }
     };
 
-    sessions.push(session.clone());
-    session
+    if session.expires_at <= now {
+        stop_session_with(conn, &token_hash);
+        return None;
+    }
+
+    // A replaced token only lives out its grace period; use does not extend it.
+    if session.replaced_at.is_none() && now - session.last_seen_at >= LAST_SEEN_RESOLUTION {
+        session.last_seen_at = now;
+        session.expires_at = expiry_for(&session.created_at, &now);
+
+        if let Err(err) = conn.execute(
+            "UPDATE sessions SET last_seen_at = ?1, expires_at = ?2 WHERE token_hash = ?3;",
+            [&format_time(&session.last_seen_at), &format_time(&session.expires_at), &token_hash],
+        ) {
+            eprintln!("Failed to refresh session: {}", err);
+        }
+
+        SESSIONS.lock().unwrap().insert(token_hash, session.clone());
+    }
+
+    Some(session)
 }
 
 pub fn match_and_return_session(id: String, token: String) -> Option<Session> {
-    let sessions = SESSIONS.lock().unwrap();
+    find_session(&token).filter(|session| session.id == id)
+}
+
+/// Issues a new token for the same account. The old one keeps working for
+/// `session_rotation_grace`, so requests a page sent alongside the rotating
+/// one still authorize, and is then removed with the expired sessions.
+/// The new token does not extend the session past its original lifetime.
+/// Sockets opened with the old token stay open and follow the new one.
+pub fn rotate_session(session: &Session) -> (String, Session) {
+    let conn = DB.lock().unwrap();
+    rotate_session_with(&conn, session, Utc::now())
+}
+
+fn rotate_session_with(conn: &Connection, session: &Session, now: DateTime<Utc>) -> (String, Session) {
+    let (token, rotated) = insert_session(conn, session.id.clone(), session.created_at, now);
+
+    let grace_end = now + TimeDelta::from_std(CONFIG.session_rotation_grace).unwrap_or(TimeDelta::zero());
+    let replaced = Session {
+        replaced_by: Some(rotated.token_hash.clone()),
+        replaced_at: Some(now),
+        expires_at: session.expires_at.min(grace_end),
+        ..session.clone()
+    };
+    if let Err(err) = conn.execute(
+        "UPDATE sessions SET replaced_by = ?1, replaced_at = ?2, expires_at = ?3 WHERE token_hash = ?4;",
+        [&rotated.token_hash, &format_time(&now), &format_time(&replaced.expires_at), &session.token_hash],
+    ) {
+        eprintln!("Failed to mark session as replaced: {}", err);
+    }
+    SESSIONS.lock().unwrap().insert(session.token_hash.clone(), replaced);
+
+    connection::rekey_session(&session.token_hash, &rotated.token_hash);
+    (token, rotated)
+}
+
+/// A token that was already replaced is not rotated again.
+pub fn needs_rotation(session: &Session) -> bool {
+    let age = Utc::now() - session.rotated_at;
+    session.replaced_at.is_none() && age.to_std().map(|age| age >= CONFIG.session_rotation_interval).unwrap_or(false)
+}
 
-    sessions.iter().find(|session| {
-        session.id == id && session.token == token
-    }).cloned()
+pub fn stop_session(token_hash: &str) -> bool {
+    let conn = DB.lock().unwrap();
+    stop_session_with(&conn, token_hash)
 }
 
-pub fn stop_session(id: &str) -> bool {
+fn stop_session_with(conn: &Connection, token_hash: &str) -> bool {
+    if let Err(err) = conn.execute("DELETE FROM sessions WHERE token_hash = ?1;", [token_hash]) {
+        eprintln!("Failed to delete session: {}", err);
+    }
+
     let mut sessions = SESSIONS.lock().unwrap();
+    sessions.remove(token_hash).is_some()
+}
 
-    let initial_length = sessions.len();
-    sessions.retain(|session| session.id != id);
-    let final_length = sessions.len();
+pub fn cleanup_expired_sessions() -> usize {
+    let now = Utc::now();
+    let conn = DB.lock().unwrap();
+    let removed = conn
+        .execute("DELETE FROM sessions WHERE expires_at <= ?1;", [&format_time(&now)])
+        .unwrap_or_else(|err| {
+            eprintln!("Failed to clean up sessions: {}", err);
+            0
+        });
 
-    initial_length != final_length
-}
\ No newline at end of file
+    let mut sessions = SESSIONS.lock().unwrap();
+    sessions.retain(|_, session| session.expires_at > now);
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("../../schema.sql")).unwrap();
+        conn.execute_batch("INSERT INTO accounts (id, name, password) VALUES ('alice-id', 'alice', '');").unwrap();
+        conn
+    }
+
+    #[test]
+    fn replaced_token_authorizes_during_grace_period() {
+        let conn = database();
+        let now = Utc::now();
+        let (old_token, session) = insert_session(&conn, "alice-id".to_string(), now, now);
+        let (new_token, _) = rotate_session_with(&conn, &session, now);
+        let grace = TimeDelta::from_std(CONFIG.session_rotation_grace).unwrap();
+
+        let old = find_session_with(&conn, &old_token, now + TimeDelta::seconds(1)).unwrap();
+        assert_eq!(old.id, "alice-id");
+        assert!(!needs_rotation(&old));
+        assert!(find_session_with(&conn, &old_token, now + grace - TimeDelta::seconds(1)).is_some());
+
+        assert!(find_session_with(&conn, &old_token, now + grace).is_none());
+        assert!(find_session_with(&conn, &new_token, now + grace).is_some());
+    }
+}
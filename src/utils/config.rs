@@ -0,0 +1,77 @@
+use std::env;
+use std::str::FromStr;
+use std::time::Duration;
+use once_cell::sync::Lazy;
+
+pub static CONFIG: Lazy<Config> = Lazy::new(Config::from_env);
+
+pub struct Config {
+    pub max_header_size: usize,
+    pub max_body_size: usize,
+    pub keep_alive_timeout: Duration,
+    pub request_read_timeout: Duration,
+    pub max_keep_alive_requests: usize,
+    pub auth_rate_limit: u32,
+    pub auth_rate_limit_window: Duration,
+    pub argon2_memory_kib: u32,
+    pub argon2_iterations: u32,
+    pub argon2_parallelism: u32,
+    pub session_ttl: Duration,
+    pub session_max_lifetime: Duration,
+    pub session_rotation_interval: Duration,
+    pub session_rotation_grace: Duration,
+    pub session_cleanup_interval: Duration,
+    pub message_page_size: usize,
+    pub max_message_page_size: usize,
+    pub room_cache_capacity: usize,
+    pub room_cache_ttl: Duration,
+    pub ws_max_subscriptions: usize,
+    pub ws_ping_interval: Duration,
+    pub ws_pong_timeout: Duration,
+    pub ws_idle_timeout: Duration,
+    pub typing_throttle: Duration,
+    pub typing_ttl: Duration,
+    pub max_direct_participants: usize,
+    pub tls: bool,
+}
+
+impl Config {
+    fn from_env() -> Self {
+        Config {
+            max_header_size: env_or("CHAT_MAX_HEADER_SIZE", 16 * 1024),
+            max_body_size: env_or("CHAT_MAX_BODY_SIZE", 1024 * 1024),
+            keep_alive_timeout: Duration::from_secs(env_or("CHAT_KEEP_ALIVE_TIMEOUT_SECS", 5)),
+            request_read_timeout: Duration::from_secs(env_or("CHAT_REQUEST_READ_TIMEOUT_SECS", 30)),
+            max_keep_alive_requests: env_or("CHAT_MAX_KEEP_ALIVE_REQUESTS", 100),
+            auth_rate_limit: env_or("CHAT_AUTH_RATE_LIMIT", 20),
+            auth_rate_limit_window: Duration::from_secs(env_or("CHAT_AUTH_RATE_LIMIT_WINDOW_SECS", 60)),
+            argon2_memory_kib: env_or("CHAT_ARGON2_MEMORY_KIB", 19 * 1024),
+            argon2_iterations: env_or("CHAT_ARGON2_ITERATIONS", 2),
+            argon2_parallelism: env_or("CHAT_ARGON2_PARALLELISM", 1),
+            session_ttl: Duration::from_secs(env_or("CHAT_SESSION_TTL_SECS", 7 * 24 * 60 * 60)),
+            session_max_lifetime: Duration::from_secs(env_or("CHAT_SESSION_MAX_LIFETIME_SECS", 30 * 24 * 60 * 60)),
+            session_rotation_interval: Duration::from_secs(env_or("CHAT_SESSION_ROTATION_SECS", 60 * 60)),
+            session_rotation_grace: Duration::from_secs(env_or("CHAT_SESSION_ROTATION_GRACE_SECS", 30)),
+            session_cleanup_interval: Duration::from_secs(env_or("CHAT_SESSION_CLEANUP_SECS", 10 * 60)),
+            message_page_size: env_or("CHAT_MESSAGE_PAGE_SIZE", 50),
+            max_message_page_size: env_or("CHAT_MAX_MESSAGE_PAGE_SIZE", 200),
+            room_cache_capacity: env_or("CHAT_ROOM_CACHE_CAPACITY", 1000),
+            room_cache_ttl: Duration::from_secs(env_or("CHAT_ROOM_CACHE_TTL_SECS", 10 * 60)),
+            ws_max_subscriptions: env_or("CHAT_WS_MAX_SUBSCRIPTIONS", 100),
+            ws_ping_interval: Duration::from_secs(env_or("CHAT_WS_PING_INTERVAL_SECS", 30)),
+            ws_pong_timeout: Duration::from_secs(env_or("CHAT_WS_PONG_TIMEOUT_SECS", 10)),
+            ws_idle_timeout: Duration::from_secs(env_or("CHAT_WS_IDLE_TIMEOUT_SECS", 90)),
+            typing_throttle: Duration::from_secs(env_or("CHAT_TYPING_THROTTLE_SECS", 3)),
+            typing_ttl: Duration::from_secs(env_or("CHAT_TYPING_TTL_SECS", 6)),
+            max_direct_participants: env_or("CHAT_DM_MAX_PARTICIPANTS", 8),
+            tls: env_or("CHAT_TLS", false),
+        }
+    }
+}
+
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
@@ -5,140 +5,101 @@ use tokio_tungstenite::tungstenite::protocol::Message;
 use tokio_tungstenite::WebSocketStream;
 use tokio::net::TcpStream;
 use tungstenite::protocol::CloseFrame;
+use tungstenite::protocol::frame::coding::CloseCode;
 use futures_util::{SinkExt, StreamExt};
 use serde::Deserialize;
 use tokio::fs::File;
 use tokio::io;
 use crate::entity::request_data::RequestData;
+use crate::entity::response::{Response, Status};
+use crate::entity::room::{RoomError, RoomErrorDTO};
+use crate::utils::utils::WS_VERSION;
 
-pub async fn ok(stream: TcpStream) -> io::Result<()> {
-    let response = "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
-    finish_request(stream, response).await
+pub fn ok() -> Response {
+    Response::new(Status::Ok)
 }
 
-pub async fn not_found(stream: TcpStream) -> io::Result<()> {
-    let response = "HTTP/1.1 404 NOT_FOUND\r\nContent-Length: 0\r\n\r\n";
-    finish_request(stream, response).await
+pub fn not_found() -> Response {
+    Response::new(Status::NotFound)
 }
 
-pub async fn invalid(stream: TcpStream) -> io::Result<()> {
-    let response = "HTTP/1.1 400 INVALID_REQUEST\r\nContent-Length: 0\r\n\r\n";
-    finish_request(stream, response).await
+pub fn invalid() -> Response {
+    Response::new(Status::BadRequest)
 }
 
-pub async fn unauthenticated(stream: TcpStream) -> io::Result<()> {
-    let response = "HTTP/1.1 401 UNAUTHENTICATED\r\nContent-Length: 0\r\n\r\n";
-    finish_request(stream, response).await
+pub fn unauthenticated() -> Response {
+    Response::new(Status::Unauthorized)
 }
 
-pub async fn finish_request(stream: TcpStream, response: &str) -> io::Result<()> {
-    let mut locked_stream = stream;
-    locked_stream.write_all(response.as_bytes()).await?;
-    locked_stream.flush().await?;
-
-    Ok(())
+pub fn internal_error() -> Response {
+    Response::new(Status::InternalServerError)
 }
 
-pub fn is_route(method: &str, path: &str, prefix: &str, data: &mut RequestData) -> bool {
-    let (request_path, _query) = data.path.split_once('?').unwrap_or((&data.path, ""));
-
-    if path.is_empty() {
-        return (request_path == prefix || request_path == format!("{}/", prefix))
-            && data.method == method;
-    }
-
-    let full_path = if prefix.ends_with('/') || path.starts_with('/') {
-        format!("{}{}", prefix, path)
-    } else {
-        format!("{}/{}", prefix, path)
-    };
-
-    let expected_segments: Vec<&str> = full_path.split('/').collect();
-    let request_segments: Vec<&str> = request_path.split('/').collect();
-
-    if data.method != method {
-        return false;
-    }
+pub fn room_error(error: RoomError) -> Response {
+    Response::json(&RoomErrorDTO::from(error)).with_status(error.status())
+}
 
-    if expected_segments.len() != request_segments.len() {
-        return false;
-    }
+pub fn method_not_allowed(allowed: &[String]) -> Response {
+    Response::new(Status::MethodNotAllowed).with_header("Allow", &allowed.join(", "))
+}
 
-    let normalized_expected = expected_segments
-        .iter()
-        .map(|&segment| if segment.starts_with(':') { "" } else { segment })
-        .collect::<Vec<_>>()
-        .join("/");
+pub fn payload_too_large() -> Response {
+    Response::new(Status::PayloadTooLarge)
+}
 
+pub fn headers_too_large() -> Response {
+    Response::new(Status::RequestHeaderFieldsTooLarge)
+}
 
-    let normalized_request = request_segments
-        .iter()
-        .zip(expected_segments.iter())
-        .map(|(&req_segment, &expected_segment)| {
-            if expected_segment.starts_with(':') {
-                ""
-            } else {
-                req_segment
-            }
-        })
-        .collect::<Vec<_>>()
-        .join("/");
-
-    data.params.clear();
-    for (expected_segment, request_segment) in expected_segments.iter().zip(request_segments.iter())
-    {
-        if expected_segment.starts_with(':') {
-            let key = expected_segment.trim_start_matches(':').to_string();
-            data.params.insert(key, request_segment.to_string());
-        } else if expected_segment != request_segment {
-            return false;
-        }
-    }
+pub async fn write_response(stream: &mut TcpStream, response: &Response, keep_alive: bool) -> io::Result<()> {
+    stream.write_all(&response.to_bytes(keep_alive)).await?;
+    stream.flush().await?;
 
-    normalized_request == normalized_expected
+    Ok(())
 }
 
 pub async fn close_ws_with_error(
-    ws_stream: WebSocketStream<&mut TcpStream>,
+    ws_stream: WebSocketStream<TcpStream>,
     code: u16,
-    reason: String,
+    reason: &str,
 ) -> Result<(), io::Error> {
     let (mut sender, _) = ws_stream.split();
 
     let close_message = Message::Close(Some(CloseFrame {
-        code: tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode::from(code),
-        reason: reason.into(),
+        code: CloseCode::from(code),
+        reason: reason.to_string().into(),
     }));
 
     sender.send(close_message).await.map_err(|e| {
         eprintln!("Failed to close WebSocket connection: {}", e);
-        io::Error::new(io::ErrorKind::Other, "Failed to close WebSocket")
+        io::Error::other("Failed to close WebSocket")
     })
 }
 
-pub async fn serve_static(data: RequestData) -> Result<(), io::Error> {
-    let file_path = format!(".{}", data.path);
-    if Path::new(&file_path).exists() {
-        let mut file = File::open(&file_path).await?;
-        let mut contents = Vec::new();
-
-        file.read_to_end(&mut contents).await?;
+pub async fn serve_static(data: RequestData) -> Response {
+    let relative_path = data.params.get("path").cloned().unwrap_or_default();
+    if relative_path.split('/').any(|segment| segment == ".." || segment.starts_with('.')) {
+        return not_found();
+    }
 
-        let content_type = get_content_type(&file_path);
-        let response = format!(
-            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
-            content_type,
-            contents.len()
-        );
+    let file_path = format!("./static/{}", relative_path);
+    if !Path::new(&file_path).is_file() {
+        return not_found();
+    }
 
-        let mut stream = data.stream;
-        stream.write_all(response.as_bytes()).await?;
-        stream.write_all(&contents).await?;
-    } else {
-        not_found(data.stream).await?;
+    let mut contents = Vec::new();
+    let read = match File::open(&file_path).await {
+        Ok(mut file) => file.read_to_end(&mut contents).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = read {
+        eprintln!("Failed to read static file {}: {}", file_path, err);
+        return internal_error();
     }
 
-    Ok(())
+    Response::new(Status::Ok)
+        .with_header("Content-Type", get_content_type(&file_path))
+        .with_body(contents)
 }
 
 fn get_content_type(file_path: &str) -> &str {
@@ -157,46 +118,192 @@ fn get_content_type(file_path: &str) -> &str {
     }
 }
 
-pub fn parse_body<T: for<'de> Deserialize<'de>>(buffer: &[u8]) -> Result<T, String> {
-    let content = std::str::from_utf8(buffer).map_err(|_| "Invalid UTF-8 encoding".to_string())?;
-    let body_start = content.find("\r\n\r\n")
-        .ok_or_else(|| "Invalid HTTP format: Missing body".to_string())? + 4;
-    let body = content[body_start..].trim_end_matches('\0').trim();
-    serde_json::from_str(body).map_err(|err| format!("Failed to parse JSON: {}", err))
+pub fn parse_body<T: for<'de> Deserialize<'de>>(body: &[u8]) -> Result<T, String> {
+    let content = std::str::from_utf8(body).map_err(|_| "Invalid UTF-8 encoding".to_string())?;
+    serde_json::from_str(content.trim()).map_err(|err| format!("Failed to parse JSON: {}", err))
 }
 
-pub fn parse_cookies(headers: &str) -> HashMap<String, String> {
+pub fn parse_cookies(headers: &HashMap<String, String>) -> HashMap<String, String> {
     let mut cookies = HashMap::new();
-    if let Some(cookie_header) = headers.lines().find(|line| line.starts_with("Cookie:")) {
-        if let Some(cookie_str) = cookie_header.strip_prefix("Cookie: ") {
-            for cookie in cookie_str.split(';') {
-                let mut parts = cookie.splitn(2, '=');
-                if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
-                    cookies.insert(key.trim().to_string(), value.trim().to_string());
-                }
+    if let Some(cookie_str) = headers.get("cookie") {
+        for cookie in cookie_str.split(';') {
+            let mut parts = cookie.splitn(2, '=');
+            if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
+                cookies.insert(key.trim().to_string(), value.trim().to_string());
             }
         }
     }
     cookies
 }
 
-pub fn is_ws_route(path: &str, prefix: &str, data_path: &str) -> bool {
-    let (request_path, _query) = data_path.split_once('?').unwrap_or((&data_path, ""));
+pub fn is_keep_alive(version: &str, headers: &HashMap<String, String>) -> bool {
+    let connection = headers
+        .get("connection")
+        .map(|value| value.to_ascii_lowercase())
+        .unwrap_or_default();
 
-    let prefix_path = format!("{}{}", prefix, path);
-    let prefix_path_with_slash = format!("{}/", prefix_path);
+    if connection.split(',').any(|option| option.trim() == "close") {
+        return false;
+    }
+
+    version == "HTTP/1.1" || connection.split(',').any(|option| option.trim() == "keep-alive")
+}
 
-    request_path == prefix_path || request_path == &prefix_path_with_slash
+pub fn is_websocket_upgrade(headers: &HashMap<String, String>) -> bool {
+    headers
+        .get("upgrade")
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+}
+
+/// Checks the rest of a WebSocket handshake once `Upgrade: websocket` has
+/// been seen, returning its key. Requests for another protocol version are
+/// answered with 426 and the version spoken here.
+pub fn websocket_key(headers: &HashMap<String, String>) -> Result<&str, Response> {
+    let upgrades = headers
+        .get("connection")
+        .is_some_and(|value| value.split(',').any(|option| option.trim().eq_ignore_ascii_case("upgrade")));
+    let key = headers.get("sec-websocket-key").filter(|key| !key.is_empty());
+    let version = headers.get("sec-websocket-version");
+
+    match (upgrades, key, version) {
+        (true, Some(key), Some(version)) if version == WS_VERSION => Ok(key),
+        (true, Some(_), Some(_)) => Err(Response::new(Status::UpgradeRequired).with_header("Sec-WebSocket-Version", WS_VERSION)),
+        _ => Err(invalid()),
+    }
 }
 
 pub fn get_query_params(query: &str) -> HashMap<String, String> {
     query
         .split('&')
         .filter_map(|pair| {
-            let mut parts = pair.split('=');
-            let key = parts.next()?;
-            let value = parts.next()?;
-            Some((key.to_string(), value.to_string()))
+            let (key, value) = pair.split_once('=')?;
+            Some((percent_decode(&key.replace('+', " ")), percent_decode(&value.replace('+', " "))))
         })
         .collect()
-}
\ No newline at end of file
+}
+
+/// Escapes text for use in HTML element content and attribute values.
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Encodes every byte outside the RFC 3986 unreserved set as `%XX`, so the
+/// result is safe inside header values such as `Set-Cookie`.
+pub fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            byte => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Decodes `%XX` escapes. Invalid escapes are kept as-is and invalid UTF-8 is
+/// replaced rather than rejected.
+pub fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        match bytes[index] {
+            b'%' if index + 2 < bytes.len() && bytes[index + 1].is_ascii_hexdigit() && bytes[index + 2].is_ascii_hexdigit() => {
+                let hex = std::str::from_utf8(&bytes[index + 1..index + 3]).unwrap_or("00");
+                decoded.push(u8::from_str_radix(hex, 16).unwrap_or(0));
+                index += 2;
+            }
+            byte => decoded.push(byte),
+        }
+        index += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_decode_handles_escapes() {
+        assert_eq!(percent_decode("a%20b"), "a b");
+        assert_eq!(percent_decode("%41%42"), "AB");
+        assert_eq!(percent_decode("%e2%9c%93"), "✓");
+        assert_eq!(percent_decode("a+b"), "a+b");
+    }
+
+    #[test]
+    fn percent_encode_escapes_header_and_cookie_delimiters() {
+        assert_eq!(percent_encode("alice-1_2.3~"), "alice-1_2.3~");
+        assert_eq!(percent_encode("a;b,c\r\nSet-Cookie: x"), "a%3Bb%2Cc%0D%0ASet-Cookie%3A%20x");
+        assert_eq!(percent_encode("zoë"), "zo%C3%AB");
+        assert_eq!(percent_decode(&percent_encode("zoë; x=y")), "zoë; x=y");
+    }
+
+    #[test]
+    fn percent_decode_keeps_invalid_escapes() {
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%4"), "%4");
+        assert_eq!(percent_decode("%zz"), "%zz");
+        assert_eq!(percent_decode("%4g%41"), "%4gA");
+        assert_eq!(percent_decode("%ff"), "\u{fffd}");
+    }
+
+    #[test]
+    fn escape_html_escapes_markup() {
+        assert_eq!(escape_html("<img src=x onerror='a&b'>\""), "&lt;img src=x onerror=&#39;a&amp;b&#39;&gt;&quot;");
+    }
+
+    fn handshake(headers: &[(&str, &str)]) -> HashMap<String, String> {
+        headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn websocket_key_accepts_valid_handshake() {
+        let headers = handshake(&[("connection", "keep-alive, Upgrade"), ("sec-websocket-key", "abc"), ("sec-websocket-version", "13")]);
+        assert_eq!(websocket_key(&headers).ok(), Some("abc"));
+    }
+
+    #[test]
+    fn websocket_key_rejects_incomplete_handshake() {
+        for headers in [
+            handshake(&[("sec-websocket-key", "abc"), ("sec-websocket-version", "13")]),
+            handshake(&[("connection", "keep-alive"), ("sec-websocket-key", "abc"), ("sec-websocket-version", "13")]),
+            handshake(&[("connection", "Upgrade"), ("sec-websocket-version", "13")]),
+            handshake(&[("connection", "Upgrade"), ("sec-websocket-key", "abc")]),
+        ] {
+            assert_eq!(websocket_key(&headers).unwrap_err().status, Status::BadRequest);
+        }
+    }
+
+    #[test]
+    fn websocket_key_requires_version_13() {
+        let headers = handshake(&[("connection", "Upgrade"), ("sec-websocket-key", "abc"), ("sec-websocket-version", "8")]);
+        let response = websocket_key(&headers).unwrap_err();
+        assert_eq!(response.status, Status::UpgradeRequired);
+        assert_eq!(response.headers, vec![("Sec-WebSocket-Version".to_string(), "13".to_string())]);
+    }
+
+    #[test]
+    fn query_params_decode_plus_as_space() {
+        let params = get_query_params("q=hello+world&room=a%2Bb&flag&empty=&expr=a=b");
+        assert_eq!(params.get("q").map(String::as_str), Some("hello world"));
+        assert_eq!(params.get("expr").map(String::as_str), Some("a=b"));
+        assert_eq!(params.get("room").map(String::as_str), Some("a+b"));
+        assert_eq!(params.get("empty").map(String::as_str), Some(""));
+        assert!(!params.contains_key("flag"));
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use crate::entity::request_data::RequestData;
+use crate::entity::response::{Response, Status};
+use crate::utils::http_helper;
+use crate::utils::router::HttpHandler;
+use crate::utils::utils::{authorize, redirect_to_login};
+
+pub type Next = Arc<dyn Fn(RequestData) -> BoxFuture<'static, Response> + Send + Sync>;
+
+pub trait Middleware: Send + Sync {
+    fn handle(&self, data: RequestData, next: Next) -> BoxFuture<'static, Response>;
+}
+
+/// An ordered list of middlewares. The first one added is the outermost and
+/// sees the request first and the response last.
+#[derive(Clone, Default)]
+pub struct Pipeline {
+    middlewares: Vec<Arc<dyn Middleware>>,
+}
+
+impl Pipeline {
+    pub fn with<M: Middleware + 'static>(mut self, middleware: M) -> Self {
+        self.middlewares.push(Arc::new(middleware));
+        self
+    }
+
+    pub fn wrap(&self, handler: HttpHandler) -> HttpHandler {
+        let mut next: Next = Arc::new(handler);
+        for middleware in self.middlewares.iter().rev() {
+            let middleware = middleware.clone();
+            let inner = next.clone();
+            next = Arc::new(move |data| middleware.handle(data, inner.clone()));
+        }
+
+        Box::new(move |data| next(data))
+    }
+}
+
+pub struct RequestLogger;
+
+impl Middleware for RequestLogger {
+    fn handle(&self, data: RequestData, next: Next) -> BoxFuture<'static, Response> {
+        async move {
+            let started = Instant::now();
+            let method = data.method.clone();
+            let path = data.path.clone();
+            let remote_addr = data.remote_addr;
+
+            let response = next(data).await;
+            println!(
+                "{} {} {} {} {:.1}ms",
+                remote_addr.ip(),
+                method,
+                path,
+                response.status.code(),
+                started.elapsed().as_secs_f64() * 1000.0
+            );
+            response
+        }.boxed()
+    }
+}
+
+/// Reports handler time to the client in a `Server-Timing` header.
+pub struct Timing;
+
+impl Middleware for Timing {
+    fn handle(&self, data: RequestData, next: Next) -> BoxFuture<'static, Response> {
+        async move {
+            let started = Instant::now();
+            let response = next(data).await;
+            let duration = started.elapsed().as_secs_f64() * 1000.0;
+            response.with_header("Server-Timing", &format!("app;dur={:.1}", duration))
+        }.boxed()
+    }
+}
+
+/// Turns a panicking handler into a 500 response instead of dropping the connection.
+pub struct CatchPanic;
+
+impl Middleware for CatchPanic {
+    fn handle(&self, data: RequestData, next: Next) -> BoxFuture<'static, Response> {
+        let method = data.method.clone();
+        let path = data.path.clone();
+
+        AssertUnwindSafe(next(data))
+            .catch_unwind()
+            .map(move |result| match result {
+                Ok(response) => response,
+                Err(_) => {
+                    eprintln!("Handler panicked on {} {}", method, path);
+                    http_helper::internal_error()
+                }
+            })
+            .boxed()
+    }
+}
+
+pub struct SecurityHeaders;
+
+impl Middleware for SecurityHeaders {
+    fn handle(&self, data: RequestData, next: Next) -> BoxFuture<'static, Response> {
+        async move {
+            next(data).await
+                .with_header("X-Content-Type-Options", "nosniff")
+                .with_header("X-Frame-Options", "DENY")
+                .with_header("Referrer-Policy", "same-origin")
+        }.boxed()
+    }
+}
+
+/// Fixed-window request limit per client IP address.
+pub struct RateLimit {
+    max_requests: u32,
+    window: Duration,
+    clients: Mutex<HashMap<IpAddr, (Instant, u32)>>,
+}
+
+impl RateLimit {
+    pub fn new(max_requests: u32, window: Duration) -> Self {
+        RateLimit {
+            max_requests,
+            window,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the time left until the client may retry, or `None` when the
+    /// request is within the limit.
+    fn check(&self, ip: IpAddr) -> Option<Duration> {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(|_, (started, _)| now.duration_since(*started) < self.window);
+
+        let (started, count) = clients.entry(ip).or_insert((now, 0));
+        if *count >= self.max_requests {
+            return Some(self.window.saturating_sub(now.duration_since(*started)));
+        }
+
+        *count += 1;
+        None
+    }
+}
+
+impl Middleware for RateLimit {
+    fn handle(&self, data: RequestData, next: Next) -> BoxFuture<'static, Response> {
+        match self.check(data.remote_addr.ip()) {
+            None => next(data),
+            Some(retry_after) => {
+                let response = Response::new(Status::TooManyRequests)
+                    .with_header("Retry-After", &retry_after.as_secs().max(1).to_string());
+                async move { response }.boxed()
+            }
+        }
+    }
+}
+
+/// Resolves the session from the request and stores it in `RequestData::session`.
+/// Requests without a valid session are rejected with 401 or redirected to the
+/// login page, depending on the route group.
+pub enum Authenticate {
+    Reject,
+    RedirectToLogin,
+}
+
+impl Middleware for Authenticate {
+    fn handle(&self, mut data: RequestData, next: Next) -> BoxFuture<'static, Response> {
+        match authorize(&data.headers) {
+            Ok(session) => {
+                data.session = Some(session);
+                next(data)
+            }
+            Err(_) => {
+                let response = match self {
+                    Authenticate::Reject => http_helper::unauthenticated(),
+                    Authenticate::RedirectToLogin => redirect_to_login(),
+                };
+                async move { response }.boxed()
+            }
+        }
+    }
+}
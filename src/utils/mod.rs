@@ -1,2 +1,8 @@
+#[allow(clippy::module_inception)]
 pub(crate) mod utils;
-pub(crate) mod http_helper;
\ No newline at end of file
+pub(crate) mod http_helper;
+pub(crate) mod config;
+pub(crate) mod request_reader;
+pub(crate) mod router;
+pub(crate) mod middleware;
+pub(crate) mod password;
@@ -0,0 +1,77 @@
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use constant_time_eq::constant_time_eq;
+use password_hash::rand_core::OsRng;
+use password_hash::SaltString;
+use crate::utils::config::CONFIG;
+
+fn argon2() -> Argon2<'static> {
+    let params = Params::new(
+        CONFIG.argon2_memory_kib,
+        CONFIG.argon2_iterations,
+        CONFIG.argon2_parallelism,
+        None,
+    ).expect("Invalid Argon2 parameters");
+
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+}
+
+/// Hashes a credential into a PHC string, which carries the algorithm,
+/// parameters and per-user salt next to the hash itself.
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    argon2()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| format!("Failed to hash password: {}", err))
+}
+
+/// Rows written before server-side hashing hold the client SHA-256 hex as-is.
+pub fn is_legacy_hash(stored: &str) -> bool {
+    stored.len() == 64 && stored.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+pub fn verify_password(stored: &str, password: &str) -> bool {
+    if is_legacy_hash(stored) {
+        return constant_time_eq(stored.as_bytes(), password.as_bytes());
+    }
+
+    match PasswordHash::new(stored) {
+        Ok(hash) => argon2().verify_password(password.as_bytes(), &hash).is_ok(),
+        Err(_) => false,
+    }
+}
+
+lazy_static::lazy_static! {
+    /// Hashed once with the configured parameters, so checking against it
+    /// costs as much as checking a real account.
+    static ref DUMMY_HASH: String = hash_password("dummy password").expect("Failed to hash dummy password");
+}
+
+/// Does the work of a failed verification for a name with no account, so
+/// login timing does not reveal which names exist.
+pub fn verify_dummy_password(password: &str) {
+    verify_password(&DUMMY_HASH, password);
+}
+
+/// True when the stored value is a legacy row or was hashed with parameters
+/// other than the currently configured ones.
+pub fn needs_rehash(stored: &str) -> bool {
+    if is_legacy_hash(stored) {
+        return true;
+    }
+
+    let hash = match PasswordHash::new(stored) {
+        Ok(hash) => hash,
+        Err(_) => return true,
+    };
+
+    let params = match Params::try_from(&hash) {
+        Ok(params) => params,
+        Err(_) => return true,
+    };
+
+    hash.algorithm.as_str() != "argon2id"
+        || params.m_cost() != CONFIG.argon2_memory_kib
+        || params.t_cost() != CONFIG.argon2_iterations
+        || params.p_cost() != CONFIG.argon2_parallelism
+}
@@ -0,0 +1,345 @@
+use std::collections::HashMap;
+use tokio::io::{self, AsyncRead, AsyncReadExt};
+use crate::utils::config::CONFIG;
+
+const READ_CHUNK_SIZE: usize = 4096;
+
+pub struct ParsedRequest {
+    pub method: String,
+    pub path: String,
+    pub version: String,
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    Io(io::Error),
+    Closed,
+    Malformed,
+    HeadersTooLarge,
+    BodyTooLarge,
+}
+
+impl From<io::Error> for ReadError {
+    fn from(err: io::Error) -> Self {
+        ReadError::Io(err)
+    }
+}
+
+/// Reads HTTP/1.1 requests from a stream. Bytes received past the end of a
+/// request are kept in the internal buffer for the next call.
+pub struct RequestReader {
+    buffer: Vec<u8>,
+    max_header_size: usize,
+    max_body_size: usize,
+}
+
+impl Default for RequestReader {
+    fn default() -> Self {
+        RequestReader {
+            buffer: Vec::new(),
+            max_header_size: CONFIG.max_header_size,
+            max_body_size: CONFIG.max_body_size,
+        }
+    }
+}
+
+impl RequestReader {
+    /// Waits until the next request has started to arrive, i.e. there is at
+    /// least one byte buffered. Idle connections are timed out around this
+    /// call only, so a slow body is not cut off by the keep-alive timer.
+    pub async fn wait_for_request<S: AsyncRead + Unpin>(&mut self, stream: &mut S) -> Result<(), ReadError> {
+        if self.buffer.is_empty() && self.fill(stream).await? == 0 {
+            return Err(ReadError::Closed);
+        }
+        Ok(())
+    }
+
+    pub async fn read_request<S: AsyncRead + Unpin>(&mut self, stream: &mut S) -> Result<ParsedRequest, ReadError> {
+        let head_end = loop {
+            if let Some(position) = find(&self.buffer, b"\r\n\r\n") {
+                break position;
+            }
+            if self.buffer.len() > self.max_header_size {
+                return Err(ReadError::HeadersTooLarge);
+            }
+            if self.fill(stream).await? == 0 {
+                return Err(if self.buffer.is_empty() { ReadError::Closed } else { ReadError::Malformed });
+            }
+        };
+
+        if head_end > self.max_header_size {
+            return Err(ReadError::HeadersTooLarge);
+        }
+
+        let head = std::str::from_utf8(&self.buffer[..head_end]).map_err(|_| ReadError::Malformed)?;
+        let (method, path, version, headers) = parse_head(head)?;
+        self.buffer.drain(..head_end + 4);
+
+        let chunked = headers
+            .get("transfer-encoding")
+            .map(|value| value.to_ascii_lowercase().contains("chunked"));
+
+        let body = match (chunked, headers.get("content-length")) {
+            (Some(true), None) => self.read_chunked_body(stream).await?,
+            (Some(_), _) => return Err(ReadError::Malformed),
+            (None, Some(length)) => {
+                let length: usize = length.trim().parse().map_err(|_| ReadError::Malformed)?;
+                if length > self.max_body_size {
+                    return Err(ReadError::BodyTooLarge);
+                }
+                self.read_exact_body(stream, length).await?
+            }
+            (None, None) => Vec::new(),
+        };
+
+        Ok(ParsedRequest { method, path, version, headers, body })
+    }
+
+    async fn fill<S: AsyncRead + Unpin>(&mut self, stream: &mut S) -> io::Result<usize> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        let bytes_read = stream.read(&mut chunk).await?;
+        self.buffer.extend_from_slice(&chunk[..bytes_read]);
+        Ok(bytes_read)
+    }
+
+    async fn read_exact_body<S: AsyncRead + Unpin>(&mut self, stream: &mut S, length: usize) -> Result<Vec<u8>, ReadError> {
+        while self.buffer.len() < length {
+            if self.fill(stream).await? == 0 {
+                return Err(ReadError::Malformed);
+            }
+        }
+        Ok(self.buffer.drain(..length).collect())
+    }
+
+    async fn read_line<S: AsyncRead + Unpin>(&mut self, stream: &mut S) -> Result<String, ReadError> {
+        loop {
+            if let Some(position) = find(&self.buffer, b"\r\n") {
+                let line = String::from_utf8(self.buffer[..position].to_vec()).map_err(|_| ReadError::Malformed)?;
+                self.buffer.drain(..position + 2);
+                return Ok(line);
+            }
+            if self.buffer.len() > self.max_header_size {
+                return Err(ReadError::HeadersTooLarge);
+            }
+            if self.fill(stream).await? == 0 {
+                return Err(ReadError::Malformed);
+            }
+        }
+    }
+
+    async fn read_chunked_body<S: AsyncRead + Unpin>(&mut self, stream: &mut S) -> Result<Vec<u8>, ReadError> {
+        let mut body = Vec::new();
+        loop {
+            let size_line = self.read_line(stream).await?;
+            let size_str = size_line.split(';').next().unwrap_or_default().trim();
+            let size = usize::from_str_radix(size_str, 16).map_err(|_| ReadError::Malformed)?;
+
+            if size == 0 {
+                // Trailer fields are not used, skip them up to the final empty
+                // line. They count against the header limit like any header.
+                let mut trailer_size = 0;
+                loop {
+                    let line = self.read_line(stream).await?;
+                    if line.is_empty() {
+                        return Ok(body);
+                    }
+                    trailer_size += line.len() + 2;
+                    if trailer_size > self.max_header_size {
+                        return Err(ReadError::HeadersTooLarge);
+                    }
+                }
+            }
+
+            match body.len().checked_add(size) {
+                Some(total) if total <= self.max_body_size => {}
+                _ => return Err(ReadError::BodyTooLarge),
+            }
+
+            let chunk_length = size.checked_add(2).ok_or(ReadError::Malformed)?;
+            let chunk = self.read_exact_body(stream, chunk_length).await?;
+            if !chunk.ends_with(b"\r\n") {
+                return Err(ReadError::Malformed);
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    }
+}
+
+fn parse_head(head: &str) -> Result<(String, String, String, HashMap<String, String>), ReadError> {
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().ok_or(ReadError::Malformed)?;
+    let parts: Vec<&str> = request_line.split_whitespace().collect();
+    if parts.len() != 3 || !parts[2].starts_with("HTTP/") {
+        return Err(ReadError::Malformed);
+    }
+
+    let mut headers: HashMap<String, String> = HashMap::new();
+    for line in lines {
+        let (name, value) = line.split_once(':').ok_or(ReadError::Malformed)?;
+        let name = name.trim().to_ascii_lowercase();
+        let value = value.trim();
+        if name.is_empty() {
+            return Err(ReadError::Malformed);
+        }
+
+        let separator = if name == "cookie" { "; " } else { ", " };
+        headers
+            .entry(name)
+            .and_modify(|existing| {
+                existing.push_str(separator);
+                existing.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+
+    Ok((parts[0].to_string(), parts[1].to_string(), parts[2].to_string(), headers))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reader() -> RequestReader {
+        RequestReader {
+            buffer: Vec::new(),
+            max_header_size: 256,
+            max_body_size: 32,
+        }
+    }
+
+    async fn read(input: &[u8]) -> Result<ParsedRequest, ReadError> {
+        let mut stream = input;
+        reader().read_request(&mut stream).await
+    }
+
+    #[tokio::test]
+    async fn reads_content_length_body() {
+        let request = read(b"POST /api/room HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello").await.unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/api/room");
+        assert_eq!(request.version, "HTTP/1.1");
+        assert_eq!(request.headers.get("host").map(String::as_str), Some("x"));
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[tokio::test]
+    async fn keeps_pipelined_requests_for_next_call() {
+        let mut stream: &[u8] = b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n";
+        let mut reader = reader();
+        assert_eq!(reader.read_request(&mut stream).await.unwrap().path, "/a");
+        assert_eq!(reader.read_request(&mut stream).await.unwrap().path, "/b");
+        assert!(matches!(reader.read_request(&mut stream).await, Err(ReadError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn waits_for_buffered_or_new_bytes() {
+        let mut stream: &[u8] = b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n";
+        let mut reader = reader();
+        reader.wait_for_request(&mut stream).await.unwrap();
+        assert_eq!(reader.read_request(&mut stream).await.unwrap().path, "/a");
+        reader.wait_for_request(&mut stream).await.unwrap();
+        assert_eq!(reader.read_request(&mut stream).await.unwrap().path, "/b");
+        assert!(matches!(reader.wait_for_request(&mut stream).await, Err(ReadError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn rejects_content_length_over_limit() {
+        let result = read(b"POST / HTTP/1.1\r\nContent-Length: 33\r\n\r\n").await;
+        assert!(matches!(result, Err(ReadError::BodyTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_body() {
+        let result = read(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort").await;
+        assert!(matches!(result, Err(ReadError::Malformed)));
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_content_length() {
+        let result = read(b"POST / HTTP/1.1\r\nContent-Length: ten\r\n\r\n").await;
+        assert!(matches!(result, Err(ReadError::Malformed)));
+    }
+
+    #[tokio::test]
+    async fn decodes_chunked_body() {
+        let input = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nTrailer: x\r\n\r\n";
+        assert_eq!(read(input).await.unwrap().body, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn rejects_trailers_over_header_limit() {
+        let mut input = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n".to_vec();
+        for _ in 0..30 {
+            input.extend_from_slice(b"X-Trailer: 0123456789\r\n");
+        }
+        input.extend_from_slice(b"\r\n");
+        assert!(matches!(read(&input).await, Err(ReadError::HeadersTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn rejects_chunk_without_terminator() {
+        let input = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabcd\r\n0\r\n\r\n";
+        assert!(matches!(read(input).await, Err(ReadError::Malformed)));
+    }
+
+    #[tokio::test]
+    async fn rejects_chunked_body_over_limit() {
+        let input = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n21\r\n";
+        assert!(matches!(read(input).await, Err(ReadError::BodyTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn rejects_overflowing_chunk_size() {
+        let input = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffffffff\r\n";
+        assert!(matches!(read(input).await, Err(ReadError::BodyTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_chunk_size() {
+        let input = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n";
+        assert!(matches!(read(input).await, Err(ReadError::Malformed)));
+    }
+
+    #[tokio::test]
+    async fn rejects_chunked_with_content_length() {
+        let input = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n";
+        assert!(matches!(read(input).await, Err(ReadError::Malformed)));
+    }
+
+    #[tokio::test]
+    async fn rejects_headers_over_limit() {
+        let mut input = b"GET / HTTP/1.1\r\nX-Long: ".to_vec();
+        input.extend_from_slice(&[b'a'; 300]);
+        input.extend_from_slice(b"\r\n\r\n");
+        assert!(matches!(read(&input).await, Err(ReadError::HeadersTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_heads() {
+        for input in [
+            &b"GET /\r\n\r\n"[..],
+            b"GET / FTP/1.0\r\n\r\n",
+            b"GET / HTTP/1.1\r\nno-colon\r\n\r\n",
+            b"GET / HTTP/1.1\r\n: empty-name\r\n\r\n",
+            b"GET / HTTP/1.1\r\n",
+        ] {
+            assert!(matches!(read(input).await, Err(ReadError::Malformed)), "{:?}", String::from_utf8_lossy(input));
+        }
+    }
+
+    #[tokio::test]
+    async fn merges_repeated_headers() {
+        let input = b"GET / HTTP/1.1\r\nCookie: a=1\r\ncookie: b=2\r\nAccept: x\r\nAccept: y\r\n\r\n";
+        let request = read(input).await.unwrap();
+        assert_eq!(request.headers.get("cookie").map(String::as_str), Some("a=1; b=2"));
+        assert_eq!(request.headers.get("accept").map(String::as_str), Some("x, y"));
+    }
+}
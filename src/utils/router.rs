@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::future::Future;
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use tokio::io;
+use tokio::net::TcpStream;
+use tokio_tungstenite::WebSocketStream;
+use uuid::Uuid;
+use crate::entity::account::Account;
+use crate::entity::request_data::RequestData;
+use crate::entity::response::Response;
+use crate::utils::http_helper::percent_decode;
+
+pub type HttpHandler = Box<dyn Fn(RequestData) -> BoxFuture<'static, Response> + Send + Sync>;
+pub type WsHandler = Box<dyn Fn(WebSocketStream<TcpStream>, RequestData, Account) -> BoxFuture<'static, io::Result<()>> + Send + Sync>;
+
+pub fn http_handler<F, Fut>(handler: F) -> HttpHandler
+where
+    F: Fn(RequestData) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Response> + Send + 'static,
+{
+    Box::new(move |data| handler(data).boxed())
+}
+
+pub fn ws_handler<F, Fut>(handler: F) -> WsHandler
+where
+    F: Fn(WebSocketStream<TcpStream>, RequestData, Account) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = io::Result<()>> + Send + 'static,
+{
+    Box::new(move |ws_stream, data, account| handler(ws_stream, data, account).boxed())
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ParamKind {
+    Any,
+    Int,
+    Uuid,
+}
+
+impl ParamKind {
+    fn accepts(&self, value: &str) -> bool {
+        match self {
+            ParamKind::Any => !value.is_empty(),
+            ParamKind::Int => value.parse::<i64>().is_ok(),
+            ParamKind::Uuid => Uuid::parse_str(value).is_ok(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Static(String),
+    Param(String, ParamKind),
+    Wildcard(String),
+}
+
+/// Parses a pattern such as `/api/room/:id<uuid>/messages` or `/static/*path`.
+/// Parameters may carry an `<int>` or `<uuid>` constraint; a `*name` segment
+/// captures the remainder of the path and must come last.
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    split_path(pattern)
+        .into_iter()
+        .map(|segment| {
+            if let Some(name) = segment.strip_prefix('*') {
+                Segment::Wildcard(name.to_string())
+            } else if let Some(param) = segment.strip_prefix(':') {
+                match param.split_once('<') {
+                    Some((name, kind)) => {
+                        let kind = match kind.trim_end_matches('>') {
+                            "int" => ParamKind::Int,
+                            "uuid" => ParamKind::Uuid,
+                            other => panic!("Unknown route parameter type: {}", other),
+                        };
+                        Segment::Param(name.to_string(), kind)
+                    }
+                    None => Segment::Param(param.to_string(), ParamKind::Any),
+                }
+            } else {
+                Segment::Static(segment.to_string())
+            }
+        })
+        .collect()
+}
+
+fn split_path(path: &str) -> Vec<&str> {
+    path.split('/').filter(|segment| !segment.is_empty()).collect()
+}
+
+struct Route<H> {
+    method: String,
+    segments: Vec<Segment>,
+    handler: H,
+}
+
+impl<H> Route<H> {
+    fn matches(&self, request_segments: &[&str]) -> Option<HashMap<String, String>> {
+        let mut params = HashMap::new();
+
+        for (index, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Wildcard(name) => {
+                    params.insert(name.clone(), request_segments[index.min(request_segments.len())..].join("/"));
+                    return Some(params);
+                }
+                Segment::Static(expected) => {
+                    if request_segments.get(index) != Some(&expected.as_str()) {
+                        return None;
+                    }
+                }
+                Segment::Param(name, kind) => {
+                    let value = percent_decode(request_segments.get(index)?);
+                    if !kind.accepts(&value) {
+                        return None;
+                    }
+                    params.insert(name.clone(), value);
+                }
+            }
+        }
+
+        (self.segments.len() == request_segments.len()).then_some(params)
+    }
+}
+
+pub enum RouteMatch<'a, H> {
+    Found(&'a H, HashMap<String, String>),
+    MethodNotAllowed(Vec<String>),
+    NotFound,
+}
+
+pub struct Router<H> {
+    routes: Vec<Route<H>>,
+}
+
+impl<H> Default for Router<H> {
+    fn default() -> Self {
+        Router { routes: Vec::new() }
+    }
+}
+
+impl<H> Router<H> {
+    pub fn route(mut self, method: &str, pattern: &str, handler: H) -> Self {
+        self.routes.push(Route {
+            method: method.to_string(),
+            segments: parse_pattern(pattern),
+            handler,
+        });
+        self
+    }
+
+    /// Finds the first route matching both path and method. When only the path
+    /// matches, the methods that would have been accepted are returned instead.
+    pub fn resolve(&self, method: &str, path: &str) -> RouteMatch<'_, H> {
+        let (request_path, _query) = path.split_once('?').unwrap_or((path, ""));
+        let request_segments = split_path(request_path);
+        let mut allowed: Vec<String> = Vec::new();
+
+        for route in &self.routes {
+            if let Some(params) = route.matches(&request_segments) {
+                if route.method == method {
+                    return RouteMatch::Found(&route.handler, params);
+                }
+                if !allowed.contains(&route.method) {
+                    allowed.push(route.method.clone());
+                }
+            }
+        }
+
+        if allowed.is_empty() {
+            RouteMatch::NotFound
+        } else {
+            RouteMatch::MethodNotAllowed(allowed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router() -> Router<&'static str> {
+        Router::default()
+            .route("GET", "/api/room", "list")
+            .route("POST", "/api/room", "create")
+            .route("GET", "/api/room/:id<uuid>", "room")
+            .route("GET", "/api/message/:id<int>", "message")
+            .route("GET", "/api/account/:name", "account")
+            .route("GET", "/static/*path", "static")
+    }
+
+    fn found(method: &str, path: &str) -> Option<(&'static str, HashMap<String, String>)> {
+        match router().resolve(method, path) {
+            RouteMatch::Found(handler, params) => Some((*handler, params)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn matches_static_routes_by_method() {
+        assert_eq!(found("GET", "/api/room").unwrap().0, "list");
+        assert_eq!(found("POST", "/api/room/").unwrap().0, "create");
+        assert_eq!(found("GET", "/api/room?limit=5").unwrap().0, "list");
+        assert!(found("GET", "/api/rooms").is_none());
+        assert!(found("GET", "/api").is_none());
+    }
+
+    #[test]
+    fn checks_typed_params() {
+        let id = "0e7a6a53-4fd5-4b5b-9c39-3bd0a3d2f0a1";
+        let (handler, params) = found("GET", &format!("/api/room/{}", id)).unwrap();
+        assert_eq!(handler, "room");
+        assert_eq!(params.get("id").map(String::as_str), Some(id));
+        assert!(found("GET", "/api/room/not-a-uuid").is_none());
+
+        assert_eq!(found("GET", "/api/message/-12").unwrap().1.get("id").map(String::as_str), Some("-12"));
+        assert!(found("GET", "/api/message/12a").is_none());
+        assert!(found("GET", "/api/message/99999999999999999999").is_none());
+    }
+
+    #[test]
+    fn decodes_params() {
+        let (_, params) = found("GET", "/api/account/j%C3%B6rg%20s+n").unwrap();
+        assert_eq!(params.get("name").map(String::as_str), Some("jörg s+n"));
+        assert!(found("GET", "/api/account/a/b").is_none());
+    }
+
+    #[test]
+    fn captures_wildcard_remainder() {
+        let (handler, params) = found("GET", "/static/css/index.css").unwrap();
+        assert_eq!(handler, "static");
+        assert_eq!(params.get("path").map(String::as_str), Some("css/index.css"));
+        assert_eq!(found("GET", "/static").unwrap().1.get("path").map(String::as_str), Some(""));
+    }
+
+    #[test]
+    fn reports_allowed_methods() {
+        match router().resolve("DELETE", "/api/room") {
+            RouteMatch::MethodNotAllowed(allowed) => assert_eq!(allowed, vec!["GET", "POST"]),
+            _ => panic!("expected 405"),
+        }
+        assert!(matches!(router().resolve("DELETE", "/nowhere"), RouteMatch::NotFound));
+    }
+}
@@ -1,43 +1,94 @@
+use std::collections::HashMap;
 use tokio::io;
+use crate::entity::response::{Cookie, Response};
 use crate::entity::session::Session;
-use crate::repository::session::match_and_return_session;
+use crate::repository::session::{find_session, match_and_return_session};
+use crate::utils::config::CONFIG;
 use crate::utils::http_helper::{parse_cookies};
 
-pub fn extract_path_from_request(request: &str) -> Option<String> {
-    let lines: Vec<&str> = request.lines().collect();
-    if let Some(first_line) = lines.first() {
-        let parts: Vec<&str> = first_line.split_whitespace().collect();
-        if parts.len() >= 2 {
-            return Some(parts[1].to_string());
-        }
+/// Resolves the session from the `id`/`token` cookies, falling back to an
+/// `Authorization: Bearer <token>` header for API clients.
+pub fn authorize(headers: &HashMap<String, String>) -> io::Result<Session> {
+    let cookies = parse_cookies(headers);
+    if let (Some(id), Some(token)) = (cookies.get("id"), cookies.get("token")) {
+        return match_and_return_session(id.clone(), token.clone())
+            .ok_or_else(|| io::Error::other("unauthenticated"));
+    }
+
+    match bearer_token(headers) {
+        Some(token) => find_session(token).ok_or_else(|| io::Error::other("unauthenticated")),
+        None => Err(io::Error::other("unauthenticated")),
     }
-    None
 }
 
-pub fn authorize(buffer: &[u8; 1024]) -> io::Result<Session> {
-    let cookies = parse_cookies(std::str::from_utf8(buffer).unwrap_or_default());
-    let id = cookies.get("id");
-    let session = cookies.get("token");
-    if id.is_none() || session.is_none() {
-        return Err(io::Error::new(io::ErrorKind::Other, "unauthenticated"))
+/// Close code sent when a WebSocket handshake carries no valid session, and
+/// to sockets of a session that logs out.
+pub const WS_UNAUTHORIZED: u16 = 4401;
+
+/// Close code for sockets the server drops: idle ones and those closed
+/// through the connection registry.
+pub const WS_GOING_AWAY: u16 = 1001;
+
+/// Close code for sockets whose client stopped answering pings.
+pub const WS_HEARTBEAT_TIMEOUT: u16 = 1011;
+
+/// The only WebSocket protocol version spoken, that of RFC 6455.
+pub const WS_VERSION: &str = "13";
+
+/// Browsers cannot set headers on a WebSocket handshake, so clients may offer
+/// `Sec-WebSocket-Protocol: bearer, <token>` instead.
+pub const WS_TOKEN_PROTOCOL: &str = "bearer";
+
+pub fn authorize_ws(headers: &HashMap<String, String>) -> io::Result<Session> {
+    if let Ok(session) = authorize(headers) {
+        return Ok(session);
     }
 
-    match match_and_return_session(id.unwrap().clone(), session.unwrap().clone()) {
-        Some(session) => Ok(session),
-        None => Err(io::Error::new(io::ErrorKind::Other, "unauthenticated")),
+    match protocol_token(headers) {
+        Some(token) => find_session(token).ok_or_else(|| io::Error::other("unauthenticated")),
+        None => Err(io::Error::other("unauthenticated")),
     }
 }
 
-pub fn clear_cookies_response() -> String {
-    let cookies_to_clear = vec!["id", "token", "name"];
-    let mut headers = String::new();
-
-    for cookie in cookies_to_clear {
-        headers.push_str(&format!(
-            "Set-Cookie: {}=; Path=/; Expires=Thu, 01 Jan 1970 00:00:00 GMT; SameSite=Strict\r\n",
-            cookie
-        ));
+pub fn protocol_token(headers: &HashMap<String, String>) -> Option<&str> {
+    let mut protocols = headers.get("sec-websocket-protocol")?.split(',').map(str::trim);
+    if protocols.next()? != WS_TOKEN_PROTOCOL {
+        return None;
     }
+    protocols.next()
+}
 
+pub fn bearer_token(headers: &HashMap<String, String>) -> Option<&str> {
     headers
-}
\ No newline at end of file
+        .get("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// Attaches the session cookies. `id` and `token` are HttpOnly so injected
+/// scripts cannot read them; `name` is only used for display by the pages.
+pub fn with_session_cookies(response: Response, id: &str, name: &str, token: &str) -> Response {
+    let max_age = CONFIG.session_max_lifetime.as_secs() as i64;
+    let secure_cookie = |name: &str, value: &str| Cookie {
+        max_age: Some(max_age),
+        http_only: true,
+        secure: CONFIG.tls,
+        ..Cookie::new(name, value)
+    };
+
+    response
+        .with_cookie(secure_cookie("id", id))
+        .with_cookie(secure_cookie("token", token))
+        .with_cookie(Cookie {
+            max_age: Some(max_age),
+            secure: CONFIG.tls,
+            ..Cookie::new("name", name)
+        })
+}
+
+pub fn redirect_to_login() -> Response {
+    Response::redirect("/login")
+        .clear_cookie("id")
+        .clear_cookie("token")
+        .clear_cookie("name")
+}
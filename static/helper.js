@@ -11,8 +11,35 @@ async function hashSHA256(message) {
 function getCookieValue(name) {
     const cookies = document.cookie.split('; ');
     for (const cookie of cookies) {
-        const [key, value] = cookie.split('=');
-        if (key === name) return value;
+        const [key, ...rest] = cookie.split('=');
+        if (key === name) return decodeURIComponent(rest.join('='));
     }
     return null;
-}
\ No newline at end of file
+}
+
+function escapeHtml(text) {
+    return text
+        .replace(/&/g, "&amp;")
+        .replace(/</g, "&lt;")
+        .replace(/>/g, "&gt;")
+        .replace(/"/g, "&quot;")
+        .replace(/'/g, "&#39;");
+}
+
+// Escapes message content for `innerHTML` and wraps the `@handle` mentions the
+// server resolved in a highlight. Mentions of the current user, directly or
+// through `@room` and `@here`, stand out more.
+function highlightMentions(content, mentions, username) {
+    content = escapeHtml(content);
+    if (!mentions || mentions.length === 0) {
+        return content;
+    }
+
+    const handles = mentions.map(handle => handle.replace(/[.*+?^${}()|[\]\\-]/g, "\\$&"));
+    const pattern = new RegExp(`(^|[^\\p{L}\\p{N}_])@(${handles.join("|")})(?![\\p{L}\\p{N}_.-]*[\\p{L}\\p{N}_])`, "giu");
+    return content.replace(pattern, (match, before, handle) => {
+        const lower = handle.toLowerCase();
+        const mine = lower === "room" || lower === "here" || lower === (username || "").toLowerCase();
+        return `${before}<span class="mention${mine ? " mine" : ""}">@${handle}</span>`;
+    });
+}
@@ -1,28 +1,35 @@
-document.getElementById("name-message").innerHTML = getCookieValue("name");
+document.getElementById("name-message").textContent = getCookieValue("name");
 
 function enterChat(chatId) {
     window.location.href = `/room?id=${chatId}`;
 }
 
-const loadChatRooms = () => fetch('/api/room', {
-    method: 'GET',
-    headers: {
-        'Content-Type': 'application/json'
-    },
-}).then(response => {
-    if (!response.ok) {
-        throw new Error(response.statusText);
-    }
-    return response.json();
-}).then(data => {
+// Invite links point here with `?invite=<code>`.
+const inviteCode = new URLSearchParams(window.location.search).get("invite");
+if (inviteCode) {
+    fetch(`/api/invite/${inviteCode}`, { method: "POST" })
+        .then(response => response.ok ? response.json() : response.json().then(error => Promise.reject(error)))
+        .then(room => enterChat(room.id))
+        .catch(error => alert(error.message || "Could not join the room"));
+}
+
+const rooms = new Map();
+
+function renderChatRooms() {
     const chatRooms = document.getElementById("chats-rooms");
     const holder = document.createElement("tbody");
 
-    data.forEach(room => {
+    rooms.forEach(room => {
         const tr = document.createElement("tr");
         tr.innerHTML = `
             <tr>
-                <td>${room.name}</td>
+                <td>
+                    <span class="room-name"></span>
+                    ${room.kind === "private" ? `<span class="badge private">private</span>` : ""}
+                    ${room.archived ? `<span class="badge archived">archived</span>` : ""}
+                    ${room.unread_count > 0 ? `<span class="badge unread">${room.unread_count}</span>` : ""}
+                    ${room.mention_count > 0 ? `<span class="badge mention">@${room.mention_count}</span>` : ""}
+                </td>
                 <td class="actions">
                     <div class="actions-wrapper">
                         <button class="enter" onclick="enterChat('${room.id}')">Enter</button>
@@ -31,14 +38,70 @@ const loadChatRooms = () => fetch('/api/room', {
                 </td>
             </tr>
         `
+        tr.querySelector(".room-name").textContent = room.name;
+        if (room.topic) {
+            const topic = document.createElement("div");
+            topic.className = "room-topic";
+            topic.textContent = room.topic;
+            tr.querySelector("td").appendChild(topic);
+        }
         holder.appendChild(tr);
     })
 
     chatRooms.innerHTML = holder.innerHTML;
-}).catch(error => {
-    console.error('Error:', error);
-});
-loadChatRooms();
+}
+
+const directRooms = new Map();
+
+function renderDirectRooms() {
+    const holder = document.createElement("tbody");
+    directRooms.forEach(room => {
+        const others = room.participants.filter(participant => participant.username !== getCookieValue("name"));
+        const tr = document.createElement("tr");
+        tr.innerHTML = `
+            <td>
+                <span class="participants"></span>
+                ${room.unread_count > 0 ? `<span class="badge unread">${room.unread_count}</span>` : ""}
+                ${room.mention_count > 0 ? `<span class="badge mention">@${room.mention_count}</span>` : ""}
+            </td>
+            <td class="actions">
+                <div class="actions-wrapper">
+                    <button class="enter" onclick="enterChat('${room.id}')">Enter</button>
+                </div>
+            </td>
+        `;
+        tr.querySelector(".participants").textContent = others.map(participant => participant.username).join(", ");
+        holder.appendChild(tr);
+    });
+
+    document.getElementById("direct-rooms").innerHTML = holder.innerHTML;
+}
+
+function loadDirectRooms() {
+    fetch("/api/dm")
+        .then(response => response.json())
+        .then(data => {
+            directRooms.clear();
+            data.forEach(room => directRooms.set(room.id, room));
+            renderDirectRooms();
+        })
+        .catch(error => console.error("Error:", error));
+}
+loadDirectRooms();
+
+function openDirect() {
+    const usernames = document.getElementById("direct-usernames").value
+        .split(",")
+        .map(username => username.trim())
+        .filter(username => username);
+    if (usernames.length === 0) {
+        return;
+    }
+
+    socket.request("open_direct", { usernames })
+        .then(room => enterChat(room.id))
+        .catch(error => alert(error.message));
+}
 
 function openPopup() {
     const popup = document.getElementById("popup");
@@ -50,45 +113,45 @@ function closePopup() {
     popup.style.display = "none";
 }
 
-const socket = new WebSocket(`ws://localhost:3000/api/room/send`)
-const socketGet = new WebSocket(`ws://localhost:3000/api/room/get`)
-const socketDelete = new WebSocket(`ws://localhost:3000/api/room/delete`)
+const socket = new ChatSocket();
 
-socket.addEventListener("open", () => {
-    console.log("Connected to the WebSocket server.");
-});
-socketGet.addEventListener("open", () => {
-    console.log("Connected to the WebSocket server.");
-});
-socketDelete.addEventListener("open", () => {
-    console.log("Connected to the WebSocket server.");
-});
-
-socket.addEventListener("error", (error) => {
-    console.error("WebSocket error:", error);
-});
-socketGet.addEventListener("error", (error) => {
-    console.error("WebSocket error:", error);
-});
-socketDelete.addEventListener("error", (error) => {
-    console.error("WebSocket error:", error);
+socket.on("room_list_event", (event) => {
+    // Direct conversations are listed separately, with their participants.
+    if (event.kind === "direct") {
+        loadDirectRooms();
+        return;
+    }
+    if (event.type === "deleted" || event.type === "left") {
+        rooms.delete(event.id);
+    } else {
+        rooms.set(event.id, { ...rooms.get(event.id), ...event });
+    }
+    renderChatRooms();
 });
 
-socket.addEventListener("close", () => {
-    console.log("WebSocket connection closed.");
-});
-socketGet.addEventListener("close", () => {
-    console.log("WebSocket connection closed.");
-});
-socketDelete.addEventListener("close", () => {
-    console.log("WebSocket connection closed.");
+socket.on("mentioned", (event) => {
+    const room = rooms.get(event.room_id);
+    if (room && event.message.seq > room.last_read_seq) {
+        room.mention_count += 1;
+        renderChatRooms();
+    } else if (!room) {
+        loadDirectRooms();
+    }
 });
 
-socketGet.addEventListener("message", (event) => {
-    loadChatRooms().catch(error => console.error(error));
-});
-socketDelete.addEventListener("message", (event) => {
-    loadChatRooms().catch(error => console.error(error));
+function subscribeRoomList() {
+    socket.request("subscribe_room_list").then(data => {
+        rooms.clear();
+        data.forEach(room => rooms.set(room.id, room));
+        renderChatRooms();
+    }).catch(error => {
+        console.error('Error:', error);
+    });
+}
+subscribeRoomList();
+socket.on("reconnect", () => {
+    subscribeRoomList();
+    loadDirectRooms();
 });
 
 function submitChat() {
@@ -97,11 +160,13 @@ function submitChat() {
         return;
     }
 
-    socket.send(roomName);
+    const isPrivate = document.getElementById("room-private").checked;
+    socket.request("create_room", { name: roomName, private: isPrivate }).catch(error => alert(error.message));
     document.getElementById("room-name").value = "";
+    document.getElementById("room-private").checked = false;
     closePopup();
 }
 
 function deleteChat(chatId) {
-    socketDelete.send(chatId);
-}
\ No newline at end of file
+    socket.request("delete_room", { room_id: chatId }).catch(error => alert(error.message));
+}
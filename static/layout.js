@@ -1,16 +1,8 @@
-if (!getCookieValue('token') || !getCookieValue('id')) {
-    logout();
-}
-
 fetch('/api/auth/me', {
     method: 'POST',
     headers: {
         'Content-Type': 'application/json'
     },
-    body: JSON.stringify({
-        id: getCookieValue('id'),
-        token: getCookieValue('token'),
-    })
 })
     .then(response => {
         if (!response.ok) {
@@ -18,9 +10,6 @@ fetch('/api/auth/me', {
         }
         return response.json();
     })
-    .then(data => {
-        document.cookie = `name=${data.name}; path=/; SameSite=Strict;`;
-    })
     .catch(_ => logout());
 
 function logout() {
@@ -29,14 +18,20 @@ function logout() {
         headers: {
             'Content-Type': 'application/json'
         },
-        body: JSON.stringify({
-            id: getCookieValue('id'),
-            token: getCookieValue('token'),
-        })
-    }).catch(_ => {
-        document.cookie = "token=; path=/; expires=Thu, 01 Jan 1970 00:00:00 UTC;";
-        document.cookie = "id=; path=/; expires=Thu, 01 Jan 1970 00:00:00 UTC;";
-        document.cookie = "name=; path=/; expires=Thu, 01 Jan 1970 00:00:00 UTC;";
+    }).finally(() => {
         window.location.href = '/login';
     })
-}
\ No newline at end of file
+}
+
+// Shows a short-lived notice in the corner, linking to `href` when given.
+function showNotice(text, href) {
+    const notice = document.createElement(href ? "a" : "div");
+    notice.classList.add("notice");
+    notice.textContent = text;
+    if (href) {
+        notice.href = href;
+    }
+
+    document.body.appendChild(notice);
+    setTimeout(() => notice.remove(), 5000);
+}
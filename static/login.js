@@ -1,6 +1,8 @@
-if (getCookieValue('token') && getCookieValue('id')) {
-    window.location.href = '/';
-}
+fetch('/api/auth/me', { method: 'POST' }).then(response => {
+    if (response.ok) {
+        window.location.href = '/';
+    }
+});
 
 document.getElementById('loginForm').addEventListener('submit', async function(event) {
     event.preventDefault();
@@ -27,10 +29,7 @@ document.getElementById('loginForm').addEventListener('submit', async function(e
             }
             return response.json();
         })
-        .then(data => {
-            document.cookie = `id=${data.id}; path=/; SameSite=Strict;`;
-            document.cookie = `name=${data.name}; path=/; SameSite=Strict;`;
-            document.cookie = `token=${data.token}; path=/; SameSite=Strict;`;
+        .then(_ => {
             window.location.href = '/';
         })
         .catch(error => {
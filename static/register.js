@@ -1,6 +1,8 @@
-if (getCookieValue('token') && getCookieValue('id')) {
-    window.location.href = '/';
-}
+fetch('/api/auth/me', { method: 'POST' }).then(response => {
+    if (response.ok) {
+        window.location.href = '/';
+    }
+});
 
 document.getElementById('registerForm').addEventListener('submit', async function(event) {
     event.preventDefault();
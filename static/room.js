@@ -6,98 +6,559 @@ if (!roomId) {
     window.location.href = '/';
 }
 
-fetch(`/api/room/${roomId}`, {
-    method: 'GET',
-    headers: {
-        'Content-Type': 'application/json'
-    },
-}).then(response => {
-    if (!response.ok) {
-        window.location.href = '/';
-    }
-    return response.json();
-}).then(data => {
-    const container = document.getElementById("chat-container");
-    const room = document.getElementById('room-name');
-    room.innerHTML = data.name;
+let oldestMessageId = null;
+let lastSeq = 0;
+let latestMessage = null;
+let lastMarkedSeq = 0;
+const readReceipts = new Map();
 
-    data.messages.forEach(message => {
-        const messageElement = document.createElement("div");
-        messageElement.classList.add("message");
+function renderReadReceipts() {
+    const element = document.getElementById("read-receipts");
+    const readers = latestMessage ? [...readReceipts.values()]
+        .filter(receipt => receipt.username !== name && receipt.last_read_seq >= latestMessage.seq)
+        .map(receipt => receipt.username) : [];
+    element.textContent = readers.length > 0 ? `Seen by ${readers.join(", ")}` : "";
+}
 
-        if (message.username === name) {
-            messageElement.classList.add("user");
-        } else {
-            messageElement.classList.add("other")
+function loadReadReceipts() {
+    fetch(`/api/room/${roomId}/reads`, {
+        method: 'GET',
+        headers: {
+            'Content-Type': 'application/json'
+        },
+    }).then(response => {
+        if (!response.ok) {
+            throw new Error(response.statusText);
         }
+        return response.json();
+    }).then(receipts => {
+        readReceipts.clear();
+        receipts.forEach(receipt => readReceipts.set(receipt.account_id, receipt));
+        renderReadReceipts();
+    }).catch(error => {
+        console.error('Error:', error);
+    });
+}
+
+// Marks the newest message read while the page is in view.
+function markRead() {
+    if (!latestMessage || latestMessage.seq <= lastMarkedSeq || document.visibilityState !== "visible") {
+        return;
+    }
+    lastMarkedSeq = latestMessage.seq;
+    socket.request("mark_read", { room_id: roomId, message_id: latestMessage.id }).catch(error => {
+        console.error('Error:', error);
+    });
+}
+
+document.addEventListener("visibilitychange", markRead);
+let hasOlderMessages = true;
+let loadingOlderMessages = false;
+
+function createMessageElement(message) {
+    const messageElement = document.createElement("div");
+    messageElement.classList.add("message");
+    messageElement.dataset.id = message.id;
 
-        messageElement.innerHTML = `
-            <div class="message-wrapper">
-                <div class="username">${message.username} </div>
-                <div class="text">${message.content}</div>
+    if (message.username === name) {
+        messageElement.classList.add("user");
+    } else {
+        messageElement.classList.add("other")
+    }
+
+    renderMessage(messageElement, message);
+    return messageElement;
+}
+
+const messagesById = new Map();
+
+function renderMessage(messageElement, message) {
+    messagesById.set(message.id, message);
+    const deleted = message.deleted_at !== null && message.deleted_at !== undefined;
+    const edited = !deleted && message.edited_at ? " (edited)" : "";
+    const ownActions = message.username === name ? `
+                <button onclick="editMessage('${message.id}')">Edit</button>
+                <button onclick="deleteMessage('${message.id}')">Delete</button>
+        ` : "";
+    const actions = !deleted ? `
+            <div class="message-actions">
+                <button onclick="replyToMessage('${message.id}')">Reply</button>
+                <button onclick="reactToMessage('${message.id}')">React</button>
+                ${ownActions}
             </div>
-            <div class="message-date">${new Date(message.date).toLocaleString()}</div>
-        `;
+        ` : "";
+    const thread = !message.thread_root && message.reply_count > 0 ? `
+            <button class="thread-link" onclick="openThread('${message.id}')">
+                ${message.reply_count} ${message.reply_count === 1 ? "reply" : "replies"}
+            </button>
+        ` : "";
 
-        container.appendChild(messageElement);
-        container.scrollTo({
-            top: container.scrollHeight,
-            behavior: "smooth"
-        });
-    })
+    messageElement.classList.toggle("deleted", deleted);
+    messageElement.innerHTML = `
+        <div class="message-wrapper">
+            <div class="username">${escapeHtml(message.username)} </div>
+            <div class="text">${deleted ? "Message deleted" : highlightMentions(message.content, message.mentions, name)}</div>
+        </div>
+        <div class="message-date">${new Date(message.date).toLocaleString()}${edited}</div>
+        ${thread}
+        ${actions}
+    `;
+    if (message.quote) {
+        messageElement.querySelector(".message-wrapper").prepend(renderQuote(message.quote));
+    }
+    if ((message.reactions || []).length > 0) {
+        messageElement.querySelector(".message-date").after(renderReactions(message));
+    }
+}
 
-}).catch(error => {
-    console.error('Error:', error);
-});
+function renderQuote(quote) {
+    const element = document.createElement("div");
+    element.className = "quote";
+    const username = document.createElement("span");
+    username.className = "username";
+    username.textContent = quote.username;
+    element.append(username, " ", quote.content || "Message deleted");
+    return element;
+}
 
-const socket = new WebSocket(`ws://localhost:3000/api/message/send?id=${roomId}`)
-const socketGet = new WebSocket(`ws://localhost:3000/api/message/get?id=${roomId}`)
+function renderReactions(message) {
+    const reactions = document.createElement("div");
+    reactions.className = "reactions";
+    message.reactions.forEach(reaction => {
+        const button = document.createElement("button");
+        button.className = "reaction";
+        button.classList.toggle("mine", reaction.usernames.includes(name));
+        button.title = reaction.usernames.join(", ");
+        button.textContent = `${reaction.emoji} ${reaction.count}`;
+        button.addEventListener("click", () => toggleReaction(message.id, reaction.emoji));
+        reactions.appendChild(button);
+    });
+    return reactions;
+}
 
-socket.addEventListener("open", () => {
-    console.log("Connected to the WebSocket server.");
-});
-socketGet.addEventListener("open", () => {
-    console.log("Connected to the WebSocket server.");
-});
-socket.addEventListener("error", (error) => {
-    console.error("WebSocket error:", error);
+function updateMessage(message) {
+    document.querySelectorAll(`.message[data-id="${message.id}"]`).forEach(messageElement => {
+        renderMessage(messageElement, message);
+    });
+}
+
+function applyReaction(event) {
+    const message = messagesById.get(event.message_id);
+    if (!message) {
+        return;
+    }
+
+    let reaction = message.reactions.find(reaction => reaction.emoji === event.emoji);
+    if (!reaction) {
+        reaction = { emoji: event.emoji, count: 0, usernames: [] };
+        message.reactions.push(reaction);
+    }
+
+    reaction.count = event.count;
+    if (event.type === "reaction_added" && !reaction.usernames.includes(event.username)) {
+        reaction.usernames.push(event.username);
+    } else if (event.type === "reaction_removed") {
+        reaction.usernames = reaction.usernames.filter(username => username !== event.username);
+    }
+    message.reactions = message.reactions.filter(reaction => reaction.count > 0);
+
+    updateMessage(message);
+}
+
+function toggleReaction(messageId, emoji) {
+    const message = messagesById.get(messageId);
+    const reaction = message && message.reactions.find(reaction => reaction.emoji === emoji);
+    const type = reaction && reaction.usernames.includes(name) ? "remove_reaction" : "add_reaction";
+    socket.request(type, { message_id: messageId, emoji }).catch(error => alert(error.message));
+}
+
+function reactToMessage(messageId) {
+    const emoji = prompt("React with", "👍");
+    if (emoji && emoji.trim()) {
+        socket.request("add_reaction", { message_id: messageId, emoji: emoji.trim() }).catch(error => alert(error.message));
+    }
+}
+
+let replyTo = null;
+
+function replyToMessage(messageId) {
+    replyTo = messageId;
+    document.getElementById("reply-username").textContent = messagesById.get(messageId).username;
+    document.getElementById("reply-bar").hidden = false;
+    document.getElementById("message-input").focus();
+}
+
+function cancelReply() {
+    replyTo = null;
+    document.getElementById("reply-bar").hidden = true;
+}
+
+let openThreadId = null;
+
+function appendToThread(message) {
+    const threadMessages = document.getElementById("thread-messages");
+    threadMessages.appendChild(createMessageElement(message));
+    threadMessages.scrollTo({
+        top: threadMessages.scrollHeight,
+        behavior: "smooth"
+    });
+}
+
+function openThread(rootId) {
+    closeThread();
+    openThreadId = rootId;
+
+    socket.request("subscribe_thread", { message_id: rootId }).then(thread => {
+        const threadMessages = document.getElementById("thread-messages");
+        threadMessages.innerHTML = "";
+        appendToThread(thread.root);
+        thread.replies.forEach(appendToThread);
+        document.getElementById("thread-panel").hidden = false;
+    }).catch(error => {
+        console.error('Error:', error);
+    });
+}
+
+function closeThread() {
+    if (openThreadId) {
+        socket.request("unsubscribe_thread", { message_id: openThreadId }).catch(() => {});
+        openThreadId = null;
+    }
+    document.getElementById("thread-panel").hidden = true;
+}
+
+function editMessage(messageId) {
+    const messageElement = document.querySelector(`.message[data-id="${messageId}"] .text`);
+    const content = prompt("Edit message", messageElement ? messageElement.textContent : "");
+    if (content === null || !content.trim()) {
+        return;
+    }
+
+    socket.request("edit_message", { message_id: messageId, content }).catch(error => alert(error.message));
+}
+
+function deleteMessage(messageId) {
+    if (!confirm("Delete this message?")) {
+        return;
+    }
+
+    socket.request("delete_message", { message_id: messageId }).catch(error => alert(error.message));
+}
+
+const socket = new ChatSocket();
+
+socket.request("subscribe", { room_id: roomId }).then(data => {
+    const container = document.getElementById("chat-container");
+    lastSeq = data.last_seq;
+    roomKind = data.kind;
+    roomInfo = data;
+    renderRoomInfo();
+    loadMembers();
+
+    if (data.messages.length > 0) {
+        oldestMessageId = data.messages[0].id;
+    } else {
+        hasOlderMessages = false;
+    }
+
+    data.messages.forEach(message => {
+        container.appendChild(createMessageElement(message));
+    })
+    latestMessage = data.messages[data.messages.length - 1] || null;
+    markRead();
+    loadReadReceipts();
+    container.scrollTo({
+        top: container.scrollHeight,
+        behavior: "smooth"
+    });
+}).catch(() => {
+    window.location.href = '/';
 });
-socketGet.addEventListener("error", (error) => {
-    console.error("WebSocket error:", error);
+
+let roomKind = null;
+let roomInfo = null;
+const members = new Map();
+
+function renderRoomInfo() {
+    document.getElementById("room-name").textContent = roomInfo.name;
+    const topic = document.getElementById("room-topic");
+    topic.textContent = roomInfo.topic;
+    topic.title = roomInfo.description;
+    topic.hidden = !roomInfo.topic && !roomInfo.description;
+    if (!roomInfo.topic) {
+        topic.textContent = roomInfo.description;
+    }
+
+    document.getElementById("archived-notice").hidden = !roomInfo.archived;
+    document.getElementById("archive-room").textContent = roomInfo.archived ? "Unarchive" : "Archive";
+    document.querySelectorAll("#chat-input input, #chat-input button").forEach(element => {
+        element.disabled = roomInfo.archived;
+    });
+}
+
+function updateRoom(changes) {
+    return socket.request("update_room", { room_id: roomId, ...changes })
+        .then(room => {
+            roomInfo = { ...roomInfo, ...room };
+            renderRoomInfo();
+        })
+        .catch(error => alert(error.message));
+}
+
+function editRoom() {
+    const name = prompt("Room name", roomInfo.name);
+    if (name === null || !name.trim()) {
+        return;
+    }
+    const topic = prompt("Topic", roomInfo.topic);
+    if (topic === null) {
+        return;
+    }
+    const description = prompt("Description", roomInfo.description);
+    if (description === null) {
+        return;
+    }
+
+    updateRoom({ name, topic, description });
+}
+
+function toggleArchived() {
+    updateRoom({ archived: !roomInfo.archived });
+}
+
+// Owners and moderators get the invite controls.
+function loadMembers() {
+    fetch(`/api/room/${roomId}/members`)
+        .then(response => response.json())
+        .then(data => {
+            members.clear();
+            data.forEach(member => members.set(member.account_id, member));
+            const self = data.find(member => member.username === name);
+            const manager = self && (self.role === "owner" || self.role === "moderator");
+            document.getElementById("manage-room").hidden = !manager;
+            document.getElementById("leave-room").hidden = roomKind === "direct" || !self || self.role === "owner";
+        })
+        .catch(error => console.error("Error:", error));
+}
+
+function inviteMember() {
+    const input = document.getElementById("invite-username");
+    const username = input.value.trim();
+    if (!username) {
+        return;
+    }
+
+    socket.request("invite_member", { room_id: roomId, username })
+        .then(member => {
+            members.set(member.account_id, member);
+            input.value = "";
+        })
+        .catch(error => alert(error.message));
+}
+
+function createInviteLink() {
+    fetch(`/api/room/${roomId}/links`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ expires_in_secs: 7 * 24 * 60 * 60 }),
+    })
+        .then(response => response.json())
+        .then(link => {
+            const output = document.getElementById("invite-link");
+            output.value = `${location.origin}/?invite=${link.code}`;
+            output.hidden = false;
+            output.select();
+        })
+        .catch(error => console.error("Error:", error));
+}
+
+function leaveRoom() {
+    if (!confirm("Leave this room?")) {
+        return;
+    }
+
+    socket.request("leave_room", { room_id: roomId })
+        .then(() => window.location.href = "/")
+        .catch(error => alert(error.message));
+}
+
+const onlineMembers = new Map();
+const typingTimers = new Map();
+
+function renderOnline() {
+    const names = [...onlineMembers.values()].sort();
+    document.getElementById("online-members").textContent = names.join(", ");
+}
+
+function loadOnline() {
+    fetch(`/api/room/${roomId}/online`, {
+        method: 'GET',
+        headers: {
+            'Content-Type': 'application/json'
+        },
+    }).then(response => {
+        if (!response.ok) {
+            throw new Error(response.statusText);
+        }
+        return response.json();
+    }).then(members => {
+        onlineMembers.clear();
+        members.forEach(member => onlineMembers.set(member.account_id, member.username));
+        renderOnline();
+    }).catch(error => {
+        console.error('Error:', error);
+    });
+}
+loadOnline();
+
+function renderTyping() {
+    const names = [...typingTimers.keys()];
+    const typing = document.getElementById("typing");
+    if (names.length === 0) {
+        typing.textContent = "";
+    } else if (names.length === 1) {
+        typing.textContent = `${names[0]} is typing...`;
+    } else {
+        typing.textContent = `${names.join(", ")} are typing...`;
+    }
+}
+
+function setTyping(username, expiresInMs) {
+    clearTimeout(typingTimers.get(username));
+    if (expiresInMs > 0) {
+        typingTimers.set(username, setTimeout(() => setTyping(username, 0), expiresInMs));
+    } else {
+        typingTimers.delete(username);
+    }
+    renderTyping();
+}
+
+let lastTypingSent = 0;
+
+document.getElementById("message-input").addEventListener("input", () => {
+    const now = Date.now();
+    if (now - lastTypingSent < 2000) {
+        return;
+    }
+    lastTypingSent = now;
+    socket.request("typing", { room_id: roomId }).catch(() => {});
 });
 
-socket.addEventListener("close", () => {
-    console.log("WebSocket connection closed.");
+// Replays whatever was missed while the connection was down.
+socket.on("reconnect", () => {
+    loadOnline();
+    loadReadReceipts();
+
+    socket.request("subscribe", { room_id: roomId, since: lastSeq }).catch(() => {
+        window.location.reload();
+    });
+
+    if (openThreadId) {
+        openThread(openThreadId);
+    }
 });
-socketGet.addEventListener("close", () => {
-    console.log("WebSocket connection closed.");
+
+function loadOlderMessages() {
+    if (!hasOlderMessages || loadingOlderMessages || !oldestMessageId) {
+        return;
+    }
+    loadingOlderMessages = true;
+
+    fetch(`/api/room/${roomId}/messages?before=${oldestMessageId}`, {
+        method: 'GET',
+        headers: {
+            'Content-Type': 'application/json'
+        },
+    }).then(response => {
+        if (!response.ok) {
+            throw new Error(response.statusText);
+        }
+        return response.json();
+    }).then(page => {
+        const container = document.getElementById("chat-container");
+        const previousHeight = container.scrollHeight;
+
+        page.messages.slice().reverse().forEach(message => {
+            container.prepend(createMessageElement(message));
+        });
+        container.scrollTop += container.scrollHeight - previousHeight;
+
+        hasOlderMessages = page.has_more;
+        if (page.messages.length > 0) {
+            oldestMessageId = page.messages[0].id;
+        }
+    }).catch(error => {
+        console.error('Error:', error);
+    }).finally(() => {
+        loadingOlderMessages = false;
+    });
+}
+
+document.getElementById("chat-container").addEventListener("scroll", (event) => {
+    if (event.target.scrollTop === 0) {
+        loadOlderMessages();
+    }
 });
 
-socketGet.addEventListener("message", (event) => {
-    let message = {};
-    try {
-        message = JSON.parse(event.data);
-    } catch (err) {
-        throw Error(event.data)
+socket.on("event", (message) => {
+    if (message.room_id !== roomId) {
+        return;
     }
 
-    const container = document.getElementById("chat-container");
-    const messageElement = document.createElement("div");
-    messageElement.classList.add("message");
+    if (message.type === "member_joined") {
+        onlineMembers.set(message.account_id, message.username);
+        renderOnline();
+        return;
+    }
+    if (message.type === "member_left") {
+        onlineMembers.delete(message.account_id);
+        setTyping(message.username, 0);
+        renderOnline();
+        return;
+    }
+    if (message.type === "room_updated") {
+        const { type, id, updated_by, updated_at, ...changes } = message;
+        roomInfo = { ...roomInfo, ...changes };
+        renderRoomInfo();
+        return;
+    }
+    if (message.type === "access_revoked") {
+        if (message.username === name) {
+            window.location.href = "/";
+        }
+        members.delete(message.account_id);
+        return;
+    }
+    if (message.type === "read_updated") {
+        readReceipts.set(message.account_id, message);
+        renderReadReceipts();
+        return;
+    }
+    if (message.type === "typing") {
+        if (message.username !== name) {
+            setTyping(message.username, message.expires_in_ms);
+        }
+        return;
+    }
 
-    if (message.username === name) {
-        messageElement.classList.add("user");
-    } else {
-        messageElement.classList.add("other")
+    lastSeq = Math.max(lastSeq, message.updated_seq);
+
+    if (message.type === "reaction_added" || message.type === "reaction_removed") {
+        applyReaction(message);
+        return;
+    }
+    if (message.type !== "message_created") {
+        updateMessage(message);
+        return;
     }
 
-    messageElement.innerHTML = `
-        <div class="message-wrapper">
-            <div class="username">${message.username} </div>
-            <div class="text">${message.content}</div>
-        </div>
-        <div class="message-date">${new Date(message.date).toLocaleString()}</div>
-    `;
+    setTyping(message.username, 0);
+    latestMessage = message;
+    markRead();
+    renderReadReceipts();
+
+    const container = document.getElementById("chat-container");
+    const messageElement = createMessageElement(message);
 
     container.appendChild(messageElement);
     container.scrollTo({
@@ -106,6 +567,24 @@ socketGet.addEventListener("message", (event) => {
     });
 });
 
+socket.on("mentioned", (event) => {
+    if (event.room_id !== roomId) {
+        showNotice(`${event.message.username} mentioned you in another room`, `/room?id=${event.room_id}`);
+    }
+});
+
+socket.on("unsubscribed", (message) => {
+    if (message.room_id === roomId && !message.thread_id) {
+        window.location.href = "/";
+    }
+});
+
+socket.on("thread_event", (message) => {
+    if (message.thread_id === openThreadId && message.type === "message_created") {
+        appendToThread(message);
+    }
+});
+
 function sendMessage(event) {
     event.preventDefault();
 
@@ -116,8 +595,11 @@ function sendMessage(event) {
         return;
     }
 
-    socket.send(messageText);
+    socket.request("send_message", { room_id: roomId, content: messageText, reply_to: replyTo })
+        .catch(error => alert(error.message));
     messageInput.value = "";
+    lastTypingSent = 0;
+    cancelReply();
 }
 
 function back() {
@@ -0,0 +1,91 @@
+const PROTOCOL_VERSION = 1;
+const WS_UNAUTHORIZED = 4401;
+const MAX_RECONNECT_DELAY = 30000;
+
+// Client for the `/ws` envelope protocol. `request` resolves with the `ack`
+// payload of the command or rejects with its `error` payload; events are
+// handed to the listeners registered with `on`. Dropped connections are
+// reopened with backoff, after which the `reconnect` listener runs so pages
+// can resubscribe.
+class ChatSocket {
+    constructor() {
+        this.nextId = 1;
+        this.pending = new Map();
+        this.listeners = new Map();
+        this.reconnectDelay = 1000;
+        this.connect();
+    }
+
+    connect() {
+        this.ready = new Promise((resolve) => {
+            this.socket = new WebSocket(`${location.protocol === "https:" ? "wss" : "ws"}://${location.host}/ws`);
+            this.socket.addEventListener("open", () => {
+                this.reconnectDelay = 1000;
+                resolve();
+            });
+        });
+
+        this.socket.addEventListener("message", (event) => this.dispatch(JSON.parse(event.data)));
+        this.socket.addEventListener("error", (error) => console.error("WebSocket error:", error));
+        this.socket.addEventListener("close", (event) => {
+            console.log("WebSocket connection closed.");
+            this.pending.forEach(({ reject }) => reject({ error: "closed", message: "Connection closed" }));
+            this.pending.clear();
+
+            if (event.code === WS_UNAUTHORIZED) {
+                window.location.href = "/login";
+                return;
+            }
+
+            setTimeout(() => {
+                this.connect();
+                this.ready.then(() => {
+                    const listener = this.listeners.get("reconnect");
+                    if (listener) {
+                        listener();
+                    }
+                });
+            }, this.reconnectDelay);
+            this.reconnectDelay = Math.min(this.reconnectDelay * 2, MAX_RECONNECT_DELAY);
+        });
+    }
+
+    on(type, listener) {
+        this.listeners.set(type, listener);
+    }
+
+    async request(type, payload) {
+        await this.ready;
+
+        const id = String(this.nextId++);
+        return new Promise((resolve, reject) => {
+            this.pending.set(id, { resolve, reject });
+            this.socket.send(JSON.stringify({ v: PROTOCOL_VERSION, id, type, payload }));
+        });
+    }
+
+    dispatch(envelope) {
+        if (envelope.type === "ack" || envelope.type === "error") {
+            const pending = this.pending.get(envelope.id);
+            if (!pending) {
+                if (envelope.type === "error") {
+                    console.error("WebSocket error:", envelope.payload);
+                }
+                return;
+            }
+
+            this.pending.delete(envelope.id);
+            if (envelope.type === "ack") {
+                pending.resolve(envelope.payload);
+            } else {
+                pending.reject(envelope.payload);
+            }
+            return;
+        }
+
+        const listener = this.listeners.get(envelope.type);
+        if (listener) {
+            listener(envelope.payload);
+        }
+    }
+}